@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Archives whose members are kept, as each holds its file open
+const MAX_TABLES: usize = 16;
+
+// The members of the archives read from last, most recent last, so that looking up every member
+// of an archive doesn't read the whole archive every time
+static TABLES: Mutex<Vec<(PathBuf, Arc<Table>)>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy)]
+enum Format {
+    Zip,
+    Tar,
+}
+
+fn format(path: &Path) -> Option<Format> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "zip" | "cbz" => Some(Format::Zip),
+        "tar" | "cbt" => Some(Format::Tar),
+        _ => None,
+    }
+}
+
+/// Whether the file is walked like a directory, going by its extension
+pub fn is_archive(path: &Path) -> bool {
+    format(path).is_some()
+}
+
+/// The archive and the path inside it, if the path is a member of an archive. Members are named
+/// like files in a directory, as in `album.zip/page01.jpg`.
+pub fn split(path: &Path) -> Option<(&Path, &Path)> {
+    path.ancestors()
+        .skip(1)
+        // Only archives on disk, not a directory that happens to be named like one
+        .find(|x| is_archive(x) && x.is_file())
+        .map(|archive| (archive, path.strip_prefix(archive).unwrap()))
+}
+
+/// The paths of the files in the archive, in the order they are stored
+pub fn members(archive: &Path) -> io::Result<Vec<PathBuf>> {
+    let table = table(archive)?;
+    Ok(table.names.iter().map(|x| archive.join(x)).collect())
+}
+
+/// The relative path without `..` and `.`, or None if it would escape the archive
+fn enclosed_name(path: &Path) -> Option<PathBuf> {
+    let mut name = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(x) => name.push(x),
+            std::path::Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!name.as_os_str().is_empty()).then_some(name)
+}
+
+/// The uncompressed size of the member
+pub fn len(archive: &Path, member: &Path) -> io::Result<u64> {
+    let mut len = None;
+    visit_member(archive, member, |_, size| {
+        len = Some(size);
+        Ok(())
+    })?;
+    len.ok_or_else(|| io::ErrorKind::NotFound.into())
+}
+
+/// Up to the first `limit` bytes of the member, and its uncompressed size
+pub fn head(archive: &Path, member: &Path, limit: u64) -> io::Result<(Vec<u8>, u64)> {
+    let mut head = None;
+    visit_member(archive, member, |reader, size| {
+        let mut bytes = vec![];
+        reader.take(limit).read_to_end(&mut bytes)?;
+        head = Some((bytes, size));
+        Ok(())
+    })?;
+    head.ok_or_else(|| io::ErrorKind::NotFound.into())
+}
+
+/// Writes the content of the member
+pub fn extract(archive: &Path, member: &Path, writer: &mut impl io::Write) -> io::Result<()> {
+    let mut found = false;
+    visit_member(archive, member, |reader, _| {
+        found = true;
+        io::copy(reader, writer)?;
+        Ok(())
+    })?;
+    if !found {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    Ok(())
+}
+
+/// Calls back with the reader and uncompressed size of the member, if it is in the archive
+fn visit_member(
+    archive: &Path,
+    member: &Path,
+    mut visit: impl FnMut(&mut dyn io::Read, u64) -> io::Result<()>,
+) -> io::Result<()> {
+    let table = table(archive)?;
+    let Some(&Member { size, location }) = table.members.get(member) else {
+        return Ok(());
+    };
+    match (location, &table.zip) {
+        (Location::Zip(i), Some(zip)) => {
+            let mut zip = zip.clone();
+            let mut reader = zip.by_index(i).map_err(io::Error::other)?;
+            visit(&mut reader, size)
+        }
+        (Location::Tar(offset), _) => {
+            let mut file = File::open(archive)?;
+            file.seek(SeekFrom::Start(offset))?;
+            visit(&mut file.take(size), size)
+        }
+        (Location::Zip(_), None) => Ok(()),
+    }
+}
+
+/// The members of the archive, read again only once it changes
+fn table(archive: &Path) -> io::Result<Arc<Table>> {
+    let modified = std::fs::metadata(archive)?.modified()?;
+    let mut tables = TABLES.lock().unwrap();
+    if let Some(i) = tables.iter().position(|(path, _)| path == archive) {
+        let (path, table) = tables.remove(i);
+        if table.modified == modified {
+            tables.push((path, table.clone()));
+            return Ok(table);
+        }
+    }
+    drop(tables);
+    let table = Arc::new(Table::read(archive, modified)?);
+    let mut tables = TABLES.lock().unwrap();
+    tables.retain(|(path, _)| path != archive);
+    if tables.len() >= MAX_TABLES {
+        tables.remove(0);
+    }
+    tables.push((archive.to_path_buf(), table.clone()));
+    Ok(table)
+}
+
+struct Table {
+    modified: SystemTime,
+    // The cleaned up names of the files, in the order they are stored
+    names: Vec<PathBuf>,
+    members: HashMap<PathBuf, Member>,
+    // The central directory of a zip, read once and shared by every reader
+    zip: Option<zip::ZipArchive<SharedFile>>,
+}
+
+#[derive(Clone, Copy)]
+struct Member {
+    // Uncompressed
+    size: u64,
+    location: Location,
+}
+
+#[derive(Clone, Copy)]
+enum Location {
+    // The index in the zip
+    Zip(usize),
+    // Where the data starts in the tar, as it is stored uncompressed
+    Tar(u64),
+}
+
+impl Table {
+    fn read(archive: &Path, modified: SystemTime) -> io::Result<Self> {
+        let file = File::open(archive)?;
+        let mut table = Self {
+            modified,
+            names: vec![],
+            members: HashMap::new(),
+            zip: None,
+        };
+        match format(archive) {
+            Some(Format::Zip) => {
+                let mut zip =
+                    zip::ZipArchive::new(SharedFile::new(file)).map_err(io::Error::other)?;
+                for i in 0..zip.len() {
+                    let member = zip.by_index_raw(i).map_err(io::Error::other)?;
+                    // Names that would escape the archive are skipped, and so is the `./` that
+                    // zip keeps
+                    let name = member
+                        .enclosed_name()
+                        .filter(|_| member.is_file())
+                        .and_then(|x| enclosed_name(&x));
+                    if let Some(name) = name {
+                        table.add(name, member.size(), Location::Zip(i));
+                    }
+                }
+                table.zip = Some(zip);
+            }
+            Some(Format::Tar) => {
+                let mut tar = tar::Archive::new(file);
+                for entry in tar.entries_with_seek()? {
+                    let entry = entry?;
+                    if !entry.header().entry_type().is_file() {
+                        continue;
+                    }
+                    if let Some(name) = enclosed_name(&entry.path()?) {
+                        let size = entry.header().size()?;
+                        table.add(name, size, Location::Tar(entry.raw_file_position()));
+                    }
+                }
+            }
+            None => return Err(io::Error::other("not an archive")),
+        }
+        Ok(table)
+    }
+
+    /// The first of members stored under the same name is the one read
+    fn add(&mut self, name: PathBuf, size: u64, location: Location) {
+        if !self.members.contains_key(&name) {
+            self.members.insert(name.clone(), Member { size, location });
+            self.names.push(name);
+        }
+    }
+}
+
+/// A file read from a position of its own, so that clones read it independently
+#[derive(Clone)]
+struct SharedFile {
+    file: Arc<File>,
+    pos: u64,
+}
+
+impl SharedFile {
+    fn new(file: File) -> Self {
+        Self {
+            file: Arc::new(file),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(x) => {
+                self.pos = x;
+                return Ok(x);
+            }
+            SeekFrom::Current(x) => (self.pos, x),
+            SeekFrom::End(x) => (self.file.metadata()?.len(), x),
+        };
+        self.pos = base
+            .checked_add_signed(offset)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use std::time::Duration;
+
+    fn tar(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, contents) in members {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *contents).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn reads_members_again_once_the_archive_changes() {
+        let dir = TestDir::new();
+        let archive = dir.file(
+            "album.tar",
+            &tar(&[("a.jpg", b"one"), ("b/c.jpg", b"three")]),
+        );
+        assert_eq!(
+            members(&archive).unwrap(),
+            [archive.join("a.jpg"), archive.join("b/c.jpg")]
+        );
+        assert_eq!(len(&archive, Path::new("b/c.jpg")).unwrap(), 5);
+        assert_eq!(
+            head(&archive, Path::new("b/c.jpg"), 3).unwrap(),
+            (b"thr".to_vec(), 5)
+        );
+        let mut extracted = vec![];
+        extract(&archive, Path::new("a.jpg"), &mut extracted).unwrap();
+        assert_eq!(extracted, b"one");
+
+        let modified = archive.metadata().unwrap().modified().unwrap();
+        std::fs::write(&archive, tar(&[("a.jpg", b"changed")])).unwrap();
+        File::options()
+            .write(true)
+            .open(&archive)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(members(&archive).unwrap(), [archive.join("a.jpg")]);
+        assert_eq!(
+            head(&archive, Path::new("a.jpg"), 100).unwrap(),
+            (b"changed".to_vec(), 7)
+        );
+        assert_eq!(
+            len(&archive, Path::new("b/c.jpg")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
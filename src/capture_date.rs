@@ -0,0 +1,83 @@
+/// A date and time without a time zone, like when a photo or video was taken according to its
+/// metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CaptureDate {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl CaptureDate {
+    /// Parses ISO 8601 as written by ffprobe, like `2021-06-01T12:30:00.000000Z`, and EXIF dates,
+    /// like `2021:06:01 12:30:00`
+    pub fn parse(str: &str) -> Option<Self> {
+        let str = str.trim();
+        let mut date = str.get(..10)?.split(['-', ':']).map(|x| x.parse().ok());
+        let mut time = str
+            .get(11..19)
+            .unwrap_or("00:00:00")
+            .split(':')
+            .map(|x| x.parse().ok());
+        let result = Self {
+            year: date.next()?? as i32,
+            month: date.next()??,
+            day: date.next()??,
+            hour: time.next()??,
+            minute: time.next()??,
+            second: time.next()??,
+        };
+        // Cameras without a clock write zeros
+        let is_valid = result.year > 0
+            && (1..=12).contains(&result.month)
+            && (1..=31).contains(&result.day)
+            && result.hour < 24
+            && result.minute < 60
+            && result.second < 61;
+        is_valid.then_some(result)
+    }
+
+    /// The current date and time in the local time zone
+    pub fn today() -> Self {
+        // SAFETY: localtime_r only writes to the given tm
+        let tm = unsafe {
+            let now = libc::time(std::ptr::null_mut());
+            let mut tm: libc::tm = std::mem::zeroed();
+            libc::localtime_r(&now, &mut tm);
+            tm
+        };
+        Self {
+            year: tm.tm_year + 1900,
+            month: tm.tm_mon as u32 + 1,
+            day: tm.tm_mday as u32,
+            hour: tm.tm_hour as u32,
+            minute: tm.tm_min as u32,
+            second: tm.tm_sec as u32,
+        }
+    }
+
+    /// How many years before today this was taken, if it was on the same calendar day
+    pub fn years_ago(&self, today: &Self) -> Option<i32> {
+        let years = today.year - self.year;
+        (self.month == today.month && self.day == today.day && years > 0).then_some(years)
+    }
+
+    /// Seconds since the Unix epoch, as if the date was in UTC
+    pub fn timestamp(&self) -> i64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        days * 86400 + (self.hour * 3600 + self.minute * 60 + self.second) as i64
+    }
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
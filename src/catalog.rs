@@ -0,0 +1,185 @@
+use crate::location;
+use crate::photo_server;
+use crate::remote;
+use crate::settings::Source;
+use crate::Options;
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+// Servers that stop responding are given up on after this long
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// The size and modification time of every file listed so far, for `stat`. The listings are
+// shared by every iterator, so this is too.
+static LISTED: Mutex<BTreeMap<PathBuf, (u64, SystemTime)>> = Mutex::new(BTreeMap::new());
+
+/// A file as listed by a server
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Listed {
+    pub path: PathBuf,
+    // 0 if the server doesn't say
+    pub len: u64,
+    // The epoch if the server doesn't say
+    pub modified: SystemTime,
+}
+
+/// A source whose files are listed by a server instead of walked, like a folder on a WebDAV
+/// server or an album on a photo server
+pub trait Catalog {
+    /// Asks the server for every file in the source
+    fn list(&self) -> Result<Vec<Listed>, String>;
+}
+
+/// Whether the path is on a server, either a source or a file listed in one
+pub fn is_catalog(path: &Path) -> bool {
+    remote::is_remote(path) || photo_server::is_photo_server(path)
+}
+
+fn open(source: &Source, opts: &Options) -> Option<Box<dyn Catalog>> {
+    let path = &source.path;
+    if remote::is_remote(path) {
+        Some(Box::new(remote::Folder::new(path)))
+    } else if photo_server::is_photo_server(path) {
+        Some(Box::new(photo_server::Collection::new(source, opts)))
+    } else {
+        None
+    }
+}
+
+/// The files in the source, listed again if the cached listing is older than the max age in the
+/// options. The cached listing is used regardless if the server can't be reached.
+pub fn list(source: &Source, opts: &Options) -> Result<Vec<PathBuf>, String> {
+    let root = &source.path;
+    let catalog = open(source, opts).ok_or("not on a server")?;
+    let max_age = Duration::from_secs_f64(opts.listing_cache_mins.max(0.0) * 60.0);
+    let cached = Listing::load(root);
+    let is_fresh = cached
+        .as_ref()
+        .is_some_and(|x| x.listed.elapsed().is_ok_and(|elapsed| elapsed < max_age));
+    let listing = match cached {
+        Some(cached) if is_fresh => cached,
+        cached => match catalog.list() {
+            Ok(files) => {
+                let listing = Listing {
+                    listed: SystemTime::now(),
+                    files,
+                };
+                listing.save(root);
+                listing
+            }
+            Err(err) => match cached {
+                Some(cached) => {
+                    eprintln!("Using the last listing of {}: {err}", root.display());
+                    cached
+                }
+                None => return Err(err),
+            },
+        },
+    };
+    let mut listed = LISTED.lock().unwrap();
+    Ok(listing
+        .files
+        .into_iter()
+        .map(|file| {
+            listed.insert(file.path.clone(), (file.len, file.modified));
+            file.path
+        })
+        .collect())
+}
+
+/// The size and modification time the server listed for the file
+pub fn stat(path: &Path) -> Option<(u64, SystemTime)> {
+    LISTED.lock().unwrap().get(path).copied()
+}
+
+/// Writes the content of the file
+pub fn download(path: &Path, writer: &mut impl io::Write) -> io::Result<()> {
+    if remote::is_remote(path) {
+        remote::download(path, writer)
+    } else {
+        photo_server::download(path, writer)
+    }
+}
+
+/// The URL that plays the file, along with the header fields from [`http_headers`]
+pub fn url(path: &Path) -> io::Result<String> {
+    if remote::is_remote(path) {
+        remote::url(path)
+            .map(String::from)
+            .ok_or_else(|| io::Error::other("not a valid URL"))
+    } else {
+        photo_server::url(path)
+    }
+}
+
+/// The header fields, like `Authorization: Basic ...`, that a request for the URL of a file on a
+/// server needs, for programs that are given the URL. None for anything else.
+pub fn http_headers(url: &str) -> Vec<String> {
+    let Ok(url) = Url::parse(url) else {
+        return vec![];
+    };
+    remote::http_header(&url)
+        .into_iter()
+        .chain(photo_server::http_header(&url))
+        .collect()
+}
+
+/// Shared by every request, so that connections to a server are reused
+pub fn agent() -> &'static ureq::Agent {
+    static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+    AGENT.get_or_init(|| ureq::AgentBuilder::new().timeout(TIMEOUT).build())
+}
+
+pub fn is_image(path: &Path) -> bool {
+    mime_guess::from_path(path)
+        .first()
+        .is_some_and(|x| x.type_() == mime_guess::mime::IMAGE)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Listing {
+    listed: SystemTime,
+    files: Vec<Listed>,
+}
+
+impl Listing {
+    fn file_path(root: &Path) -> PathBuf {
+        let mut hasher = location::Fnv1a::default();
+        hasher.write(root.as_os_str().as_bytes());
+        let project_dirs = directories::ProjectDirs::from("", "", "abelscreensaver").unwrap();
+        project_dirs
+            .cache_dir()
+            .join("listings")
+            .join(format!("{:016x}.json", hasher.finish()))
+    }
+
+    fn load(root: &Path) -> Option<Self> {
+        let file = std::fs::File::open(Self::file_path(root)).ok()?;
+        serde_json::from_reader(io::BufReader::new(file)).ok()
+    }
+
+    fn save(&self, root: &Path) {
+        let file_path = Self::file_path(root);
+        let serialized = match serde_json::to_string(self) {
+            Ok(serialized) => serialized,
+            Err(err) => {
+                eprintln!("Failed to save the listing of {}: {err}", root.display());
+                return;
+            }
+        };
+        if let Some(Err(err)) = file_path.parent().map(std::fs::create_dir_all) {
+            eprintln!("Failed to save the listing of {}: {err}", root.display());
+            return;
+        }
+        let tmp_path = file_path.with_extension("json.tmp");
+        if std::fs::write(&tmp_path, serialized).is_ok() {
+            std::fs::rename(tmp_path, file_path).ok();
+        }
+    }
+}
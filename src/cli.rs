@@ -0,0 +1,411 @@
+use crate::settings::{Options, Order, SortBy, Source};
+use std::path::PathBuf;
+use structopt::clap;
+use structopt::StructOpt;
+
+/// A configurable screensaver with wide multimedia support and on the fly shuffling.
+///
+/// Options given here override the config file for this run only. Of a flag and its `--no-`
+/// counterpart, the last one given wins.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "abelscreensaver")]
+pub struct Cli {
+    /// Use this config file instead of the default one
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Never write the config file
+    #[structopt(long)]
+    pub no_save: bool,
+
+    /// Run in a window instead of fullscreen
+    #[structopt(long)]
+    pub windowed: bool,
+
+    /// Probe every file again instead of using the media index
+    #[structopt(long)]
+    pub rebuild_index: bool,
+
+    /// Seed for the random order, to get the same order every run
+    #[structopt(long, overrides_with = "no_seed")]
+    seed: Option<u64>,
+
+    /// A different random order every run, even if the config has a seed
+    #[structopt(long, overrides_with = "seed")]
+    no_seed: bool,
+
+    /// How to order playback: sequential, shuffle, least-recently-shown or on-this-day
+    #[structopt(long)]
+    order: Option<Order>,
+
+    /// What sequential playback is sorted by: name, natural-name, modified, capture-date or size
+    #[structopt(long)]
+    sort_by: Option<SortBy>,
+
+    /// How many days of not being shown a newly added file is worth, for least-recently-shown
+    #[structopt(long)]
+    new_file_boost_days: Option<f64>,
+
+    /// Sort sequential playback in descending order
+    #[structopt(long, overrides_with = "no_reverse")]
+    reverse: bool,
+
+    /// Sort sequential playback in ascending order
+    #[structopt(long, overrides_with = "reverse")]
+    no_reverse: bool,
+
+    /// Include hidden entries
+    #[structopt(long, overrides_with = "no_hidden")]
+    hidden: bool,
+
+    /// Exclude hidden entries
+    #[structopt(long, overrides_with = "hidden")]
+    no_hidden: bool,
+
+    /// Include videos
+    #[structopt(long, overrides_with = "no_video")]
+    video: bool,
+
+    /// Exclude videos
+    #[structopt(long, overrides_with = "video")]
+    no_video: bool,
+
+    /// Mute audio
+    #[structopt(long, overrides_with = "no_mute")]
+    mute: bool,
+
+    /// Unmute audio
+    #[structopt(long, overrides_with = "mute")]
+    no_mute: bool,
+
+    /// Pick up media added to or removed from the paths while running
+    #[structopt(long, overrides_with = "no_watch")]
+    watch: bool,
+
+    /// Don't watch the paths while running
+    #[structopt(long, overrides_with = "watch")]
+    no_watch: bool,
+
+    /// How long to show each image, in seconds
+    #[structopt(long)]
+    period: Option<f64>,
+
+    /// Only include files matching one of these globs
+    #[structopt(long, number_of_values = 1, overrides_with = "no_include")]
+    include: Vec<String>,
+
+    /// Include every file, even if the config only includes some
+    #[structopt(long, overrides_with = "include")]
+    no_include: bool,
+
+    /// Skip files and directories matching any of these globs
+    #[structopt(long, number_of_values = 1, overrides_with = "no_exclude")]
+    exclude: Vec<String>,
+
+    /// Skip nothing, even if the config excludes some files
+    #[structopt(long, overrides_with = "exclude")]
+    no_exclude: bool,
+
+    /// Walk into linked directories and play linked files
+    #[structopt(long, overrides_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
+
+    /// Skip symlinks
+    #[structopt(long, overrides_with = "follow_symlinks")]
+    no_follow_symlinks: bool,
+
+    /// Skip files with the same content as one found before
+    #[structopt(long, overrides_with = "no_dedupe_content")]
+    dedupe_content: bool,
+
+    /// Don't compare the content of files
+    #[structopt(long, overrides_with = "dedupe_content")]
+    no_dedupe_content: bool,
+
+    /// Skip images that look the same as one found before
+    #[structopt(long, overrides_with = "no_dedupe_similar")]
+    dedupe_similar: bool,
+
+    /// Don't compare how images look
+    #[structopt(long, overrides_with = "dedupe_similar")]
+    no_dedupe_similar: bool,
+
+    /// Only play files whose metadata matches this expression,
+    /// like "width >= 1920 && duration < 60s"
+    #[structopt(long)]
+    filter: Option<String>,
+
+    /// Only play files rated at least this many stars
+    #[structopt(long)]
+    min_rating: Option<u8>,
+
+    /// Only play files with all of these tags, or tags below them
+    #[structopt(long, number_of_values = 1, overrides_with = "no_require_tag")]
+    require_tag: Vec<String>,
+
+    /// Don't require any tags, even if the config does
+    #[structopt(long, overrides_with = "require_tag")]
+    no_require_tag: bool,
+
+    /// Skip files with any of these tags, or tags below them
+    #[structopt(long, number_of_values = 1, overrides_with = "no_exclude_tag")]
+    exclude_tag: Vec<String>,
+
+    /// Don't skip any tags, even if the config does
+    #[structopt(long, overrides_with = "exclude_tag")]
+    no_exclude_tag: bool,
+
+    /// Skip files that take longer than this many seconds to probe
+    #[structopt(long)]
+    probe_timeout: Option<f64>,
+
+    /// Probe files with the ffprobe binary instead of in-process
+    #[structopt(long, overrides_with = "no_ffprobe")]
+    ffprobe: bool,
+
+    /// Probe files in-process
+    #[structopt(long, overrides_with = "ffprobe")]
+    no_ffprobe: bool,
+
+    /// List sources on servers again once their cached listing is this many minutes old
+    #[structopt(long)]
+    listing_cache_mins: Option<f64>,
+
+    /// Show the previews photo servers make instead of the originals
+    #[structopt(long, overrides_with = "no_server_previews")]
+    server_previews: bool,
+
+    /// Show the originals from photo servers
+    #[structopt(long, overrides_with = "server_previews")]
+    no_server_previews: bool,
+
+    /// The API key for photo servers in the paths given on the command line
+    #[structopt(long, requires = "paths")]
+    api_key: Option<String>,
+
+    /// How often each path given on the command line is picked relative to the others when
+    /// randomized, given once for each of them in the same order
+    #[structopt(
+        long,
+        number_of_values = 1,
+        requires = "paths",
+        parse(try_from_str = parse_weight)
+    )]
+    weight: Vec<f64>,
+
+    /// How many directories deep to search the paths given on the command line
+    #[structopt(long, requires = "paths")]
+    max_depth: Option<usize>,
+
+    /// Search these paths instead of the configured ones. They are all searched, with videos and
+    /// sound as set for every path, and what to play from libraries can only be set in the config.
+    #[structopt(parse(from_os_str))]
+    paths: Vec<PathBuf>,
+}
+
+impl Cli {
+    /// Parses the arguments, exiting with the usage if they don't fit together
+    pub fn parse() -> Self {
+        let cli = Self::from_args();
+        cli.check().unwrap_or_else(|err| err.exit());
+        cli
+    }
+
+    /// What clap can't check by itself
+    fn check(&self) -> Result<(), clap::Error> {
+        if !self.weight.is_empty() && self.weight.len() != self.paths.len() {
+            let description = format!(
+                "--weight was given {} times for {} paths, instead of once for each",
+                self.weight.len(),
+                self.paths.len()
+            );
+            return Err(clap::Error::with_description(
+                &description,
+                clap::ErrorKind::WrongNumberOfValues,
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, opts: &mut Options) {
+        if let Some(order) = self.order {
+            opts.order = order;
+        }
+        if let Some(sort_by) = self.sort_by {
+            opts.sort_by = sort_by;
+        }
+        if let Some(new_file_boost_days) = self.new_file_boost_days {
+            opts.new_file_boost_days = new_file_boost_days;
+        }
+        apply_flag(&mut opts.reverse_sort, self.reverse, self.no_reverse);
+        apply_flag(&mut opts.hidden, self.hidden, self.no_hidden);
+        apply_flag(&mut opts.video, self.video, self.no_video);
+        apply_flag(&mut opts.mute, self.mute, self.no_mute);
+        apply_flag(&mut opts.watch, self.watch, self.no_watch);
+        apply_flag(
+            &mut opts.follow_symlinks,
+            self.follow_symlinks,
+            self.no_follow_symlinks,
+        );
+        apply_flag(
+            &mut opts.dedupe_content,
+            self.dedupe_content,
+            self.no_dedupe_content,
+        );
+        apply_flag(
+            &mut opts.dedupe_similar,
+            self.dedupe_similar,
+            self.no_dedupe_similar,
+        );
+        if let Some(period) = self.period {
+            opts.period_secs = period;
+        }
+        apply_flag(&mut opts.use_ffprobe, self.ffprobe, self.no_ffprobe);
+        if let Some(probe_timeout) = self.probe_timeout {
+            opts.probe_timeout_secs = probe_timeout;
+        }
+        if let Some(listing_cache_mins) = self.listing_cache_mins {
+            opts.listing_cache_mins = listing_cache_mins;
+        }
+        apply_list(&mut opts.include, &self.include, self.no_include);
+        apply_list(&mut opts.exclude, &self.exclude, self.no_exclude);
+        if let Some(filter) = &self.filter {
+            opts.filter = filter.clone();
+        }
+        if let Some(min_rating) = self.min_rating {
+            opts.min_rating = min_rating;
+        }
+        apply_list(
+            &mut opts.required_tags,
+            &self.require_tag,
+            self.no_require_tag,
+        );
+        apply_list(
+            &mut opts.excluded_tags,
+            &self.exclude_tag,
+            self.no_exclude_tag,
+        );
+        apply_flag(
+            &mut opts.server_previews,
+            self.server_previews,
+            self.no_server_previews,
+        );
+        if !self.paths.is_empty() {
+            let api_key = self.api_key.clone().unwrap_or_default();
+            opts.paths = self
+                .paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| {
+                    let mut source = Source {
+                        api_key: api_key.clone(),
+                        max_depth: self.max_depth,
+                        ..Source::new(path.clone())
+                    };
+                    if let Some(&weight) = self.weight.get(i) {
+                        source.weight = weight;
+                    }
+                    source
+                })
+                .collect();
+        }
+        if self.seed.is_some() {
+            opts.seed = self.seed;
+        } else if self.no_seed {
+            opts.seed = None;
+        }
+    }
+}
+
+/// Within the range the settings allow
+fn parse_weight(value: &str) -> Result<f64, String> {
+    let weight: f64 = value.parse().map_err(|err| format!("{err}"))?;
+    if (0.0..=100.0).contains(&weight) {
+        Ok(weight)
+    } else {
+        Err("must be between 0 and 100".to_string())
+    }
+}
+
+fn apply_flag(value: &mut bool, on: bool, off: bool) {
+    if on {
+        *value = true;
+    } else if off {
+        *value = false;
+    }
+}
+
+/// Replaces the list with the given items, or with none if cleared
+fn apply_list(value: &mut Vec<String>, items: &[String], clear: bool) {
+    if !items.is_empty() {
+        *value = items.to_vec();
+    } else if clear {
+        value.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(args: &[&str]) -> Options {
+        let cli =
+            Cli::from_iter_safe(std::iter::once("abelscreensaver").chain(args.iter().copied()))
+                .unwrap();
+        let mut opts = Options::default();
+        cli.apply(&mut opts);
+        opts
+    }
+
+    #[test]
+    fn the_last_of_a_flag_and_its_negation_wins() {
+        assert!(!applied(&["--reverse", "--no-reverse"]).reverse_sort);
+        assert!(applied(&["--no-reverse", "--reverse"]).reverse_sort);
+        assert!(!applied(&["--video", "--no-video"]).video);
+        assert_eq!(applied(&["--seed", "3", "--no-seed"]).seed, None);
+        assert_eq!(applied(&["--no-seed", "--seed", "3"]).seed, Some(3));
+        assert!(applied(&["--exclude", "*.gif", "--no-exclude"])
+            .exclude
+            .is_empty());
+        assert_eq!(
+            applied(&["--no-exclude", "--exclude", "*.gif"]).exclude,
+            ["*.gif"]
+        );
+    }
+
+    #[test]
+    fn sets_the_paths_given() {
+        let opts = applied(&[
+            "--weight",
+            "2",
+            "--weight",
+            "0.5",
+            "--max-depth",
+            "1",
+            "/photos",
+            "/videos",
+        ]);
+        let sources: Vec<_> = opts
+            .paths
+            .iter()
+            .map(|x| (x.path.to_str().unwrap(), x.weight, x.max_depth))
+            .collect();
+        assert_eq!(
+            sources,
+            [("/photos", 2.0, Some(1)), ("/videos", 0.5, Some(1))]
+        );
+    }
+
+    #[test]
+    fn rejects_settings_for_paths_without_paths() {
+        let parse = |args: &[&str]| {
+            Cli::from_iter_safe(std::iter::once("abelscreensaver").chain(args.iter().copied()))
+        };
+        assert!(parse(&["--weight", "2"]).is_err());
+        assert!(parse(&["--max-depth", "1"]).is_err());
+        assert!(parse(&["--api-key", "x"]).is_err());
+        assert!(parse(&["--weight", "-1", "/photos"]).is_err());
+        let cli = parse(&["--weight", "2", "/photos", "/videos"]).unwrap();
+        assert!(cli.check().is_err());
+    }
+}
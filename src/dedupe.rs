@@ -0,0 +1,294 @@
+use crate::diagnostics::{Diagnostics, Reason};
+use crate::location;
+use crate::media_index::MediaIndex;
+use crate::Options;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Inner {
+    // Kept files by device and inode, to recognize the same file reached through another path
+    identities: HashMap<(u64, u64), PathBuf>,
+    // Kept files without an identity, so that one reported again isn't taken for a copy of itself
+    unidentified: HashSet<PathBuf>,
+    // Kept files by size, with their content hash once another file of that size showed up
+    sizes: HashMap<u64, Vec<(PathBuf, Option<u64>)>>,
+    // Kept images by each byte of their similarity hash. Hashes that differ in fewer bits than
+    // there are bytes share at least one byte, so only those buckets need to be compared.
+    similar: [HashMap<u8, Vec<(PathBuf, u64)>>; 8],
+    skipped: usize,
+}
+
+/// Skips files that were seen before: the same file reached through another path, and if enabled
+/// copies with the same content or images that look the same
+///
+/// Cloning is cheap and every clone shares what was seen
+#[derive(Clone)]
+pub struct Deduper {
+    index: MediaIndex,
+    diagnostics: Diagnostics,
+    follow_symlinks: bool,
+    by_content: bool,
+    by_similarity: bool,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Deduper {
+    // At most 7, see `Inner::similar`
+    const MAX_SIMILAR_DISTANCE: u32 = 4;
+
+    pub fn new(opts: &Options, index: MediaIndex, diagnostics: Diagnostics) -> Self {
+        Self {
+            index,
+            diagnostics,
+            follow_symlinks: opts.follow_symlinks,
+            by_content: opts.dedupe_content,
+            by_similarity: opts.dedupe_similar,
+            inner: Arc::default(),
+        }
+    }
+
+    /// Whether the file duplicates one that was kept before, keeping it otherwise
+    pub fn is_duplicate(&self, path: &Path) -> bool {
+        // Members of an archive share its identity and files on servers have none, so they can
+        // only be compared by content
+        let metadata = match location::is_local(path) {
+            true => match std::fs::metadata(path) {
+                Ok(metadata) => Some(metadata),
+                Err(_) => return false,
+            },
+            false => None,
+        };
+        let identity = metadata.as_ref().map(|x| (x.dev(), x.ino()));
+        // A file can only be reached twice through a link, and unless it is compared by content
+        // nothing has to be remembered about the others, which keeps memory bounded on large
+        // libraries
+        let is_linked = metadata
+            .as_ref()
+            .is_some_and(|x| x.nlink() > 1 || self.follow_symlinks);
+        if !is_linked && !self.by_content && !self.by_similarity {
+            return false;
+        }
+        let size = match &metadata {
+            Some(metadata) => metadata.len(),
+            None if self.by_content => location::len(path).unwrap_or_default(),
+            None => 0,
+        };
+        // Hashing reads whole files, so it happens without holding the lock, which is taken again
+        // to check what was kept meanwhile
+        let mut content_hashes = HashMap::new();
+        let mut similarity_hash = None;
+        loop {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(kept) = identity.and_then(|x| inner.identities.get(&x)) {
+                // Reported again, such as after being modified
+                if kept == path {
+                    return false;
+                }
+                let detail = format!("the same file as {}", kept.display());
+                return self.skip(&mut inner, path, detail);
+            }
+            if identity.is_none() && inner.unidentified.contains(path) {
+                return false;
+            }
+            let unhashed = match self.by_content {
+                true => Self::unhashed(&mut inner, path, size, &content_hashes),
+                false => vec![],
+            };
+            if unhashed.is_empty() {
+                let content_hash = content_hashes.get(path).copied().flatten();
+                let same_content = content_hash.and_then(|hash| {
+                    inner.sizes[&size]
+                        .iter()
+                        .find(|(_, other)| *other == Some(hash))
+                        .map(|(other, _)| other.clone())
+                });
+                if let Some(other) = same_content {
+                    let detail = format!("the same content as {}", other.display());
+                    return self.skip(&mut inner, path, detail);
+                }
+                if similarity_hash.is_some() || !self.by_similarity {
+                    let similarity_hash = similarity_hash.flatten();
+                    if let Some(other) =
+                        similarity_hash.and_then(|hash| Self::find_similar(&inner, hash))
+                    {
+                        let detail = format!("looks like {}", other.display());
+                        return self.skip(&mut inner, path, detail);
+                    }
+                    self.keep(
+                        &mut inner,
+                        path,
+                        identity,
+                        size,
+                        content_hash,
+                        similarity_hash,
+                    );
+                    return false;
+                }
+            }
+            drop(inner);
+            if unhashed.is_empty() {
+                similarity_hash = Some(self.index.similarity_hash(path));
+            }
+            for other in unhashed {
+                let hash = self.index.content_hash(&other);
+                content_hashes.insert(other, hash);
+            }
+        }
+    }
+
+    /// Files are only hashed once another kept file has the same size, and then that one too.
+    /// Takes the hashes of kept files that were computed, and returns the files left to hash.
+    fn unhashed(
+        inner: &mut Inner,
+        path: &Path,
+        size: u64,
+        hashes: &HashMap<PathBuf, Option<u64>>,
+    ) -> Vec<PathBuf> {
+        let Some(others) = inner.sizes.get_mut(&size) else {
+            return vec![];
+        };
+        let mut unhashed = vec![];
+        for (other, other_hash) in others.iter_mut() {
+            if other_hash.is_none() {
+                match hashes.get(other) {
+                    Some(hash) => *other_hash = *hash,
+                    None => unhashed.push(other.clone()),
+                }
+            }
+        }
+        if !hashes.contains_key(path) {
+            unhashed.push(path.to_path_buf());
+        }
+        unhashed
+    }
+
+    fn keep(
+        &self,
+        inner: &mut Inner,
+        path: &Path,
+        identity: Option<(u64, u64)>,
+        size: u64,
+        content_hash: Option<u64>,
+        similarity_hash: Option<u64>,
+    ) {
+        match identity {
+            Some(identity) => {
+                inner.identities.insert(identity, path.to_path_buf());
+            }
+            None => {
+                inner.unidentified.insert(path.to_path_buf());
+            }
+        }
+        if self.by_content {
+            inner
+                .sizes
+                .entry(size)
+                .or_default()
+                .push((path.to_path_buf(), content_hash));
+        }
+        if let Some(hash) = similarity_hash {
+            for (i, buckets) in inner.similar.iter_mut().enumerate() {
+                let byte = (hash >> (i * 8)) as u8;
+                buckets
+                    .entry(byte)
+                    .or_default()
+                    .push((path.to_path_buf(), hash));
+            }
+        }
+    }
+
+    fn find_similar(inner: &Inner, hash: u64) -> Option<PathBuf> {
+        inner.similar.iter().enumerate().find_map(|(i, buckets)| {
+            let byte = (hash >> (i * 8)) as u8;
+            buckets.get(&byte)?.iter().find_map(|(other, other_hash)| {
+                let is_similar = (other_hash ^ hash).count_ones() <= Self::MAX_SIMILAR_DISTANCE;
+                is_similar.then(|| other.clone())
+            })
+        })
+    }
+
+    fn skip(&self, inner: &mut Inner, path: &Path, detail: String) -> bool {
+        inner.skipped += 1;
+        self.diagnostics
+            .reject(path, Reason::Duplicate, Some(detail));
+        true
+    }
+
+    /// Forget kept files at or below the path, so that copies elsewhere are kept from now on
+    pub fn forget(&self, path: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        inner.identities.retain(|_, x| !x.starts_with(path));
+        inner.unidentified.retain(|x| !x.starts_with(path));
+        for files in inner.sizes.values_mut() {
+            files.retain(|(x, _)| !x.starts_with(path));
+        }
+        for buckets in &mut inner.similar {
+            for bucket in buckets.values_mut() {
+                bucket.retain(|(x, _)| !x.starts_with(path));
+            }
+        }
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.inner.lock().unwrap().skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::Playable;
+    use crate::test_dir::TestDir;
+
+    fn deduper() -> Deduper {
+        let opts = Options {
+            dedupe_content: true,
+            ..Default::default()
+        };
+        let index = MediaIndex::default();
+        index.set_prober(Arc::new(Playable));
+        Deduper::new(&opts, index, Diagnostics::default())
+    }
+
+    #[test]
+    fn keeps_archive_members_reported_again() {
+        let dir = TestDir::new();
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, contents) in [("a.jpg", b"one"), ("b.jpg", b"one"), ("c.jpg", b"two")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, &contents[..])
+                .unwrap();
+        }
+        let archive = dir.file("album.tar", &builder.into_inner().unwrap());
+        let deduper = deduper();
+        assert!(!deduper.is_duplicate(&archive.join("a.jpg")));
+        assert!(!deduper.is_duplicate(&archive.join("a.jpg")));
+        assert!(deduper.is_duplicate(&archive.join("b.jpg")));
+        assert!(!deduper.is_duplicate(&archive.join("c.jpg")));
+        assert!(!deduper.is_duplicate(&archive.join("a.jpg")));
+        assert_eq!(deduper.skipped(), 1);
+    }
+
+    #[test]
+    fn skips_copies_on_disk() {
+        let dir = TestDir::new();
+        let a = dir.file("a.jpg", b"one");
+        let b = dir.file("b/a.jpg", b"one");
+        let c = dir.file("c.jpg", b"two");
+        let deduper = deduper();
+        assert!(!deduper.is_duplicate(&a));
+        assert!(!deduper.is_duplicate(&a));
+        assert!(deduper.is_duplicate(&b));
+        assert!(!deduper.is_duplicate(&c));
+        deduper.forget(&a);
+        assert!(!deduper.is_duplicate(&b));
+    }
+}
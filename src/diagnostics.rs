@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Why a file was passed over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    // Not an image or video going by its extension
+    NotMedia,
+    // Skipped by the hidden, include, exclude or ignore file rules
+    Excluded,
+    // Couldn't be read for lack of permission
+    PermissionDenied,
+    // A directory that couldn't be listed
+    UnreadableDir,
+    // A playlist that couldn't be read
+    UnreadablePlaylist,
+    // An archive that couldn't be listed
+    UnreadableArchive,
+    // A digiKam or Shotwell library that couldn't be read
+    UnreadableLibrary,
+    // Listed in a playlist or library but not found
+    Missing,
+    // Probing failed or timed out
+    ProbeFailed,
+    // Didn't match the filter expression, or the rating and tag options
+    Filtered,
+    // The same as a file found before
+    Duplicate,
+}
+
+impl Reason {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::NotMedia => "Not media",
+            Self::Excluded => "Excluded",
+            Self::PermissionDenied => "Permission denied",
+            Self::UnreadableDir => "Unreadable directory",
+            Self::UnreadablePlaylist => "Unreadable playlist",
+            Self::UnreadableArchive => "Unreadable archive",
+            Self::UnreadableLibrary => "Unreadable library",
+            Self::Missing => "Missing",
+            Self::ProbeFailed => "Probe failed",
+            Self::Filtered => "Filtered out",
+            Self::Duplicate => "Duplicate",
+        }
+    }
+
+    /// Tells a missing permission apart from other errors
+    pub fn of_io_error(err: &std::io::Error, otherwise: Self) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::PermissionDenied => Self::PermissionDenied,
+            _ => otherwise,
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize)]
+struct Rejection {
+    // Lossy, as JSON can't hold paths that aren't UTF-8
+    path: String,
+    reason: Reason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    counts: BTreeMap<Reason, usize>,
+    // The most recent, newest last
+    rejected: VecDeque<Rejection>,
+}
+
+/// How many files the scan passed over and why, with the most recent of them
+///
+/// Cloning is cheap and every clone shares the same counts
+#[derive(Clone, Default)]
+pub struct Diagnostics {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Diagnostics {
+    // Keeps memory bounded on large libraries, the counts are still complete
+    const MAX_REJECTED: usize = 1000;
+
+    fn file_path() -> PathBuf {
+        let project_dirs = directories::ProjectDirs::from("", "", "abelscreensaver").unwrap();
+        project_dirs.cache_dir().join("diagnostics.json")
+    }
+
+    pub fn reject(&self, path: &Path, reason: Reason, detail: Option<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.counts.entry(reason).or_default() += 1;
+        if inner.rejected.len() >= Self::MAX_REJECTED {
+            inner.rejected.pop_front();
+        }
+        inner.rejected.push_back(Rejection {
+            path: path.to_string_lossy().into_owned(),
+            reason,
+            detail,
+        });
+    }
+
+    /// Forget everything, when scanning starts over
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.counts.clear();
+        inner.rejected.clear();
+    }
+
+    fn to_json(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        serde_json::to_string_pretty(&serde_json::json!({
+            "counts": inner.counts,
+            "rejected": inner.rejected,
+        }))
+        .unwrap()
+    }
+
+    /// Writes the counts and the most recent rejected files as JSON to the cache dir
+    pub fn export(&self) -> std::io::Result<PathBuf> {
+        let file_path = Self::file_path();
+        std::fs::create_dir_all(file_path.parent().unwrap())?;
+        std::fs::write(&file_path, self.to_json())?;
+        Ok(file_path)
+    }
+
+    pub fn ui(&self, ui: &mut egui::Ui) {
+        let inner = self.inner.lock().unwrap();
+        if inner.counts.is_empty() {
+            ui.label("Nothing was skipped");
+            return;
+        }
+        egui::Grid::new("diagnostics_counts")
+            .num_columns(2)
+            .spacing(egui::vec2(16.0, 4.0))
+            .show(ui, |ui| {
+                for (reason, count) in &inner.counts {
+                    ui.label(reason.label());
+                    ui.label(count.to_string());
+                    ui.end_row();
+                }
+            });
+        ui.separator();
+        egui::ScrollArea::vertical()
+            .max_height(240.0)
+            .auto_shrink([false, true])
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for rejection in &inner.rejected {
+                    let label =
+                        ui.label(format!("{}: {}", rejection.reason.label(), rejection.path));
+                    if let Some(detail) = &rejection.detail {
+                        label.on_hover_text(detail);
+                    }
+                }
+            });
+    }
+}
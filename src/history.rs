@@ -0,0 +1,143 @@
+use crate::location;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Inner {
+    // Files shown before this belong to an earlier cycle
+    cycle_start: SystemTime,
+    last_shown: HashMap<PathBuf, SystemTime>,
+    #[serde(skip)]
+    unsaved: usize,
+    // Where the history is saved, only kept in memory if None
+    #[serde(skip)]
+    file_path: Option<PathBuf>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self {
+            cycle_start: SystemTime::UNIX_EPOCH,
+            last_shown: HashMap::new(),
+            unsaved: 0,
+            file_path: None,
+        }
+    }
+}
+
+/// Persistent record of when each file was last shown, so that shuffling works like a deck
+/// across sessions: files shown in the current cycle wait until the whole library has been shown.
+/// A default one is only kept in memory.
+///
+/// Cloning is cheap and every clone shares the same record
+#[derive(Clone, Default)]
+pub struct History {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl History {
+    const SAVE_INTERVAL: usize = 20;
+
+    fn file_path() -> PathBuf {
+        let project_dirs = directories::ProjectDirs::from("", "", "abelscreensaver").unwrap();
+        project_dirs.cache_dir().join("history.json")
+    }
+
+    /// Loads the history from the cache dir, starting empty if it is missing or unreadable
+    pub fn load() -> Self {
+        let file_path = Self::file_path();
+        let inner = std::fs::File::open(&file_path)
+            .ok()
+            .and_then(|file| serde_json::from_reader(std::io::BufReader::new(file)).ok())
+            .unwrap_or_default();
+        let inner = Inner {
+            file_path: Some(file_path),
+            ..inner
+        };
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    pub fn save(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(file_path) = inner.file_path.clone() else {
+            return;
+        };
+        // JSON keys are strings, so paths that aren't UTF-8 are only remembered while running
+        let last_shown: HashMap<&PathBuf, &SystemTime> = inner
+            .last_shown
+            .iter()
+            .filter(|(path, _)| path.to_str().is_some())
+            .collect();
+        let serialized = serde_json::to_string(&serde_json::json!({
+            "cycle_start": inner.cycle_start,
+            "last_shown": last_shown,
+        }));
+        inner.unsaved = 0;
+        drop(inner);
+        let serialized = match serialized {
+            Ok(serialized) => serialized,
+            Err(err) => {
+                eprintln!("Failed to save the history: {err}");
+                return;
+            }
+        };
+        if let Some(Err(err)) = file_path.parent().map(std::fs::create_dir_all) {
+            eprintln!("Failed to save the history: {err}");
+            return;
+        }
+        // Write then rename so a crash mid-write can't corrupt the history
+        let tmp_path = file_path.with_extension("json.tmp");
+        if std::fs::write(&tmp_path, serialized).is_ok() {
+            std::fs::rename(tmp_path, file_path).ok();
+        }
+    }
+
+    pub fn mark_shown(&self, path: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .last_shown
+            .insert(path.to_path_buf(), SystemTime::now());
+        inner.unsaved += 1;
+        let needs_save = inner.unsaved >= Self::SAVE_INTERVAL;
+        drop(inner);
+        if needs_save {
+            self.save();
+        }
+    }
+
+    pub fn last_shown(&self, path: &Path) -> Option<SystemTime> {
+        self.inner.lock().unwrap().last_shown.get(path).copied()
+    }
+
+    pub fn is_shown_in_cycle(&self, path: &Path) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .last_shown
+            .get(path)
+            .is_some_and(|&time| time >= inner.cycle_start)
+    }
+
+    /// Start over once everything has been shown, forgetting files that are gone
+    pub fn next_cycle(&self) {
+        let paths: Vec<PathBuf> = self
+            .inner
+            .lock()
+            .unwrap()
+            .last_shown
+            .keys()
+            .cloned()
+            .collect();
+        let gone: Vec<PathBuf> = paths.into_iter().filter(|x| !location::exists(x)).collect();
+        let mut inner = self.inner.lock().unwrap();
+        for path in gone {
+            inner.last_shown.remove(&path);
+        }
+        inner.cycle_start = SystemTime::now();
+        drop(inner);
+        self.save();
+    }
+}
@@ -0,0 +1,508 @@
+use crate::playlist::{percent_decode, Entry};
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// The albums and tags of each library shown in the settings, or None if they couldn't be read,
+// until the database changes
+static CHOICES: Mutex<BTreeMap<PathBuf, (SystemTime, Option<Choices>)>> =
+    Mutex::new(BTreeMap::new());
+
+// digiKam keeps its own tags, like the ones marking faces, below this one
+const DIGIKAM_INTERNAL_TAGS: &str = "_Digikam_Internal_Tags_";
+
+/// Which files of a library are played
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Selection {
+    // An album in digiKam or an event in Shotwell, with the albums below it. Any if empty
+    pub album: String,
+
+    // A tag, with the tags below it. Any if empty
+    pub tag: String,
+
+    // At least this many stars, from 0 to 5
+    pub min_rating: u8,
+}
+
+/// The albums and tags to select from
+#[derive(Clone, Default)]
+pub struct Choices {
+    pub albums: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    DigiKam,
+    Shotwell,
+}
+
+/// Whether the file is read as a digiKam or Shotwell database rather than walked or shown
+pub fn is_library(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("db"))
+}
+
+fn open(path: &Path) -> Result<(Connection, Kind), String> {
+    // Read only, so that it can stay open in digiKam or Shotwell
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let connection = Connection::open_with_flags(path, flags).map_err(|err| err.to_string())?;
+    let has_table = |name: &str| {
+        connection
+            .query_row(
+                "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                [name],
+                |_| Ok(()),
+            )
+            .optional()
+            .map_err(|err| err.to_string())
+            .map(|x| x.is_some())
+    };
+    let kind = if has_table("Images")? && has_table("AlbumRoots")? {
+        Kind::DigiKam
+    } else if has_table("PhotoTable")? {
+        Kind::Shotwell
+    } else {
+        return Err("not a digiKam or Shotwell database".to_string());
+    };
+    Ok((connection, kind))
+}
+
+/// The files in the selection, as long as they are still in the library. They might have been
+/// moved or deleted since it was last scanned.
+pub fn read(path: &Path, selection: &Selection) -> Result<Vec<Entry>, String> {
+    let (connection, kind) = open(path)?;
+    let result = match kind {
+        Kind::DigiKam => read_digikam(&connection, selection),
+        Kind::Shotwell => read_shotwell(&connection, selection),
+    };
+    result.map_err(|err| err.to_string())
+}
+
+fn read_digikam(connection: &Connection, selection: &Selection) -> rusqlite::Result<Vec<Entry>> {
+    let tagged = match selection.tag.as_str() {
+        "" => None,
+        tag => {
+            let ids = selected_tags(&digikam_tags(connection)?, tag);
+            let mut statement = connection.prepare("SELECT imageid, tagid FROM ImageTags")?;
+            let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            let mut tagged = HashSet::new();
+            for row in rows {
+                let (image, tag): (i64, i64) = row?;
+                if ids.contains(&tag) {
+                    tagged.insert(image);
+                }
+            }
+            Some(tagged)
+        }
+    };
+    let mut statement = connection.prepare(
+        "SELECT Images.id, AlbumRoots.identifier, AlbumRoots.specificPath, Albums.relativePath,
+                Images.name,
+                (SELECT comment FROM ImageComments
+                 WHERE imageid = Images.id AND type = 3 AND comment != '' LIMIT 1)
+         FROM Images
+         JOIN Albums ON Albums.id = Images.album
+         JOIN AlbumRoots ON AlbumRoots.id = Albums.albumRoot
+         LEFT JOIN ImageInformation ON ImageInformation.imageid = Images.id
+         WHERE Images.status = 1
+           AND (?1 = '' OR Albums.relativePath = ?1
+                OR substr(Albums.relativePath, 1, length(?1) + 1) = ?1 || '/')
+           AND max(coalesce(ImageInformation.rating, 0), 0) >= ?2",
+    )?;
+    let rows = statement.query_map(
+        rusqlite::params![selection.album, selection.min_rating],
+        |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, Option<String>>(5)?,
+            ))
+        },
+    )?;
+    // Resolved once per root, which might mean looking up where a volume is mounted
+    let mut roots: HashMap<(String, String), Option<PathBuf>> = HashMap::new();
+    let mut entries = vec![];
+    for row in rows {
+        let (id, identifier, specific_path, album, name, title) = row?;
+        if tagged.as_ref().is_some_and(|x| !x.contains(&id)) {
+            continue;
+        }
+        let root = roots.entry((identifier, specific_path)).or_insert_with_key(
+            |(identifier, specific_path)| {
+                let root = digikam_album_root(identifier, specific_path);
+                if root.is_none() {
+                    eprintln!("Can't find the digiKam collection {identifier}");
+                }
+                root
+            },
+        );
+        if let Some(root) = root {
+            entries.push(Entry {
+                path: root.join(album.trim_start_matches('/')).join(name),
+                title,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Where a collection is, from its identifier like `volumeid:?uuid=...` or
+/// `volumeid:?path=%2Fhome%2Fme%2FPictures` and its path on that volume
+fn digikam_album_root(identifier: &str, specific_path: &str) -> Option<PathBuf> {
+    let (_, query) = identifier.split_once('?')?;
+    let params: HashMap<&str, PathBuf> = query
+        .split('&')
+        .filter_map(|x| x.split_once('='))
+        .map(|(key, value)| {
+            let value = percent_decode(value.as_bytes());
+            (key, PathBuf::from(OsStr::from_bytes(&value)))
+        })
+        .collect();
+    let volume = match (
+        params.get("path"),
+        params.get("mountpath"),
+        params.get("uuid"),
+    ) {
+        (Some(path), _, _) | (_, Some(path), _) => path.clone(),
+        (_, _, Some(uuid)) => mount_point(uuid)?,
+        _ => return None,
+    };
+    Some(volume.join(specific_path.trim_start_matches('/')))
+}
+
+/// Where the volume with the filesystem UUID is mounted
+fn mount_point(uuid: &Path) -> Option<PathBuf> {
+    let device = std::fs::canonicalize(Path::new("/dev/disk/by-uuid").join(uuid)).ok()?;
+    let mounts = std::fs::read("/proc/mounts").ok()?;
+    mounts.split(|&x| x == b'\n').find_map(|line| {
+        let mut fields = line.split(|&x| x == b' ');
+        let mounted = std::fs::canonicalize(OsStr::from_bytes(fields.next()?)).ok()?;
+        let mount_point = unescape_mount(fields.next()?);
+        (mounted == device).then(|| PathBuf::from(OsStr::from_bytes(&mount_point)))
+    })
+}
+
+/// Undoes the octal escapes of spaces and the like in `/proc/mounts`
+fn unescape_mount(bytes: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 8).ok());
+        match octal {
+            Some(byte) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            None => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    unescaped
+}
+
+/// The tags by id, named by their path like `People/Alice`, without the internal ones
+fn digikam_tags(connection: &Connection) -> rusqlite::Result<BTreeMap<i64, String>> {
+    let mut statement = connection.prepare("SELECT id, pid, name FROM Tags")?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let mut parents: HashMap<i64, (i64, String)> = HashMap::new();
+    for row in rows {
+        let (id, pid, name): (i64, Option<i64>, String) = row?;
+        parents.insert(id, (pid.unwrap_or_default(), name));
+    }
+    let mut tags = BTreeMap::new();
+    for &id in parents.keys() {
+        let mut names = vec![];
+        let mut current = id;
+        // Guards against a cycle in a broken database
+        while let Some((pid, name)) = parents.get(&current).filter(|_| names.len() < 64) {
+            names.push(name.as_str());
+            current = *pid;
+        }
+        if names.last() != Some(&DIGIKAM_INTERNAL_TAGS) {
+            names.reverse();
+            tags.insert(id, names.join("/"));
+        }
+    }
+    Ok(tags)
+}
+
+/// The ids of the tag and the tags below it
+fn selected_tags(tags: &BTreeMap<i64, String>, tag: &str) -> HashSet<i64> {
+    tags.iter()
+        .filter(|(_, path)| {
+            path.strip_prefix(tag)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .map(|(&id, _)| id)
+        .collect()
+}
+
+fn read_shotwell(connection: &Connection, selection: &Selection) -> rusqlite::Result<Vec<Entry>> {
+    let tagged = match selection.tag.as_str() {
+        "" => None,
+        tag => {
+            let ids = selected_tags(&shotwell_tags(connection)?, tag);
+            let mut statement = connection.prepare("SELECT id, photo_id_list FROM TagTable")?;
+            let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            let mut tagged = HashSet::new();
+            for row in rows {
+                let (id, list): (i64, Option<String>) = row?;
+                if ids.contains(&id) {
+                    // Like `thumb000000000000002a,video-0000000000000003,`
+                    let list = list.unwrap_or_default();
+                    tagged.extend(
+                        list.split(',')
+                            .filter(|x| !x.is_empty())
+                            .map(str::to_string),
+                    );
+                }
+            }
+            Some(tagged)
+        }
+    };
+    let mut entries = vec![];
+    for (table, prefix) in [("PhotoTable", "thumb"), ("VideoTable", "video-")] {
+        // Rejected files are rated -1
+        let mut statement = connection.prepare(&format!(
+            "SELECT id, filename, title FROM {table}
+             WHERE rating >= ?2
+               AND (?1 = '' OR event_id IN (SELECT id FROM EventTable WHERE name = ?1))"
+        ))?;
+        let rows = statement.query_map(
+            rusqlite::params![selection.album, selection.min_rating],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    // As bytes, as file names might not be UTF-8
+                    row.get_ref(1)?.as_bytes()?.to_vec(),
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            },
+        )?;
+        for row in rows {
+            let (id, filename, title) = row?;
+            if tagged
+                .as_ref()
+                .is_some_and(|x| !x.contains(&format!("{prefix}{id:016x}")))
+            {
+                continue;
+            }
+            entries.push(Entry {
+                path: PathBuf::from(OsStr::from_bytes(&filename)),
+                title: title.filter(|x| !x.is_empty()),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// The tags by id, named like `People/Alice`. Shotwell names tags below others by their path
+/// with a leading slash.
+fn shotwell_tags(connection: &Connection) -> rusqlite::Result<BTreeMap<i64, String>> {
+    let mut statement = connection.prepare("SELECT id, name FROM TagTable")?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut tags = BTreeMap::new();
+    for row in rows {
+        let (id, name): (i64, String) = row?;
+        tags.insert(id, name.trim_start_matches('/').to_string());
+    }
+    Ok(tags)
+}
+
+/// The albums and tags in the library, read again only once the database changes. Asked for on
+/// every frame, so a library that can't be read isn't tried again until then either.
+pub fn choices(path: &Path) -> Option<Choices> {
+    let modified = std::fs::metadata(path).and_then(|x| x.modified()).ok()?;
+    let mut cache = CHOICES.lock().unwrap();
+    if let Some((cached_modified, choices)) = cache.get(path) {
+        if *cached_modified == modified {
+            return choices.clone();
+        }
+    }
+    let result = open(path).and_then(|(connection, kind)| {
+        let result = match kind {
+            Kind::DigiKam => digikam_choices(&connection),
+            Kind::Shotwell => shotwell_choices(&connection),
+        };
+        result.map_err(|err| err.to_string())
+    });
+    let choices = result
+        .map_err(|err| eprintln!("Failed to read {}: {err}", path.display()))
+        .ok();
+    cache.insert(path.to_path_buf(), (modified, choices.clone()));
+    choices
+}
+
+fn digikam_choices(connection: &Connection) -> rusqlite::Result<Choices> {
+    let mut statement = connection.prepare(
+        "SELECT DISTINCT relativePath FROM Albums
+         WHERE relativePath != '/' ORDER BY relativePath",
+    )?;
+    let albums = statement
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut tags: Vec<String> = digikam_tags(connection)?.into_values().collect();
+    tags.sort();
+    Ok(Choices { albums, tags })
+}
+
+fn shotwell_choices(connection: &Connection) -> rusqlite::Result<Choices> {
+    let mut statement = connection
+        .prepare("SELECT DISTINCT name FROM EventTable WHERE name != '' ORDER BY name")?;
+    let albums = statement
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut tags: Vec<String> = shotwell_tags(connection)?.into_values().collect();
+    tags.sort();
+    Ok(Choices { albums, tags })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn library(dir: &TestDir, schema: &str) -> PathBuf {
+        let path = dir.path().join("library.db");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(schema)
+            .unwrap();
+        path
+    }
+
+    fn paths(entries: Vec<Entry>) -> Vec<String> {
+        let mut paths: Vec<String> = entries
+            .into_iter()
+            .map(|x| x.path.display().to_string())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn select(album: &str, tag: &str, min_rating: u8) -> Selection {
+        Selection {
+            album: album.to_string(),
+            tag: tag.to_string(),
+            min_rating,
+        }
+    }
+
+    #[test]
+    fn reads_digikam() {
+        let dir = TestDir::new();
+        let path = library(
+            &dir,
+            "CREATE TABLE AlbumRoots (id INTEGER PRIMARY KEY, identifier TEXT, specificPath TEXT);
+             CREATE TABLE Albums (id INTEGER PRIMARY KEY, albumRoot INTEGER, relativePath TEXT);
+             CREATE TABLE Images (id INTEGER PRIMARY KEY, album INTEGER, name TEXT, status INTEGER);
+             CREATE TABLE ImageInformation (imageid INTEGER, rating INTEGER);
+             CREATE TABLE ImageComments (imageid INTEGER, type INTEGER, comment TEXT);
+             CREATE TABLE Tags (id INTEGER PRIMARY KEY, pid INTEGER, name TEXT);
+             CREATE TABLE ImageTags (imageid INTEGER, tagid INTEGER);
+             INSERT INTO AlbumRoots VALUES (1, 'volumeid:?path=%2Fphotos', '/');
+             INSERT INTO Albums VALUES (1, 1, '/'), (2, 1, '/2020'), (3, 1, '/2020/Trip'),
+                 (4, 1, '/2021');
+             INSERT INTO Images VALUES (1, 2, 'a.jpg', 1), (2, 3, 'b.jpg', 1), (3, 4, 'c.jpg', 1),
+                 (4, 2, 'trashed.jpg', 3);
+             INSERT INTO ImageInformation VALUES (1, 3), (2, 1), (3, -1);
+             INSERT INTO ImageComments VALUES (1, 3, 'Beach');
+             INSERT INTO Tags VALUES (1, 0, 'People'), (2, 1, 'Alice'),
+                 (3, 0, '_Digikam_Internal_Tags_'), (4, 3, 'Face');
+             INSERT INTO ImageTags VALUES (1, 2), (2, 4);",
+        );
+        let read_paths = |selection| paths(read(&path, &selection).unwrap());
+        assert_eq!(
+            read_paths(select("", "", 0)),
+            [
+                "/photos/2020/Trip/b.jpg",
+                "/photos/2020/a.jpg",
+                "/photos/2021/c.jpg"
+            ]
+        );
+        assert_eq!(
+            read_paths(select("/2020", "", 0)),
+            ["/photos/2020/Trip/b.jpg", "/photos/2020/a.jpg"]
+        );
+        assert_eq!(
+            read_paths(select("/2020/Trip", "", 0)),
+            ["/photos/2020/Trip/b.jpg"]
+        );
+        assert_eq!(read_paths(select("", "People", 0)), ["/photos/2020/a.jpg"]);
+        assert_eq!(
+            read_paths(select("", "People/Alice", 0)),
+            ["/photos/2020/a.jpg"]
+        );
+        assert_eq!(read_paths(select("", "", 2)), ["/photos/2020/a.jpg"]);
+        assert_eq!(read_paths(select("", "Face", 0)), [] as [&str; 0]);
+        let entries = read(&path, &select("", "People", 0)).unwrap();
+        assert_eq!(entries[0].title.as_deref(), Some("Beach"));
+
+        let choices = choices(&path).unwrap();
+        assert_eq!(choices.albums, ["/2020", "/2020/Trip", "/2021"]);
+        assert_eq!(choices.tags, ["People", "People/Alice"]);
+    }
+
+    #[test]
+    fn reads_shotwell() {
+        let dir = TestDir::new();
+        let path = library(
+            &dir,
+            "CREATE TABLE PhotoTable (id INTEGER PRIMARY KEY, filename TEXT, title TEXT,
+                 rating INTEGER, event_id INTEGER);
+             CREATE TABLE VideoTable (id INTEGER PRIMARY KEY, filename TEXT, title TEXT,
+                 rating INTEGER, event_id INTEGER);
+             CREATE TABLE EventTable (id INTEGER PRIMARY KEY, name TEXT);
+             CREATE TABLE TagTable (id INTEGER PRIMARY KEY, name TEXT, photo_id_list TEXT);
+             INSERT INTO PhotoTable VALUES (1, '/p/1.jpg', 'Sunset', 4, 1),
+                 (2, '/p/rejected.jpg', '', -1, 1), (3, '/p/3.jpg', '', 0, 2);
+             INSERT INTO VideoTable VALUES (1, '/p/v.mp4', NULL, 2, 2);
+             INSERT INTO EventTable VALUES (1, 'Holiday'), (2, 'Party'), (3, '');
+             INSERT INTO TagTable VALUES (1, 'Places', 'thumb0000000000000001,'),
+                 (2, '/Places/Paris', 'video-0000000000000001,thumb0000000000000002,');",
+        );
+        let read_paths = |selection| paths(read(&path, &selection).unwrap());
+        assert_eq!(
+            read_paths(select("", "", 0)),
+            ["/p/1.jpg", "/p/3.jpg", "/p/v.mp4"]
+        );
+        assert_eq!(read_paths(select("Holiday", "", 0)), ["/p/1.jpg"]);
+        assert_eq!(read_paths(select("Party", "", 0)), ["/p/3.jpg", "/p/v.mp4"]);
+        assert_eq!(
+            read_paths(select("", "Places", 0)),
+            ["/p/1.jpg", "/p/v.mp4"]
+        );
+        assert_eq!(read_paths(select("", "Places/Paris", 0)), ["/p/v.mp4"]);
+        assert_eq!(read_paths(select("", "", 2)), ["/p/1.jpg", "/p/v.mp4"]);
+        let entries = read(&path, &select("Holiday", "", 0)).unwrap();
+        assert_eq!(entries[0].title.as_deref(), Some("Sunset"));
+
+        let choices = choices(&path).unwrap();
+        assert_eq!(choices.albums, ["Holiday", "Party"]);
+        assert_eq!(choices.tags, ["Places", "Places/Paris"]);
+    }
+
+    #[test]
+    fn remembers_libraries_that_cant_be_read() {
+        let dir = TestDir::new();
+        let path = library(&dir, "CREATE TABLE Other (id INTEGER);");
+        assert!(choices(&path).is_none());
+        let cache = CHOICES.lock().unwrap();
+        assert!(matches!(cache.get(&path), Some((_, None))));
+    }
+}
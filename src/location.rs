@@ -0,0 +1,204 @@
+use crate::archive;
+use crate::catalog;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+// Copies of files beyond this are deleted, least recently used first
+const MAX_CACHE_BYTES: u64 = 1 << 30;
+
+/// FNV-1a, which unlike the hasher of the standard library hashes the same in every release, for
+/// names that must outlive the build that chose them
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Whether the path is a file on disk, rather than a member of an archive or on a server
+pub fn is_local(path: &Path) -> bool {
+    !catalog::is_catalog(path) && archive::split(path).is_none()
+}
+
+pub fn exists(path: &Path) -> bool {
+    stamp(path).is_ok()
+}
+
+/// The size and modification time of whatever changes whenever the file does: the file itself,
+/// the archive it is in, or its entry in the server's listing
+pub fn stamp(path: &Path) -> io::Result<(u64, SystemTime)> {
+    if catalog::is_catalog(path) {
+        return catalog::stat(path).ok_or_else(|| io::ErrorKind::NotFound.into());
+    }
+    let metadata = match archive::split(path) {
+        Some((archive, _)) => fs::metadata(archive)?,
+        None => fs::metadata(path)?,
+    };
+    Ok((metadata.len(), metadata.modified()?))
+}
+
+pub fn modified(path: &Path) -> io::Result<SystemTime> {
+    Ok(stamp(path)?.1)
+}
+
+/// The size of the file, uncompressed for a member of an archive
+pub fn len(path: &Path) -> io::Result<u64> {
+    match archive::split(path) {
+        Some((archive, member)) => archive::len(archive, member),
+        None => Ok(stamp(path)?.0),
+    }
+}
+
+/// The path as shown to the user, like `album.zip!/page01.jpg` for a member of an archive
+pub fn display(path: &Path) -> String {
+    match archive::split(path) {
+        Some((archive, member)) => format!("{}!/{}", archive.display(), member.display()),
+        None => path.display().to_string(),
+    }
+}
+
+/// A path that other programs can open. Members of archives and images on servers are copied to
+/// the cache dir, while videos on servers are streamed from their URL.
+pub fn local_path(path: &Path) -> io::Result<PathBuf> {
+    if catalog::is_catalog(path) {
+        if !catalog::is_image(path) {
+            return Ok(PathBuf::from(playable_path(path)?));
+        }
+        let modified = modified(path)?;
+        return cached(path, modified, |file| catalog::download(path, file));
+    }
+    match archive::split(path) {
+        Some((archive, member)) => {
+            let modified = fs::metadata(archive)?.modified()?;
+            cached(path, modified, |file| {
+                archive::extract(archive, member, file)
+            })
+        }
+        None => Ok(path.to_path_buf()),
+    }
+}
+
+/// What mpv is given to play the file: a URL for a file on a server, so that it is streamed
+pub fn playable_path(path: &Path) -> io::Result<OsString> {
+    if catalog::is_catalog(path) {
+        return catalog::url(path).map(OsString::from);
+    }
+    local_path(path).map(PathBuf::into_os_string)
+}
+
+/// Copies the file to the cache dir, unless it already was since it was last modified
+fn cached(
+    path: &Path,
+    modified: SystemTime,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<PathBuf> {
+    let cache_dir = cache_dir();
+    let cached = cache_dir.join(cache_name(path, modified));
+    if cached.exists() {
+        // Marks it as recently used
+        File::options()
+            .write(true)
+            .open(&cached)?
+            .set_modified(SystemTime::now())?;
+        return Ok(cached);
+    }
+    fs::create_dir_all(&cache_dir)?;
+    // Unique, as another thread might be copying the same file
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let tmp_path = cache_dir.join(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| write(&mut file))
+        .and_then(|()| fs::rename(&tmp_path, &cached));
+    if result.is_err() {
+        fs::remove_file(&tmp_path).ok();
+    }
+    result?;
+    evict(&cache_dir);
+    Ok(cached)
+}
+
+fn cache_dir() -> PathBuf {
+    let project_dirs = directories::ProjectDirs::from("", "", "abelscreensaver").unwrap();
+    project_dirs.cache_dir().join("files")
+}
+
+/// Named by the path and when it was modified, keeping the extension so that the type can be told
+/// from the name
+fn cache_name(path: &Path, modified: SystemTime) -> PathBuf {
+    let mut hasher = Fnv1a::default();
+    hasher.write(path.as_os_str().as_bytes());
+    let nanos = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    hasher.write(&nanos.to_le_bytes());
+    let mut name = PathBuf::from(format!("{:016x}", hasher.finish()));
+    if let Some(ext) = path.extension() {
+        name.set_extension(ext);
+    }
+    name
+}
+
+/// Deletes the least recently used copies until the cache fits
+fn evict(cache_dir: &Path) {
+    let Ok(entries) = fs::read_dir(cache_dir) else {
+        return;
+    };
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+        .filter_map(|x| x.ok())
+        .filter(|x| !x.file_name().as_bytes().starts_with(b"."))
+        .filter_map(|x| {
+            let metadata = x.metadata().ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), x.path()))
+        })
+        .collect();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    files.sort();
+    for (_, len, path) in files {
+        if total <= MAX_CACHE_BYTES {
+            break;
+        }
+        if fs::remove_file(path).is_ok() {
+            total -= len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_copies_the_same_in_every_release() {
+        let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let name = cache_name(Path::new("https://example.com/a/b.jpg"), modified);
+        assert_eq!(name, Path::new("75b86b5a5320b0df.jpg"));
+        // Published test vector, so that the hash stays FNV-1a
+        let mut hasher = Fnv1a::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+    }
+}
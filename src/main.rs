@@ -1,25 +1,63 @@
+mod archive;
+mod capture_date;
+mod catalog;
+mod cli;
+mod dedupe;
+mod diagnostics;
+mod history;
+mod library;
+mod location;
+mod media_filter;
+mod media_index;
 mod media_iterator;
 mod mpvclient;
 mod overlay;
+mod photo_server;
+mod playlist;
+mod probe;
+mod probe_pool;
+mod remote;
 mod runner;
 mod settings;
+mod sort_key;
+#[cfg(test)]
+mod test_dir;
+#[cfg(test)]
+mod test_server;
+mod walk_filter;
+mod watcher;
+mod xmp;
 
-use crate::settings::Options;
+use crate::cli::Cli;
+use crate::history::History;
+use crate::media_index::MediaIndex;
+use crate::settings::{Config, Options};
 use std::fs::File;
 use std::io::prelude::Write;
 use std::path::PathBuf;
 
 fn main() {
-    ensure_ffprobe_exists();
-    runner::run(Options::load(), black_pixel_path());
-}
-
-fn ensure_ffprobe_exists() {
-    if let Err(ffprobe::FfProbeError::Io(err)) = ffprobe::ffprobe("") {
-        if err.kind() == std::io::ErrorKind::NotFound {
-            panic!("ffprobe: command not found");
-        }
+    let exit_signals = runner::ExitSignals::block();
+    let cli = Cli::parse();
+    let config_path = cli.config.clone().unwrap_or_else(Config::default_path);
+    let (mut config, config_problem) = Config::load(config_path, cli.no_save);
+    config.apply_overrides(|opts| cli.apply(opts));
+    if let Some(problem) = &config_problem {
+        eprintln!("{problem}");
+    }
+    let index = MediaIndex::load();
+    if cli.rebuild_index {
+        index.clear();
     }
+    runner::run(
+        config,
+        index,
+        History::load(),
+        config_problem.into_iter().collect(),
+        cli.windowed,
+        black_pixel_path(),
+        exit_signals,
+    );
 }
 
 /// Writes a black pixel to the temp filesystem and returns the path
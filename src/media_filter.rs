@@ -0,0 +1,637 @@
+use crate::location;
+use crate::media_index::MediaInfo;
+use crate::Options;
+use mime_guess::mime;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// A filter expression over the metadata of a file, like
+/// `width >= 1920 && orientation == landscape && duration < 60s && path !~ "screenshots"`
+///
+/// Comparisons are joined with `&&`, `||`, `!` and parentheses. Text is compared ignoring case, and
+/// `~` tests whether it contains the value. Comparisons against missing metadata are false, except
+/// that images have a duration of 0.
+///
+/// Built from the options, it also applies the minimum rating and the required and excluded tags.
+#[derive(Debug, Clone, Default)]
+pub struct MediaFilter {
+    // None if only the rating and tags filter
+    expr: Option<Expr>,
+    min_rating: u8,
+    // Lowercase, matching tags below them too
+    required_tags: Vec<String>,
+    excluded_tags: Vec<String>,
+}
+
+impl MediaFilter {
+    /// Parses the expression, None if it is blank
+    pub fn parse(str: &str) -> Result<Option<Self>, String> {
+        let tokens = tokenize(str)?;
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        match parser.peek() {
+            Some(token) => Err(format!("unexpected {token}")),
+            None => Ok(Some(Self {
+                expr: Some(expr),
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// The filter of the options, ignoring the expression if it doesn't parse. None if nothing
+    /// is filtered.
+    pub fn from_options(opts: &Options) -> Option<Self> {
+        let expr = Self::parse(&opts.filter)
+            .unwrap_or_else(|err| {
+                eprintln!("Ignoring the filter: {err}");
+                None
+            })
+            .and_then(|x| x.expr);
+        let tags = |tags: &[String]| -> Vec<String> {
+            tags.iter()
+                .map(|x| x.trim().to_lowercase())
+                .filter(|x| !x.is_empty())
+                .collect()
+        };
+        let filter = Self {
+            expr,
+            min_rating: opts.min_rating,
+            required_tags: tags(&opts.required_tags),
+            excluded_tags: tags(&opts.excluded_tags),
+        };
+        let filters = filter.expr.is_some()
+            || filter.min_rating > 0
+            || !filter.required_tags.is_empty()
+            || !filter.excluded_tags.is_empty();
+        filters.then_some(filter)
+    }
+
+    /// Whether the rating and tags are filtered on, which are only read if needed
+    pub fn uses_metadata(&self) -> bool {
+        self.min_rating > 0 || !self.required_tags.is_empty() || !self.excluded_tags.is_empty()
+    }
+
+    /// Why the file doesn't pass, None if it does
+    pub fn rejection(&self, path: &Path, info: &MediaInfo) -> Option<String> {
+        // Unrated files count as 0 stars, and rejected ones as -1
+        if info.rating.unwrap_or(0) < self.min_rating as i8 {
+            return Some(match info.rating {
+                Some(rating) => format!("rated {rating}"),
+                None => "unrated".to_string(),
+            });
+        }
+        if let Some(tag) = self.excluded_tags.iter().find(|x| has_tag(info, x)) {
+            return Some(format!("tagged {tag}"));
+        }
+        if let Some(tag) = self.required_tags.iter().find(|x| !has_tag(info, x)) {
+            return Some(format!("not tagged {tag}"));
+        }
+        if self.expr.as_ref().is_some_and(|x| !x.eval(path, info)) {
+            return Some("doesn't match the expression".to_string());
+        }
+        None
+    }
+}
+
+/// Whether the file has the lowercase tag or one below it, so that `people` matches
+/// `People/Alice`
+fn has_tag(info: &MediaInfo, tag: &str) -> bool {
+    info.tags.iter().any(|x| {
+        let x = x.to_lowercase();
+        x == tag
+            || x.strip_prefix(tag)
+                .is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Width,
+    Height,
+    Orientation,
+    Size,
+    Duration,
+    Codec,
+    Format,
+    Date,
+    Ext,
+    Path,
+    Type,
+}
+
+impl Field {
+    const ALL: [Self; 11] = [
+        Self::Width,
+        Self::Height,
+        Self::Orientation,
+        Self::Size,
+        Self::Duration,
+        Self::Codec,
+        Self::Format,
+        Self::Date,
+        Self::Ext,
+        Self::Path,
+        Self::Type,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Width => "width",
+            Self::Height => "height",
+            Self::Orientation => "orientation",
+            Self::Size => "size",
+            Self::Duration => "duration",
+            Self::Codec => "codec",
+            Self::Format => "format",
+            Self::Date => "date",
+            Self::Ext => "ext",
+            Self::Path => "path",
+            Self::Type => "type",
+        }
+    }
+
+    /// The values a text field can take, if limited
+    fn choices(self) -> Option<&'static [&'static str]> {
+        match self {
+            Self::Orientation => Some(&["landscape", "portrait", "square"]),
+            Self::Type => Some(&["image", "video"]),
+            _ => None,
+        }
+    }
+
+    fn is_text(self) -> bool {
+        matches!(
+            self,
+            Self::Orientation | Self::Codec | Self::Format | Self::Ext | Self::Path | Self::Type
+        )
+    }
+
+    fn parse_number(self, str: &str) -> Result<f64, String> {
+        match self {
+            Self::Size => parse_with_unit(
+                str,
+                &[
+                    ("", 1.0),
+                    ("b", 1.0),
+                    ("kb", 1e3),
+                    ("mb", 1e6),
+                    ("gb", 1e9),
+                    ("kib", 1024.0),
+                    ("mib", 1024.0 * 1024.0),
+                    ("gib", 1024.0 * 1024.0 * 1024.0),
+                ],
+            ),
+            Self::Duration => parse_with_unit(
+                str,
+                &[
+                    ("", 1.0),
+                    ("ms", 1e-3),
+                    ("s", 1.0),
+                    ("m", 60.0),
+                    ("min", 60.0),
+                    ("h", 3600.0),
+                ],
+            ),
+            Self::Date => crate::capture_date::CaptureDate::parse(str)
+                .filter(|_| str.len() == 10)
+                .map(|date| date.timestamp().div_euclid(86400) as f64)
+                .ok_or_else(|| format!("expected a date like 2021-06-01, not {str}")),
+            _ => str
+                .parse()
+                .map_err(|_| format!("expected a number, not {str}")),
+        }
+    }
+
+    fn number(self, path: &Path, info: &MediaInfo) -> Option<f64> {
+        match self {
+            Self::Width => info.width.map(f64::from),
+            Self::Height => info.height.map(f64::from),
+            Self::Size => info.size.map(|x| x as f64),
+            Self::Duration => match media_type(path) {
+                Some("video") => info.duration_secs,
+                _ => Some(0.0),
+            },
+            Self::Date => {
+                let secs = match info.capture_date() {
+                    Some(date) => date.timestamp(),
+                    None => location::modified(path)
+                        .ok()?
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .ok()?
+                        .as_secs() as i64,
+                };
+                Some(secs.div_euclid(86400) as f64)
+            }
+            _ => None,
+        }
+    }
+
+    fn text(self, path: &Path, info: &MediaInfo) -> Option<String> {
+        match self {
+            Self::Orientation => {
+                let (width, height) = (info.width?, info.height?);
+                Some(
+                    match width.cmp(&height) {
+                        std::cmp::Ordering::Greater => "landscape",
+                        std::cmp::Ordering::Less => "portrait",
+                        std::cmp::Ordering::Equal => "square",
+                    }
+                    .to_string(),
+                )
+            }
+            Self::Codec => info.codec.clone(),
+            Self::Format => Some(info.format.clone()),
+            Self::Ext => Some(path.extension()?.to_string_lossy().into_owned()),
+            Self::Path => Some(path.to_string_lossy().into_owned()),
+            Self::Type => Some(media_type(path)?.to_string()),
+            _ => None,
+        }
+    }
+}
+
+fn media_type(path: &Path) -> Option<&'static str> {
+    let mime = mime_guess::from_path(path).first()?;
+    match mime.type_() {
+        mime::IMAGE => Some("image"),
+        mime::VIDEO => Some("video"),
+        _ => None,
+    }
+}
+
+/// A number with one of the given case-insensitive unit suffixes
+fn parse_with_unit(str: &str, units: &[(&str, f64)]) -> Result<f64, String> {
+    let split = str
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(str.len());
+    let (number, unit) = str.split_at(split);
+    let factor = units
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+        .map(|(_, factor)| factor)
+        .ok_or_else(|| format!("unknown unit in {str}"))?;
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("expected a number, not {str}"))?;
+    Ok(number * factor)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+    NotContains,
+}
+
+impl Op {
+    fn from_symbol(symbol: &str) -> Option<Self> {
+        Some(match symbol {
+            "==" => Self::Eq,
+            "!=" => Self::Ne,
+            "<" => Self::Lt,
+            "<=" => Self::Le,
+            ">" => Self::Gt,
+            ">=" => Self::Ge,
+            "~" => Self::Contains,
+            "!~" => Self::NotContains,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, Op, Value),
+}
+
+impl Expr {
+    fn eval(&self, path: &Path, info: &MediaInfo) -> bool {
+        match self {
+            Self::And(a, b) => a.eval(path, info) && b.eval(path, info),
+            Self::Or(a, b) => a.eval(path, info) || b.eval(path, info),
+            Self::Not(a) => !a.eval(path, info),
+            Self::Compare(field, op, Value::Number(value)) => {
+                let Some(number) = field.number(path, info) else {
+                    return false;
+                };
+                match op {
+                    Op::Eq => number == *value,
+                    Op::Ne => number != *value,
+                    Op::Lt => number < *value,
+                    Op::Le => number <= *value,
+                    Op::Gt => number > *value,
+                    Op::Ge => number >= *value,
+                    Op::Contains | Op::NotContains => false,
+                }
+            }
+            Self::Compare(field, op, Value::Text(value)) => {
+                let Some(text) = field.text(path, info) else {
+                    return false;
+                };
+                let text = text.to_lowercase();
+                match op {
+                    Op::Eq => text == *value,
+                    Op::Ne => text != *value,
+                    Op::Contains => text.contains(value.as_str()),
+                    Op::NotContains => !text.contains(value.as_str()),
+                    Op::Lt | Op::Le | Op::Gt | Op::Ge => false,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Symbol(&'static str),
+    Word(String),
+    Quoted(String),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Symbol(symbol) => write!(f, "`{symbol}`"),
+            Self::Word(word) => write!(f, "`{word}`"),
+            Self::Quoted(text) => write!(f, "\"{text}\""),
+        }
+    }
+}
+
+// Longer symbols first, so that `<=` isn't read as `<`
+const SYMBOLS: [&str; 13] = [
+    "&&", "||", "==", "!=", "<=", ">=", "!~", "<", ">", "~", "!", "(", ")",
+];
+
+fn tokenize(str: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut rest = str.trim_start();
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').ok_or("unterminated quote")?;
+            tokens.push(Token::Quoted(quoted[..end].to_string()));
+            rest = &quoted[end + 1..];
+        } else if let Some(symbol) = SYMBOLS.iter().find(|x| rest.starts_with(*x)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || "_.-:/".contains(c)))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(format!("unexpected `{}`", rest.chars().next().unwrap()));
+            }
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("unexpected end")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, symbol: &'static str) -> bool {
+        let is_match = self.peek() == Some(&Token::Symbol(symbol));
+        if is_match {
+            self.pos += 1;
+        }
+        is_match
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err("expected `)`".to_string());
+            }
+            return Ok(expr);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let field = match self.next()? {
+            Token::Word(word) => Field::ALL
+                .into_iter()
+                .find(|field| field.name() == word)
+                .ok_or_else(|| {
+                    let names: Vec<_> = Field::ALL.iter().map(|x| x.name()).collect();
+                    format!("unknown field `{word}`, expected {}", names.join(", "))
+                })?,
+            token => return Err(format!("expected a field, not {token}")),
+        };
+        let op = match self.next()? {
+            Token::Symbol(symbol) => Op::from_symbol(symbol),
+            _ => None,
+        }
+        .ok_or_else(|| format!("expected a comparison after `{}`", field.name()))?;
+        let value = match self.next()? {
+            Token::Word(word) | Token::Quoted(word) => word,
+            token => return Err(format!("expected a value, not {token}")),
+        };
+        let value = if field.is_text() {
+            if matches!(op, Op::Lt | Op::Le | Op::Gt | Op::Ge) {
+                return Err(format!("`{}` can't be compared by size", field.name()));
+            }
+            let value = value.to_lowercase();
+            if let Some(choices) = field.choices() {
+                if !choices.contains(&value.as_str()) {
+                    return Err(format!(
+                        "`{}` is one of {}",
+                        field.name(),
+                        choices.join(", ")
+                    ));
+                }
+            }
+            Value::Text(value)
+        } else {
+            if matches!(op, Op::Contains | Op::NotContains) {
+                return Err(format!("`{}` can't be searched in", field.name()));
+            }
+            Value::Number(field.parse_number(&value)?)
+        };
+        Ok(Expr::Compare(field, op, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video() -> MediaInfo {
+        MediaInfo {
+            width: Some(1920),
+            height: Some(1080),
+            duration_secs: Some(90.0),
+            codec: Some("h264".to_string()),
+            format: "mov,mp4".to_string(),
+            creation_time: Some("2021-06-01T12:00:00Z".to_string()),
+            size: Some(1_500_000_000),
+            ..Default::default()
+        }
+    }
+
+    fn matches(filter: &str, path: &str, info: &MediaInfo) -> bool {
+        let filter = MediaFilter::parse(filter).unwrap().unwrap();
+        filter.rejection(Path::new(path), info).is_none()
+    }
+
+    fn error(filter: &str) -> String {
+        MediaFilter::parse(filter).unwrap_err()
+    }
+
+    #[test]
+    fn blank_is_no_filter() {
+        assert!(MediaFilter::parse("  ").unwrap().is_none());
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let info = video();
+        let path = "/videos/clip.mp4";
+        assert!(matches(
+            "width < 100 && height < 100 || codec == h264",
+            path,
+            &info
+        ));
+        assert!(matches(
+            "codec == h264 || width < 100 && height < 100",
+            path,
+            &info
+        ));
+        assert!(!matches(
+            "(codec == h264 || width < 100) && height < 100",
+            path,
+            &info
+        ));
+        assert!(!matches("!codec == h264 || width < 100", path, &info));
+        assert!(matches("!(codec == vp9 && width > 100)", path, &info));
+    }
+
+    #[test]
+    fn units() {
+        let info = video();
+        let path = "/videos/clip.mp4";
+        assert!(matches("duration > 60s && duration < 2m", path, &info));
+        assert!(matches("duration == 90000ms", path, &info));
+        assert!(matches("size == 1.5GB && size > 1GiB", path, &info));
+        assert!(matches("size < 1.5gib", path, &info));
+        assert!(!matches("size < 1500mb", path, &info));
+        // Images last no time at all
+        assert!(matches(
+            "duration == 0",
+            "/photos/a.jpg",
+            &MediaInfo::default()
+        ));
+    }
+
+    #[test]
+    fn dates() {
+        let info = video();
+        let path = "/videos/clip.mp4";
+        assert!(matches("date == 2021-06-01", path, &info));
+        assert!(matches(
+            "date > 2021-05-31 && date < 2021-06-02",
+            path,
+            &info
+        ));
+        assert!(!matches("date >= 2022-01-01", path, &info));
+    }
+
+    #[test]
+    fn text() {
+        let info = video();
+        let path = "/videos/Holiday Clip.MP4";
+        assert!(matches(
+            "orientation == landscape && type == video",
+            path,
+            &info
+        ));
+        assert!(matches(
+            "ext == mp4 && path ~ \"holiday clip\"",
+            path,
+            &info
+        ));
+        assert!(matches("path !~ screenshots && format ~ MP4", path, &info));
+        // Missing metadata never matches
+        assert!(!matches("codec == h264", path, &MediaInfo::default()));
+        assert!(!matches("codec != h264", path, &MediaInfo::default()));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("colour == red"),
+            "unknown field `colour`, expected width, height, orientation, size, duration, codec, \
+             format, date, ext, path, type"
+        );
+        assert_eq!(error("width >"), "unexpected end");
+        assert_eq!(error("width 10"), "expected a comparison after `width`");
+        assert_eq!(error("width > wide"), "expected a number, not wide");
+        assert_eq!(error("size > 10xb"), "unknown unit in 10xb");
+        assert_eq!(
+            error("date > 2021-6-1"),
+            "expected a date like 2021-06-01, not 2021-6-1"
+        );
+        assert_eq!(error("path > a"), "`path` can't be compared by size");
+        assert_eq!(error("width ~ 10"), "`width` can't be searched in");
+        assert_eq!(
+            error("orientation == round"),
+            "`orientation` is one of landscape, portrait, square"
+        );
+        assert_eq!(error("(width > 10"), "expected `)`");
+        assert_eq!(error("width > 10 )"), "unexpected `)`");
+        assert_eq!(error("path ~ \"open"), "unterminated quote");
+        assert_eq!(error("width > 10 $"), "unexpected `$`");
+    }
+}
@@ -0,0 +1,566 @@
+use crate::archive;
+use crate::capture_date::CaptureDate;
+use crate::catalog;
+use crate::location;
+use crate::probe::{self, Builtin, Prober};
+use crate::xmp;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Metadata recorded for every successfully probed file
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MediaInfo {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration_secs: Option<f64>,
+    pub codec: Option<String>,
+    pub format: String,
+    // From the container or stream tags, or else from EXIF
+    pub creation_time: Option<String>,
+    // In bytes, uncompressed for a member of an archive
+    #[serde(default)]
+    pub size: Option<u64>,
+    // From -1 for rejected to 5 stars, from the XMP or EXIF data or the `.xmp` sidecar
+    #[serde(default)]
+    pub rating: Option<i8>,
+    // Keywords from the same places, see `xmp::Metadata`
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl MediaInfo {
+    pub fn capture_date(&self) -> Option<CaptureDate> {
+        CaptureDate::parse(self.creation_time.as_deref()?)
+    }
+}
+
+/// The date and time the original image was taken, from the EXIF data of images
+fn exif_date_time(reader: &mut (impl BufRead + Seek)) -> Option<String> {
+    let exif = exif::Reader::new().read_from_container(reader).ok()?;
+    let field = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)?;
+    match &field.value {
+        exif::Value::Ascii(values) => String::from_utf8(values.first()?.clone()).ok(),
+        _ => None,
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct IndexEntry {
+    size: u64,
+    modified: SystemTime,
+    // None if the probe failed
+    info: Option<MediaInfo>,
+    // Why the probe failed
+    #[serde(default)]
+    failure: Option<String>,
+    // Hashes used to skip duplicates, computed when first needed
+    #[serde(default)]
+    content_hash: Option<u64>,
+    #[serde(default)]
+    similarity_hash: Option<u64>,
+    // The sidecar is edited without touching the file, so it is checked on its own
+    #[serde(default)]
+    sidecar_modified: Option<SystemTime>,
+    // Whether the rating and tags were read, which is skipped unless needed
+    #[serde(default)]
+    has_metadata: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct IndexFile {
+    version: u32,
+    entries: HashMap<PathBuf, IndexEntry>,
+}
+
+struct Inner {
+    entries: HashMap<PathBuf, IndexEntry>,
+    unsaved: usize,
+    // Probes new and changed files
+    prober: Arc<dyn Prober>,
+    // Whether every probe reads the rating and tags, rather than only those of the files shown
+    reads_metadata: bool,
+    // Given by playlists, only kept while running
+    titles: HashMap<PathBuf, String>,
+    // Where the index is saved, only kept in memory if None
+    file_path: Option<PathBuf>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            unsaved: 0,
+            prober: Arc::new(Builtin::default()),
+            reads_metadata: false,
+            titles: HashMap::new(),
+            file_path: None,
+        }
+    }
+}
+
+/// Persistent cache of probe results, keyed by path, size and modification time. A default one
+/// is only kept in memory.
+///
+/// Cloning is cheap and every clone shares the same entries
+#[derive(Clone, Default)]
+pub struct MediaIndex {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MediaIndex {
+    // Probes between saves, or a tenth of the entries if more, so that saving a large index
+    // doesn't take longer than the probes in between
+    const SAVE_INTERVAL: usize = 500;
+    // Bumped whenever probing records more, so that every file is probed again
+    const VERSION: u32 = 3;
+
+    fn file_path() -> PathBuf {
+        let project_dirs = directories::ProjectDirs::from("", "", "abelscreensaver").unwrap();
+        project_dirs.cache_dir().join("index.json")
+    }
+
+    /// Loads the index from the cache dir, starting empty if it is missing or unreadable. Entries
+    /// of files that were deleted since are dropped in the background, so that the index doesn't
+    /// grow forever.
+    pub fn load() -> Self {
+        let index = Self::load_from(Self::file_path());
+        let index0 = index.clone();
+        std::thread::spawn(move || index0.prune());
+        index
+    }
+
+    fn load_from(file_path: PathBuf) -> Self {
+        let entries = std::fs::File::open(&file_path)
+            .ok()
+            .and_then(|file| {
+                serde_json::from_reader::<_, IndexFile>(std::io::BufReader::new(file)).ok()
+            })
+            .filter(|index_file| index_file.version == Self::VERSION)
+            .map(|index_file| index_file.entries)
+            .unwrap_or_default();
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                entries,
+                file_path: Some(file_path),
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Forgets the files that no longer exist, without holding the lock while checking
+    fn prune(&self) {
+        let paths: Vec<PathBuf> = self.inner.lock().unwrap().entries.keys().cloned().collect();
+        // Files on servers are only known once listed again, so they are kept
+        let gone: Vec<PathBuf> = paths
+            .into_iter()
+            .filter(|x| !catalog::is_catalog(x) && !location::exists(x))
+            .collect();
+        let mut inner = self.inner.lock().unwrap();
+        for path in gone {
+            inner.entries.remove(&path);
+        }
+    }
+
+    pub fn save(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(file_path) = inner.file_path.clone() else {
+            return;
+        };
+        // JSON keys are strings, so paths that aren't UTF-8 are probed again next time
+        let entries: HashMap<PathBuf, IndexEntry> = inner
+            .entries
+            .iter()
+            .filter(|(path, _)| path.to_str().is_some())
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect();
+        inner.unsaved = 0;
+        drop(inner);
+        // Serialized from a copy, so that probing goes on meanwhile
+        let serialized = serde_json::to_string(&IndexFile {
+            version: Self::VERSION,
+            entries,
+        });
+        let serialized = match serialized {
+            Ok(serialized) => serialized,
+            Err(err) => {
+                eprintln!("Failed to save the index: {err}");
+                return;
+            }
+        };
+        if let Some(Err(err)) = file_path.parent().map(std::fs::create_dir_all) {
+            eprintln!("Failed to save the index: {err}");
+            return;
+        }
+        // Write then rename so a crash mid-write can't corrupt the index
+        let tmp_path = file_path.with_extension("json.tmp");
+        if std::fs::write(&tmp_path, serialized).is_ok() {
+            std::fs::rename(tmp_path, file_path).ok();
+        }
+    }
+
+    /// Forget every entry, so that all files are probed again
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.unsaved = 0;
+        drop(inner);
+        self.save();
+    }
+
+    pub fn set_prober(&self, prober: Arc<dyn Prober>) {
+        self.inner.lock().unwrap().prober = prober;
+    }
+
+    /// Whether probing reads the rating and tags, which only their filters need before a file is
+    /// shown. Scanning for them reads much more of every file.
+    pub fn set_reads_metadata(&self, reads_metadata: bool) {
+        self.inner.lock().unwrap().reads_metadata = reads_metadata;
+    }
+
+    /// Returns the media info of a playable file, probing it only if it is new or has changed
+    pub fn probe(&self, path: &Path) -> Option<MediaInfo> {
+        let reads_metadata = self.inner.lock().unwrap().reads_metadata;
+        self.probe_as(path, reads_metadata)
+    }
+
+    /// Like [`Self::probe`], always with the rating and tags, as shown with the file
+    pub fn probe_shown(&self, path: &Path) -> Option<MediaInfo> {
+        self.probe_as(path, true)
+    }
+
+    fn probe_as(&self, path: &Path, reads_metadata: bool) -> Option<MediaInfo> {
+        let (size, modified) = location::stamp(path).ok()?;
+        let sidecar_modified = reads_metadata
+            .then(|| xmp::sidecar_modified(path))
+            .flatten();
+        if let Some(entry) = self.inner.lock().unwrap().entries.get(path) {
+            let is_fresh = entry.size == size
+                && entry.modified == modified
+                && (!reads_metadata
+                    || entry.has_metadata && entry.sidecar_modified == sidecar_modified);
+            if is_fresh {
+                return entry.info.clone();
+            }
+        }
+        let prober = self.inner.lock().unwrap().prober.clone();
+        let result = match archive::split(path) {
+            Some((archive, member)) => {
+                probe_member(prober.as_ref(), path, archive, member, reads_metadata)
+            }
+            None => probe_file(prober.as_ref(), path, size, reads_metadata),
+        };
+        if let Err(err) = &result {
+            eprintln!("Failed to probe {}: {err}", path.display());
+        }
+        let (info, failure) = match result {
+            Ok(info) => (Some(info), None),
+            Err(err) => (None, Some(err)),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.insert(
+            path.to_path_buf(),
+            IndexEntry {
+                size,
+                modified,
+                info: info.clone(),
+                failure,
+                content_hash: None,
+                similarity_hash: None,
+                sidecar_modified,
+                has_metadata: reads_metadata,
+            },
+        );
+        inner.unsaved += 1;
+        let needs_save = inner.unsaved >= Self::SAVE_INTERVAL.max(inner.entries.len() / 10);
+        drop(inner);
+        if needs_save {
+            self.save();
+        }
+        info
+    }
+
+    /// The media info from the last probe of the file, without checking whether it has changed
+    pub fn cached(&self, path: &Path) -> Option<MediaInfo> {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.get(path)?.info.clone()
+    }
+
+    /// Why probing the file failed, if it did
+    pub fn failure(&self, path: &Path) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.get(path)?.failure.clone()
+    }
+
+    pub fn set_title(&self, path: &Path, title: String) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.titles.insert(path.to_path_buf(), title);
+    }
+
+    /// The title a playlist gave the file, if any
+    pub fn title(&self, path: &Path) -> Option<String> {
+        self.inner.lock().unwrap().titles.get(path).cloned()
+    }
+
+    /// FNV-1a hash of the contents of a playable file
+    pub fn content_hash(&self, path: &Path) -> Option<u64> {
+        self.cached_hash(path, |entry| &mut entry.content_hash, hash_content)
+    }
+
+    /// Difference hash of a downscaled playable image, which barely changes when it is
+    /// re-encoded, resized or has its metadata edited
+    pub fn similarity_hash(&self, path: &Path) -> Option<u64> {
+        self.cached_hash(path, |entry| &mut entry.similarity_hash, hash_similarity)
+    }
+
+    fn cached_hash(
+        &self,
+        path: &Path,
+        field: fn(&mut IndexEntry) -> &mut Option<u64>,
+        compute: fn(&Path) -> Option<u64>,
+    ) -> Option<u64> {
+        // Makes sure the entry is current
+        self.probe(path)?;
+        let cached = self
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut(path)
+            .and_then(|x| *field(x));
+        if cached.is_some() {
+            return cached;
+        }
+        let hash = compute(&location::local_path(path).ok()?)?;
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.entries.get_mut(path) {
+            *field(entry) = Some(hash);
+            inner.unsaved += 1;
+        }
+        Some(hash)
+    }
+}
+
+/// Probes the copy of the file on disk, which is downloaded first for images on servers
+fn probe_file(
+    prober: &dyn Prober,
+    path: &Path,
+    size: u64,
+    reads_metadata: bool,
+) -> Result<MediaInfo, String> {
+    let local_path = location::local_path(path).map_err(|err| format!("failed to fetch: {err}"))?;
+    let mut info = prober.probe(&local_path)?;
+    let needs_date = info.creation_time.is_none();
+    if needs_date || reads_metadata {
+        // Opened once for both
+        if let Ok(file) = std::fs::File::open(&local_path) {
+            let mut reader = BufReader::new(file);
+            if needs_date {
+                info.creation_time = exif_date_time(&mut reader);
+            }
+            if reads_metadata && reader.rewind().is_ok() {
+                let metadata = xmp::read(path, &mut reader);
+                info.rating = metadata.rating;
+                info.tags = metadata.tags;
+            }
+        }
+    }
+    info.size = Some(size);
+    Ok(info)
+}
+
+/// Images in archives are probed from their start, where their size and metadata are, so that
+/// only the members that are played get extracted. Other members are extracted, as probing them
+/// takes a file.
+fn probe_member(
+    prober: &dyn Prober,
+    path: &Path,
+    archive: &Path,
+    member: &Path,
+    reads_metadata: bool,
+) -> Result<MediaInfo, String> {
+    let (head, len) = archive::head(archive, member, xmp::MAX_SCAN_BYTES)
+        .map_err(|err| format!("failed to read it from the archive: {err}"))?;
+    let is_image = mime_guess::from_path(path)
+        .first()
+        .is_some_and(|x| x.type_() == mime_guess::mime::IMAGE);
+    let mut info = match is_image.then(|| probe::probe_image_head(&head)).flatten() {
+        Some(info) => info,
+        None => {
+            let local_path =
+                location::local_path(path).map_err(|err| format!("failed to extract: {err}"))?;
+            prober.probe(&local_path)?
+        }
+    };
+    if info.creation_time.is_none() {
+        info.creation_time = exif_date_time(&mut Cursor::new(&head));
+    }
+    if reads_metadata {
+        let metadata = xmp::read_head(path, &head);
+        info.rating = metadata.rating;
+        info.tags = metadata.tags;
+    }
+    info.size = Some(len);
+    Ok(info)
+}
+
+fn hash_content(path: &Path) -> Option<u64> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut buf = vec![0; 1 << 16];
+    let mut hasher = location::Fnv1a::default();
+    loop {
+        let n = file.read(&mut buf).ok()?;
+        if n == 0 {
+            return Some(hasher.finish());
+        }
+        hasher.write(&buf[..n]);
+    }
+}
+
+/// Decodes the image to a 9x8 grayscale thumbnail, and sets a bit for every pixel that is darker
+/// than its right neighbour
+fn hash_similarity(path: &Path) -> Option<u64> {
+    let is_image = mime_guess::from_path(path)
+        .first()
+        .is_some_and(|x| x.type_() == mime_guess::mime::IMAGE);
+    if !is_image {
+        return None;
+    }
+    let image = image::ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .decode()
+        .ok()?;
+    let thumbnail = image.thumbnail_exact(9, 8).into_luma8();
+    let mut hash = 0;
+    for row in thumbnail.as_raw().chunks(9) {
+        for pair in row.windows(2) {
+            hash = (hash << 1) | (pair[0] < pair[1]) as u64;
+        }
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::Playable;
+    use crate::test_dir::TestDir;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn saves_names_that_need_escaping() {
+        let dir = TestDir::new();
+        let escaped = [
+            dir.file("it's a \"test\".jpg", b"1"),
+            dir.file("caf\u{e9} [2]/\u{1f600} *.png", b"12"),
+            dir.file("new\nline\ttab.jpg", b"123"),
+            dir.file("back\\slash {}.gif", b"1234"),
+        ];
+        let not_utf8 = dir.file(OsStr::from_bytes(b"latin1 caf\xe9.jpg"), b"12345");
+        let index = MediaIndex::load_from(dir.path().join("cache/index.json"));
+        index.set_prober(Arc::new(Playable));
+        for path in escaped.iter().chain([&not_utf8]) {
+            assert!(index.probe(path).is_some());
+        }
+        index.save();
+
+        let loaded = MediaIndex::load_from(dir.path().join("cache/index.json"));
+        let inner = loaded.inner.lock().unwrap();
+        for (path, size) in escaped.iter().zip(1..) {
+            let entry = &inner.entries[path];
+            assert_eq!(entry.size, size);
+            assert_eq!(entry.info.as_ref().unwrap().format, "test");
+        }
+        // Left out, to be probed again
+        assert!(!inner.entries.contains_key(&not_utf8));
+    }
+
+    #[test]
+    fn prunes_files_that_are_gone() {
+        let dir = TestDir::new();
+        let kept = dir.file("kept.jpg", b"");
+        let gone = dir.file("gone.jpg", b"");
+        let index = MediaIndex::default();
+        index.set_prober(Arc::new(Playable));
+        index.probe(&kept);
+        index.probe(&gone);
+        std::fs::remove_file(&gone).unwrap();
+        index.prune();
+        let inner = index.inner.lock().unwrap();
+        assert!(inner.entries.contains_key(&kept));
+        assert!(!inner.entries.contains_key(&gone));
+    }
+
+    /// Waves that are still recognizable once downscaled, mirrored if asked to
+    fn waves(width: u32, height: u32, mirrored: bool) -> image::GrayImage {
+        image::GrayImage::from_fn(width, height, |x, y| {
+            let x = if mirrored { width - 1 - x } else { x };
+            let (x, y) = (x as f64 / width as f64, y as f64 / height as f64);
+            let value = 128.0 + 120.0 * (x * 17.0).sin() * (y * 5.0 + x * 3.0).cos();
+            image::Luma([value as u8])
+        })
+    }
+
+    #[test]
+    fn hashes_similarity_without_ffmpeg() {
+        let dir = TestDir::new();
+        let original = dir.path().join("original.png");
+        let smaller = dir.path().join("smaller.jpg");
+        let mirrored = dir.path().join("mirrored.png");
+        waves(640, 480, false).save(&original).unwrap();
+        waves(320, 240, false).save(&smaller).unwrap();
+        waves(640, 480, true).save(&mirrored).unwrap();
+        // Decoded in-process, so no other program is run
+        let [original, smaller, mirrored] =
+            [&original, &smaller, &mirrored].map(|x| hash_similarity(x).unwrap());
+        assert!((original ^ smaller).count_ones() <= 4);
+        assert!((original ^ mirrored).count_ones() > 16);
+    }
+
+    /// Fails every probe, for files that should be probed without it
+    struct Unprobeable;
+
+    impl Prober for Unprobeable {
+        fn probe(&self, _path: &Path) -> Result<MediaInfo, String> {
+            Err("not probed in place".to_string())
+        }
+    }
+
+    #[test]
+    fn probes_images_in_archives_in_place() {
+        let dir = TestDir::new();
+        let mut png = Cursor::new(vec![]);
+        waves(64, 48, false)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        let options = zip::write::SimpleFileOptions::default();
+        // Stored with a `./`, but listed and looked up without it
+        for name in ["pages/01.png", "./pages/02.png"] {
+            zip.start_file(name, options).unwrap();
+            std::io::Write::write_all(&mut zip, png.get_ref()).unwrap();
+        }
+        zip.start_file("notes.mp4", options).unwrap();
+        let archive = dir.file("comic.cbz", zip.finish().unwrap().get_ref());
+        let index = MediaIndex::default();
+        index.set_prober(Arc::new(Unprobeable));
+        for name in ["pages/01.png", "pages/02.png"] {
+            let info = index.probe(&archive.join(name)).unwrap();
+            assert_eq!((info.width, info.height), (Some(64), Some(48)));
+            assert_eq!(info.format, "png");
+            assert_eq!(info.size, Some(png.get_ref().len() as u64));
+        }
+        assert!(index.probe(&archive.join("pages/03.png")).is_none());
+        assert!(index.probe(&archive.join("notes.mp4")).is_none());
+        assert_eq!(
+            index.failure(&archive.join("notes.mp4")).unwrap(),
+            "not probed in place"
+        );
+    }
+}
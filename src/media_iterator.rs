@@ -1,97 +1,836 @@
+use crate::archive;
+use crate::capture_date::CaptureDate;
+use crate::catalog;
+use crate::dedupe::Deduper;
+use crate::diagnostics::{Diagnostics, Reason};
+use crate::history::History;
+use crate::library;
+use crate::location;
+use crate::media_filter::MediaFilter;
+use crate::media_index::{MediaIndex, MediaInfo};
+use crate::photo_server;
+use crate::playlist;
+use crate::probe;
+use crate::probe_pool::{ProbePool, Probing};
+use crate::settings::{Order, SortBy, Source};
+use crate::sort_key::{natural_cmp, natural_path_cmp, SortKey};
+use crate::walk_filter::WalkFilter;
+use crate::watcher::{self, WatchEvent};
 use crate::Options;
 use auto_enums::auto_enum;
 use mime_guess::mime;
-use rand::{thread_rng, Rng};
-use std::ffi::OsStr;
+use notify::RecommendedWatcher;
+use rand::seq::index;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{
+    sync_channel, Receiver, RecvTimeoutError, SendError, SyncSender, TrySendError,
+};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use std::{fs, thread};
 use walkdir::WalkDir;
 
 pub struct RandomMediaIterator {
     rx: Receiver<PathBuf>,
+    // Set once every file found by the initial scan has been sent
+    exhausted: Arc<AtomicBool>,
+    _watcher: Option<RecommendedWatcher>,
 }
 
 impl RandomMediaIterator {
-    pub fn new(opts: Options) -> Self {
+    pub fn new(
+        opts: Options,
+        index: MediaIndex,
+        history: History,
+        diagnostics: Diagnostics,
+    ) -> Self {
         let (tx, rx) = sync_channel(3);
+        let exhausted = Arc::new(AtomicBool::new(false));
+        let (watcher, events) = opts
+            .watch
+            .then(|| watcher::watch(&roots(&opts)))
+            .flatten()
+            .unzip();
 
-        thread::spawn(move || populate(opts, tx));
+        let exhausted0 = exhausted.clone();
+        thread::spawn(move || {
+            populate(opts, &index, &history, diagnostics, events, &exhausted0, tx);
+            index.save();
+        });
 
-        Self { rx }
+        Self {
+            rx,
+            exhausted,
+            _watcher: watcher,
+        }
     }
 }
 
 impl std::iter::Iterator for RandomMediaIterator {
     type Item = PathBuf;
 
+    /// Blocks while scanning, afterwards only returns files that were added in the meantime
     fn next(&mut self) -> Option<Self::Item> {
-        self.rx.recv().ok()
+        loop {
+            let path = if self.exhausted.load(Ordering::Relaxed) {
+                self.rx.try_recv().ok()?
+            } else {
+                match self.rx.recv_timeout(Duration::from_millis(100)) {
+                    Ok(path) => path,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return None,
+                }
+            };
+            // It may have been removed while queued
+            if location::exists(&path) {
+                return Some(path);
+            }
+        }
     }
 }
 
-fn populate(opts: Options, tx: SyncSender<PathBuf>) {
-    let mut dirs = opts.paths;
-    let mut paths = vec![];
-    let mut next = None;
-    let mut rng = thread_rng();
+/// A random sample of the files of one source, drawn without replacement. Sources with more
+/// files than fit in the sample are walked again once it is used up.
+#[derive(Default)]
+struct Pool {
+    // Files that have not been drawn yet
+    files: Vec<PathBuf>,
+    // Files the scorer prefers, which are drawn before the rest and kept outside the sample
+    preferred: Vec<PathBuf>,
+    // Files drawn since the last refill, skipped when walking again
+    drawn: HashSet<PathBuf>,
+    // Files offered by the current walk, for reservoir sampling
+    seen: usize,
+    // Whether the current walk found more files than fit in the sample
+    overflowed: bool,
+    // Whether the last walk fit in the sample, so that walking again finds nothing new
+    complete: bool,
+    // Files held back by the last walk because they were shown in the current cycle
+    deferred: usize,
+    // Repeated while larger sources finish, including files shown in the current cycle
+    refilled: bool,
+}
+
+impl Pool {
+    // Bounds the memory used per source, however large the library
+    const SAMPLE_SIZE: usize = 100_000;
 
-    while !dirs.is_empty() {
-        let i = rng.gen_range(0..dirs.len());
-        let dir = dirs.swap_remove(i);
-        let Ok(entries) = fs::read_dir(&dir) else {
-            continue;
+    fn start_walk(&mut self) {
+        self.seen = 0;
+        self.overflowed = false;
+        self.deferred = 0;
+    }
+
+    fn finish_walk(&mut self) {
+        self.complete = !self.overflowed;
+    }
+
+    /// Reservoir sampling, so that every file found by the walk is equally likely to be kept.
+    /// Preferred files are all kept.
+    fn offer(&mut self, path: PathBuf, rng: &mut impl Rng, is_preferred: bool) {
+        if is_preferred {
+            self.preferred.push(path);
+            return;
+        }
+        self.seen += 1;
+        if self.files.len() < Self::SAMPLE_SIZE {
+            self.files.push(path);
+            return;
+        }
+        self.overflowed = true;
+        let i = rng.gen_range(0..self.seen);
+        if i < Self::SAMPLE_SIZE {
+            self.files[i] = path;
+        }
+    }
+
+    fn push(&mut self, path: PathBuf) {
+        self.files.push(path);
+    }
+
+    fn draw(&mut self, rng: &mut impl Rng, scorer: Option<&mut Scorer>) -> Option<PathBuf> {
+        let path = if !self.preferred.is_empty() {
+            let i = rng.gen_range(0..self.preferred.len());
+            self.preferred.swap_remove(i)
+        } else if !self.files.is_empty() {
+            let i = match scorer {
+                Some(scorer) => scorer.best(&self.files, rng),
+                None => rng.gen_range(0..self.files.len()),
+            };
+            self.files.swap_remove(i)
+        } else {
+            return None;
         };
-        for entry in entries.filter_map(|x| x.ok()) {
-            let file_name = entry.file_name();
-            if !opts.hidden && is_hidden(file_name.as_os_str()) {
+        self.drawn.insert(path.clone());
+        Some(path)
+    }
+
+    /// Whether there are sampled files left to draw
+    fn has_files(&self) -> bool {
+        !self.files.is_empty() || !self.preferred.is_empty()
+    }
+
+    /// Whether there are files left to draw, possibly after walking again
+    fn has_more(&self) -> bool {
+        self.has_files() || !self.complete
+    }
+
+    fn refill(&mut self) {
+        self.refilled = true;
+        if self.complete && self.deferred == 0 {
+            // Sorted, as the order of a set differs between runs
+            let mut drawn: Vec<_> = self.drawn.drain().collect();
+            drawn.sort();
+            self.files.extend(drawn);
+        } else {
+            self.drawn.clear();
+            self.complete = false;
+        }
+    }
+
+    /// Stop walking, only files added later are drawn from now on
+    fn finish(&mut self) {
+        self.files.clear();
+        self.preferred.clear();
+        self.drawn.clear();
+        self.complete = true;
+    }
+
+    fn remove(&mut self, path: &Path) {
+        self.files.retain(|x| !x.starts_with(path));
+        self.preferred.retain(|x| !x.starts_with(path));
+        self.drawn.retain(|x| !x.starts_with(path));
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        self.drawn.contains(path) || self.files.iter().chain(&self.preferred).any(|x| x == path)
+    }
+}
+
+/// Scores files for the orders that prefer some files over others, the highest of a sample is
+/// drawn first
+enum Scorer {
+    // By the days since a file was last shown, or since it was added if never shown, plus a
+    // boost for new files that fades out over as many days
+    LeastRecentlyShown {
+        history: History,
+        boost_days: f64,
+        // Modification times, looked up once per file
+        added: HashMap<PathBuf, SystemTime>,
+    },
+    // Files taken on this day in earlier years first, in random order like the rest. Only the
+    // capture dates of files probed before are known, so files are drawn in random order until
+    // enough of them were probed
+    OnThisDay {
+        index: MediaIndex,
+        today: CaptureDate,
+    },
+}
+
+impl Scorer {
+    const SECS_PER_DAY: f64 = 86400.0;
+    // Files scored per draw, so that a draw takes as long however many files are pooled
+    const SAMPLE_SIZE: usize = 64;
+
+    fn new(opts: &Options, index: &MediaIndex, history: &History) -> Option<Self> {
+        match opts.order {
+            Order::LeastRecentlyShown => Some(Self::LeastRecentlyShown {
+                history: history.clone(),
+                boost_days: opts.new_file_boost_days,
+                added: HashMap::new(),
+            }),
+            Order::OnThisDay => Some(Self::OnThisDay {
+                index: index.clone(),
+                today: CaptureDate::today(),
+            }),
+            Order::Sequential | Order::Shuffle => None,
+        }
+    }
+
+    fn days_since(now: SystemTime, time: SystemTime) -> f64 {
+        now.duration_since(time).unwrap_or_default().as_secs_f64() / Self::SECS_PER_DAY
+    }
+
+    /// The index of the highest scoring file of a random sample, with some jitter so that ties
+    /// are broken randomly
+    fn best(&mut self, files: &[PathBuf], rng: &mut impl Rng) -> usize {
+        let now = SystemTime::now();
+        let sample = index::sample(rng, files.len(), Self::SAMPLE_SIZE.min(files.len()));
+        let mut best = (0, f64::MIN);
+        for i in sample {
+            let path = &files[i];
+            let score = match self {
+                Self::LeastRecentlyShown {
+                    history,
+                    boost_days,
+                    added,
+                } => {
+                    let added = *added.entry(path.clone()).or_insert_with(|| {
+                        location::modified(path).unwrap_or(SystemTime::UNIX_EPOCH)
+                    });
+                    let added_days = Self::days_since(now, added);
+                    let last_shown = history.last_shown(path).unwrap_or(added);
+                    let score =
+                        Self::days_since(now, last_shown) + (*boost_days - added_days).max(0.0);
+                    score * rng.gen_range(0.9..=1.0)
+                }
+                // Preferred files are drawn before scoring
+                Self::OnThisDay { .. } => rng.gen::<f64>(),
+            };
+            if score > best.1 {
+                best = (i, score);
+            }
+        }
+        best.0
+    }
+
+    /// Whether the file is drawn before the others, as far as is known without probing it
+    fn is_preferred(&self, path: &Path) -> bool {
+        match self {
+            Self::LeastRecentlyShown { .. } => false,
+            Self::OnThisDay { index, today } => index
+                .cached(path)
+                .and_then(|x| x.capture_date())
+                .is_some_and(|x| x.years_ago(today).is_some()),
+        }
+    }
+}
+
+/// Picks a source that passes the check, proportionally to the source weights
+fn pick_pool(
+    pools: &[Pool],
+    weights: &[f64],
+    rng: &mut impl Rng,
+    is_candidate: impl Fn(&Pool) -> bool,
+) -> Option<usize> {
+    let candidates = || (0..pools.len()).filter(|&i| is_candidate(&pools[i]) && weights[i] > 0.0);
+    let total: f64 = candidates().map(|i| weights[i]).sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut target = rng.gen_range(0.0..total);
+    let mut last = None;
+    for i in candidates() {
+        if target < weights[i] {
+            return Some(i);
+        }
+        target -= weights[i];
+        last = Some(i);
+    }
+    last
+}
+
+/// Walks the sources in random order, sampling their files into the pools, and sends the files
+/// drawn from them
+struct Scanner<'a> {
+    opts: Options,
+    history: &'a History,
+    events: Option<Receiver<WatchEvent>>,
+    tx: SyncSender<PathBuf>,
+    filter: WalkFilter,
+    media_filter: Option<MediaFilter>,
+    deduper: Deduper,
+    index: &'a MediaIndex,
+    diagnostics: Diagnostics,
+    // Only the first walk is recorded, later ones pass over the same files
+    is_diagnosed: bool,
+    weights: Vec<f64>,
+    pools: Vec<Pool>,
+    rng: ChaCha8Rng,
+    scorer: Option<Scorer>,
+    probe_pool: ProbePool,
+    // Drawn files being probed, oldest first
+    probing: VecDeque<Probing>,
+    // Drawn while walking, waiting for room in the channel
+    next: Option<PathBuf>,
+    // Drawing while walking depends on how fast files are consumed, so a seeded order waits for
+    // the walk to finish
+    draw_early: bool,
+    // Files shown in the current cycle are held back, unless files are drawn by score instead or
+    // the order is seeded, which would then depend on what was shown before
+    deck: bool,
+}
+
+impl Scanner<'_> {
+    fn sources(&self) -> Vec<(usize, PathBuf)> {
+        roots(&self.opts).into_iter().enumerate().collect()
+    }
+
+    fn apply_events(&mut self) {
+        if let Some(events) = &self.events {
+            apply_events(
+                &self.opts,
+                &mut self.filter,
+                &self.deduper,
+                events.try_iter(),
+                &mut self.pools,
+            );
+        }
+    }
+
+    /// Walks from the directories in random order. Unless seeded, a file is drawn from what was
+    /// sampled so far after each directory, so that the first one is sent right away.
+    fn walk(&mut self, mut dirs: Vec<(usize, PathBuf)>) -> Result<(), SendError<PathBuf>> {
+        let sources: Vec<usize> = dirs.iter().map(|&(source, _)| source).collect();
+        for &source in &sources {
+            self.pools[source].start_walk();
+        }
+        self.filter.restart();
+        while !dirs.is_empty() {
+            self.apply_events();
+            let i = self.rng.gen_range(0..dirs.len());
+            let (source, dir) = dirs.swap_remove(i);
+            if is_playlist_source(&dir) {
+                self.expand(source, &dir, &mut dirs);
                 continue;
             }
-            let Ok(ft) = entry.file_type() else {
+            if is_archive_file(&dir) {
+                self.expand_archive(source, &dir);
                 continue;
+            }
+            if catalog::is_catalog(&dir) {
+                self.expand_catalog(source, &dir);
+                continue;
+            }
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    let reason = Reason::of_io_error(&err, Reason::UnreadableDir);
+                    self.reject(&dir, reason, Some(err.to_string()));
+                    continue;
+                }
             };
-            if ft.is_dir() {
-                dirs.push(entry.path());
-            } else if ft.is_file() && is_valid_media(file_name, opts.video) {
-                paths.push(entry.path());
-                if let Some(path) = next.take() {
-                    match tx.try_send(path) {
-                        Ok(()) => {}
-                        Err(TrySendError::Full(x)) => next = Some(x),
-                        Err(TrySendError::Disconnected(_)) => return,
+            // The order of read_dir is up to the filesystem
+            let mut entries: Vec<_> = entries.filter_map(|x| x.ok()).collect();
+            entries.sort_by_key(|x| x.file_name());
+            for entry in entries {
+                let file_name = entry.file_name();
+                let Ok(mut ft) = entry.file_type() else {
+                    continue;
+                };
+                if ft.is_symlink() && self.opts.follow_symlinks {
+                    // Skips dangling links
+                    let Ok(metadata) = fs::metadata(entry.path()) else {
+                        continue;
+                    };
+                    ft = metadata.file_type();
+                }
+                // Archives are walked like directories
+                let is_dir = ft.is_dir() || (ft.is_file() && archive::is_archive(&entry.path()));
+                if !self.filter.allows(&entry.path(), is_dir) {
+                    if ft.is_file() {
+                        self.reject(&entry.path(), Reason::Excluded, None);
                     }
-                } else {
-                    next = loop {
-                        if paths.len() > 99 {
-                            let i = rng.gen_range(0..paths.len());
-                            let target = paths.swap_remove(i);
-                            if ffprobe::ffprobe(&target).is_ok() {
-                                break Some(target);
-                            }
-                        } else {
-                            break None;
-                        }
+                    continue;
+                }
+                if is_dir {
+                    dirs.push((source, entry.path()));
+                } else if ft.is_file() {
+                    if is_valid_media(file_name, includes_video(&self.opts, source)) {
+                        self.offer(source, entry.path());
+                    } else {
+                        self.reject(&entry.path(), Reason::NotMedia, None);
                     }
                 }
             }
+            if self.draw_early {
+                self.send_early()?;
+            }
+        }
+        for source in sources {
+            self.pools[source].finish_walk();
         }
+        self.is_diagnosed = true;
+        Ok(())
     }
-    if let Some(target) = next {
-        if ffprobe::ffprobe(&target).is_ok() {
-            if tx.send(target).is_err() {
+
+    /// Offers the files listed in the playlist or library and queues its directories to be walked
+    fn expand(&mut self, source: usize, playlist: &Path, dirs: &mut Vec<(usize, PathBuf)>) {
+        let entries = match read_list(&self.opts.paths[source]) {
+            Ok(entries) => entries,
+            Err((reason, err)) => {
+                self.reject(playlist, reason, Some(err));
                 return;
             }
+        };
+        for entry in entries {
+            let Some((path, is_dir)) = listed_path(&entry.path) else {
+                self.reject(&entry.path, Reason::Missing, None);
+                continue;
+            };
+            if !self.filter.allows_listed(&path, is_dir) {
+                if !is_dir {
+                    self.reject(&path, Reason::Excluded, None);
+                }
+                continue;
+            }
+            if is_dir {
+                dirs.push((source, path));
+            } else if is_valid_media(&path, includes_video(&self.opts, source)) {
+                if let Some(title) = entry.title {
+                    self.index.set_title(&path, title);
+                }
+                self.offer(source, path);
+            } else {
+                self.reject(&path, Reason::NotMedia, None);
+            }
         }
     }
-    while !paths.is_empty() {
-        let i = rng.gen_range(0..paths.len());
-        let target = paths.swap_remove(i);
-        if ffprobe::ffprobe(&target).is_ok() {
-            if tx.send(target).is_err() {
+
+    /// Offers the media files in the archive
+    fn expand_archive(&mut self, source: usize, path: &Path) {
+        let members = match archive::members(path) {
+            Ok(members) => members,
+            Err(err) => {
+                self.reject(path, Reason::UnreadableArchive, Some(err.to_string()));
                 return;
             }
+        };
+        for member in members {
+            if !self.filter.allows(&member, false) {
+                self.reject(&member, Reason::Excluded, None);
+            } else if is_valid_media(&member, includes_video(&self.opts, source)) {
+                self.offer(source, member);
+            } else {
+                self.reject(&member, Reason::NotMedia, None);
+            }
+        }
+    }
+
+    /// Offers the media files listed by the server
+    fn expand_catalog(&mut self, source: usize, root: &Path) {
+        let files = match catalog::list(&self.opts.paths[source], &self.opts) {
+            Ok(files) => files,
+            Err(err) => {
+                self.reject(root, Reason::UnreadableDir, Some(err));
+                return;
+            }
+        };
+        for file in files {
+            if !self.filter.allows(&file, false) {
+                self.reject(&file, Reason::Excluded, None);
+            } else if is_valid_media(&file, includes_video(&self.opts, source)) {
+                self.offer(source, file);
+            } else {
+                self.reject(&file, Reason::NotMedia, None);
+            }
         }
     }
+
+    fn reject(&self, path: &Path, reason: Reason, detail: Option<String>) {
+        if !self.is_diagnosed {
+            self.diagnostics.reject(path, reason, detail);
+        }
+    }
+
+    fn offer(&mut self, source: usize, path: PathBuf) {
+        let pool = &mut self.pools[source];
+        if pool.drawn.contains(&path) || self.deduper.is_duplicate(&path) {
+            return;
+        }
+        if self.deck && !pool.refilled && self.history.is_shown_in_cycle(&path) {
+            pool.deferred += 1;
+            return;
+        }
+        let is_preferred = self.scorer.as_ref().is_some_and(|x| x.is_preferred(&path));
+        pool.offer(path, &mut self.rng, is_preferred);
+    }
+
+    fn send_early(&mut self) -> Result<(), SendError<PathBuf>> {
+        if self.next.is_none() {
+            self.next = self.draw();
+        }
+        if let Some(path) = self.next.take() {
+            match self.tx.try_send(path) {
+                Ok(()) => {}
+                Err(TrySendError::Full(x)) => self.next = Some(x),
+                Err(TrySendError::Disconnected(x)) => return Err(SendError(x)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws sampled files ahead so that they are probed in parallel, and returns the oldest one
+    /// that probes successfully and passes the filter
+    fn draw(&mut self) -> Option<PathBuf> {
+        loop {
+            while self.probing.len() < self.probe_pool.threads() {
+                let Some(i) = pick_pool(&self.pools, &self.weights, &mut self.rng, Pool::has_files)
+                else {
+                    break;
+                };
+                let target = self.pools[i]
+                    .draw(&mut self.rng, self.scorer.as_mut())
+                    .unwrap();
+                self.probing.push_back(self.probe_pool.start(target));
+            }
+            let (target, info) = self.probing.pop_front()?.wait();
+            let media_filter = self.media_filter.as_ref();
+            if is_wanted(&self.diagnostics, self.index, media_filter, &target, info) {
+                return Some(target);
+            }
+        }
+    }
+
+    fn run(&mut self, exhausted: &AtomicBool) -> Result<(), SendError<PathBuf>> {
+        self.walk(self.sources())?;
+        report_duplicates(&self.deduper);
+        let is_shown = self.pools.iter().all(|x| !x.has_files() && x.complete);
+        if self.deck && is_shown && self.pools.iter().any(|x| x.deferred > 0) {
+            // Everything was shown in this cycle, so start the next one
+            self.history.next_cycle();
+            self.walk(self.sources())?;
+        }
+        // Sources are drawn by weight, so smaller ones are refilled until every source was shown
+        let cycled: Vec<usize> = (0..self.pools.len())
+            .filter(|&i| {
+                let pool = &self.pools[i];
+                (pool.has_more() || !pool.drawn.is_empty()) && self.weights[i] > 0.0
+            })
+            .collect();
+        let mut unfinished: Vec<bool> =
+            (0..self.pools.len()).map(|i| cycled.contains(&i)).collect();
+        loop {
+            if let Some(path) = self.next.take() {
+                self.tx.send(path)?;
+            }
+            self.apply_events();
+            for &i in &cycled {
+                if !self.pools[i].has_more() {
+                    unfinished[i] = false;
+                    if unfinished.contains(&true) {
+                        self.pools[i].refill();
+                    }
+                }
+            }
+            if !unfinished.contains(&true) {
+                break;
+            }
+            let Some(i) = pick_pool(&self.pools, &self.weights, &mut self.rng, Pool::has_more)
+            else {
+                break;
+            };
+            if !self.pools[i].has_files() {
+                // The sample is used up, so take the next one
+                self.walk(vec![(i, self.opts.paths[i].path.clone())])?;
+                continue;
+            }
+            self.next = self.draw();
+        }
+        for pool in &mut self.pools {
+            pool.finish();
+        }
+        loop {
+            while let Some(target) = self.draw() {
+                self.tx.send(target)?;
+                self.apply_events();
+            }
+            exhausted.store(true, Ordering::Relaxed);
+            // Keep running for as long as there are paths to watch
+            let Some(events) = &self.events else {
+                return Ok(());
+            };
+            let Ok(event) = events.recv() else {
+                return Ok(());
+            };
+            apply_events(
+                &self.opts,
+                &mut self.filter,
+                &self.deduper,
+                std::iter::once(event).chain(events.try_iter()),
+                &mut self.pools,
+            );
+        }
+    }
+}
+
+fn populate(
+    opts: Options,
+    index: &MediaIndex,
+    history: &History,
+    diagnostics: Diagnostics,
+    events: Option<Receiver<WatchEvent>>,
+    exhausted: &AtomicBool,
+    tx: SyncSender<PathBuf>,
+) {
+    // ChaCha gives the same sequence on every platform and version, unlike StdRng
+    let rng = match opts.seed {
+        Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+        None => ChaCha8Rng::from_entropy(),
+    };
+    let scorer = Scorer::new(&opts, index, history);
+    let mut scanner = Scanner {
+        filter: WalkFilter::new(&opts),
+        media_filter: MediaFilter::from_options(&opts),
+        deduper: Deduper::new(&opts, index.clone(), diagnostics.clone()),
+        index,
+        diagnostics,
+        is_diagnosed: false,
+        weights: opts.paths.iter().map(|x| x.weight).collect(),
+        pools: opts.paths.iter().map(|_| Pool::default()).collect(),
+        rng,
+        draw_early: opts.seed.is_none(),
+        deck: scorer.is_none() && opts.seed.is_none(),
+        scorer,
+        probe_pool: ProbePool::new(index),
+        probing: VecDeque::new(),
+        next: None,
+        opts,
+        history,
+        events,
+        tx,
+    };
+    // Stops once the iterator is dropped
+    scanner.run(exhausted).ok();
+}
+
+fn apply_events(
+    opts: &Options,
+    filter: &mut WalkFilter,
+    deduper: &Deduper,
+    events: impl Iterator<Item = WatchEvent>,
+    pools: &mut [Pool],
+) {
+    for event in events {
+        match event {
+            WatchEvent::Added(path) => {
+                let Some((source, files)) = added_files(opts, filter, &path) else {
+                    continue;
+                };
+                for file in files {
+                    if !pools[source].contains(&file) && !deduper.is_duplicate(&file) {
+                        pools[source].push(file);
+                    }
+                }
+            }
+            WatchEvent::Removed(path) => {
+                deduper.forget(&path);
+                for pool in pools.iter_mut() {
+                    pool.remove(&path);
+                }
+            }
+        }
+    }
+}
+
+/// The source of a file reported by the watcher and the files it adds, which pass the same checks
+/// as the walkers. An archive adds its members, and a playlist or library that is a source adds the
+/// files it lists, while directories it lists are only walked when it is first read.
+fn added_files(
+    opts: &Options,
+    filter: &mut WalkFilter,
+    path: &Path,
+) -> Option<(usize, Vec<PathBuf>)> {
+    let source = watcher::root_index(&roots(opts), path)?;
+    let video = includes_video(opts, source);
+    let files = if path == opts.paths[source].path && is_playlist_source(path) {
+        let entries = read_list(&opts.paths[source])
+            .map_err(|(_, err)| eprintln!("Failed to read {}: {err}", path.display()))
+            .ok()?;
+        entries
+            .into_iter()
+            .filter_map(|entry| listed_path(&entry.path))
+            .filter(|(path, is_dir)| {
+                !is_dir && filter.allows_listed(path, false) && is_valid_media(path, video)
+            })
+            .map(|(path, _)| path)
+            .collect()
+    } else if archive::is_archive(path) {
+        let members = archive::members(path)
+            .map_err(|err| eprintln!("Failed to read {}: {err}", path.display()))
+            .ok()?;
+        // The rules for the archive apply to its members too
+        members
+            .into_iter()
+            .filter(|member| filter.allows(member, false) && is_valid_media(member, video))
+            .collect()
+    } else if filter.allows(path, false) && is_valid_media(path, video) {
+        vec![path.to_path_buf()]
+    } else {
+        return None;
+    };
+    Some((source, files))
+}
+
+/// Probes the file and checks it against the filter
+fn is_playable(
+    diagnostics: &Diagnostics,
+    index: &MediaIndex,
+    media_filter: Option<&MediaFilter>,
+    path: &Path,
+) -> bool {
+    is_wanted(diagnostics, index, media_filter, path, index.probe(path))
+}
+
+/// Whether a file probed successfully and passes the filter, recording why not
+fn is_wanted(
+    diagnostics: &Diagnostics,
+    index: &MediaIndex,
+    media_filter: Option<&MediaFilter>,
+    path: &Path,
+    info: Option<MediaInfo>,
+) -> bool {
+    let Some(info) = info else {
+        let reason = match fs::File::open(path) {
+            Ok(_) => Reason::ProbeFailed,
+            Err(err) => Reason::of_io_error(&err, Reason::ProbeFailed),
+        };
+        diagnostics.reject(path, reason, index.failure(path));
+        return false;
+    };
+    match media_filter.and_then(|x| x.rejection(path, &info)) {
+        Some(rejection) => {
+            diagnostics.reject(path, Reason::Filtered, Some(rejection));
+            false
+        }
+        None => true,
+    }
+}
+
+fn report_duplicates(deduper: &Deduper) {
+    let skipped = deduper.skipped();
+    if skipped > 0 {
+        eprintln!("Skipped {skipped} duplicates");
+    }
+}
+
+/// Whether the source is a playlist or a library, whose entries are walked instead
+fn is_playlist_source(path: &Path) -> bool {
+    (playlist::is_playlist(path) || library::is_library(path)) && path.is_file()
+}
+
+/// The entries of the playlist, or the files selected from the library
+fn read_list(source: &Source) -> Result<Vec<playlist::Entry>, (Reason, String)> {
+    if library::is_library(&source.path) {
+        library::read(&source.path, &source.library).map_err(|err| (Reason::UnreadableLibrary, err))
+    } else {
+        playlist::read(&source.path).map_err(|err| (Reason::UnreadablePlaylist, err.to_string()))
+    }
+}
+
+fn is_archive_file(path: &Path) -> bool {
+    archive::is_archive(path) && path.is_file()
+}
+
+/// The resolved path of an entry in a playlist and whether it is walked like a directory, if it
+/// exists
+fn listed_path(path: &Path) -> Option<(PathBuf, bool)> {
+    let path = fs::canonicalize(path).ok()?;
+    let is_dir = fs::metadata(&path).ok()?.is_dir() || archive::is_archive(&path);
+    Some((path, is_dir))
+}
+
+fn roots(opts: &Options) -> Vec<PathBuf> {
+    opts.paths.iter().map(|x| x.path.clone()).collect()
+}
+
+fn includes_video(opts: &Options, source: usize) -> bool {
+    opts.video && opts.paths[source].video
 }
 
 fn is_valid_media<P: AsRef<Path>>(file_name: P, include_video: bool) -> bool {
@@ -105,45 +844,779 @@ fn is_valid_media<P: AsRef<Path>>(file_name: P, include_video: bool) -> bool {
         })
 }
 
-fn is_hidden(str: &OsStr) -> bool {
-    str.to_str().unwrap().starts_with('.')
+/// Walks the paths in the order of [`SortKey`], slotting in files that are added while running
+pub struct SequentialMediaIterator {
+    opts: Options,
+    index: MediaIndex,
+    filter: WalkFilter,
+    media_filter: Option<MediaFilter>,
+    deduper: Deduper,
+    diagnostics: Diagnostics,
+    walk: Box<dyn Iterator<Item = SortKey>>,
+    peeked: Option<SortKey>,
+    last: Option<SortKey>,
+    // Added files that have not been played yet, ordered like the walk
+    pending: BTreeSet<SortKey>,
+    // Added files that were played before the walk reached them
+    played_early: HashSet<PathBuf>,
+    // Every file played so far, which the watcher reporting it again doesn't bring back
+    played: HashSet<PathBuf>,
+    events: Option<Receiver<WatchEvent>>,
+    _watcher: Option<RecommendedWatcher>,
 }
 
-pub fn sequential_media_iterator(opts: Options) -> impl Iterator<Item = PathBuf> {
-    opts.paths.into_iter().flat_map(move |dir| {
-        WalkDir::new(dir)
-            .sort_by_file_name()
-            .into_iter()
-            .filter_entry(move |x| opts.hidden || !is_hidden(x.file_name()))
-            .filter_map(|x| x.ok())
-            .filter(move |x| {
-                is_valid_media(x.file_name(), opts.video) && ffprobe::ffprobe(x.path()).is_ok()
+impl SequentialMediaIterator {
+    pub fn new(opts: Options, index: MediaIndex, diagnostics: Diagnostics) -> Self {
+        let (watcher, events) = opts
+            .watch
+            .then(|| watcher::watch(&roots(&opts)))
+            .flatten()
+            .unzip();
+        let media_filter = MediaFilter::from_options(&opts);
+        let deduper = Deduper::new(&opts, index.clone(), diagnostics.clone());
+        let probe_pool = ProbePool::new(&index);
+        let walks = {
+            let (opts, index) = (opts.clone(), index.clone());
+            let (media_filter, deduper) = (media_filter.clone(), deduper.clone());
+            let diagnostics = diagnostics.clone();
+            move || -> Vec<_> {
+                (0..opts.paths.len())
+                    .map(|i| {
+                        walk_source(
+                            &opts,
+                            &index,
+                            &probe_pool,
+                            media_filter.clone(),
+                            deduper.clone(),
+                            diagnostics.clone(),
+                            i,
+                        )
+                    })
+                    .collect()
+            }
+        };
+        let walk: Box<dyn Iterator<Item = SortKey>> = if opts.sort_by.is_by_name() {
+            Box::new(merge_sorted(walks()))
+        } else {
+            // Every file has to be looked at before the first one is known, which is done on
+            // another thread like the random order does, so that the caller isn't held up
+            let (tx, rx) = sync_channel(3);
+            thread::spawn(move || {
+                let mut keys: Vec<_> = walks().into_iter().flatten().collect();
+                keys.sort();
+                // Stops once the iterator is dropped
+                for key in keys {
+                    if tx.send(key).is_err() {
+                        return;
+                    }
+                }
+            });
+            Box::new(rx.into_iter())
+        };
+        let walk_deduper = deduper.clone();
+        let walk = Box::new(walk.chain(
+            std::iter::once_with(move || report_duplicates(&walk_deduper)).flat_map(|()| None),
+        ));
+        Self {
+            filter: WalkFilter::new(&opts),
+            media_filter,
+            deduper,
+            diagnostics,
+            opts,
+            index,
+            walk,
+            peeked: None,
+            last: None,
+            pending: BTreeSet::new(),
+            played_early: HashSet::new(),
+            played: HashSet::new(),
+            events,
+            _watcher: watcher,
+        }
+    }
+
+    fn sort_key(&self, path: PathBuf) -> SortKey {
+        SortKey::new(self.opts.sort_by, self.opts.reverse_sort, path, &self.index)
+    }
+
+    fn apply_events(&mut self) {
+        let Some(events) = &self.events else {
+            return;
+        };
+        let events: Vec<_> = events.try_iter().collect();
+        for event in events {
+            match event {
+                WatchEvent::Added(path) => {
+                    let Some((_, files)) = added_files(&self.opts, &mut self.filter, &path) else {
+                        continue;
+                    };
+                    for file in files {
+                        if !self.played.contains(&file) && !self.deduper.is_duplicate(&file) {
+                            let key = self.sort_key(file);
+                            self.pending.insert(key);
+                        }
+                    }
+                }
+                WatchEvent::Removed(path) => {
+                    self.deduper.forget(&path);
+                    self.played.retain(|x| !x.starts_with(&path));
+                    self.pending.retain(|x| !x.path.starts_with(&path));
+                    if self
+                        .peeked
+                        .as_ref()
+                        .is_some_and(|x| x.path.starts_with(&path))
+                    {
+                        self.peeked = None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn next_walked(&mut self) -> Option<SortKey> {
+        if self.peeked.is_none() {
+            self.peeked = loop {
+                let item = self.walk.next()?;
+                if !self.played_early.remove(&item.path) {
+                    break Some(item);
+                }
+            };
+        }
+        self.peeked.clone()
+    }
+}
+
+/// The playable files of one source, sorted if the order follows the directory tree. Playlists
+/// keep their own order.
+fn walk_source(
+    opts: &Options,
+    index: &MediaIndex,
+    probe_pool: &ProbePool,
+    media_filter: Option<MediaFilter>,
+    deduper: Deduper,
+    diagnostics: Diagnostics,
+    i: usize,
+) -> Box<dyn Iterator<Item = SortKey>> {
+    let video = includes_video(opts, i);
+    let (sort_by, reverse) = (opts.sort_by, opts.reverse_sort);
+    let index = index.clone();
+    let root = &opts.paths[i].path;
+    let paths: Box<dyn Iterator<Item = PathBuf>> = if is_playlist_source(root) {
+        Box::new(walk_playlist(
+            opts,
+            &opts.paths[i],
+            video,
+            &index,
+            diagnostics.clone(),
+        ))
+    } else if catalog::is_catalog(root) {
+        let filter = WalkFilter::new(opts);
+        Box::new(walk_catalog(opts, i, video, filter, &diagnostics).into_iter())
+    } else {
+        let filter = WalkFilter::new(opts);
+        let max_depth = opts.paths[i].max_depth;
+        Box::new(walk_tree(
+            opts,
+            root,
+            max_depth,
+            video,
+            filter,
+            diagnostics.clone(),
+        ))
+    };
+    Box::new(
+        probe_pool
+            .probe_ahead(paths)
+            .filter_map(move |(path, info)| {
+                let is_kept = is_wanted(&diagnostics, &index, media_filter.as_ref(), &path, info)
+                    && !deduper.is_duplicate(&path);
+                is_kept.then(|| SortKey::new(sort_by, reverse, path, &index))
+            }),
+    )
+}
+
+/// The media files below the directory, sorted like [`SortKey`] sorts by name
+fn walk_tree(
+    opts: &Options,
+    root: &Path,
+    max_depth: Option<usize>,
+    video: bool,
+    mut filter: WalkFilter,
+    diagnostics: Diagnostics,
+) -> impl Iterator<Item = PathBuf> {
+    let (sort_by, reverse) = (opts.sort_by, opts.reverse_sort);
+    let mut walk_dir = WalkDir::new(root)
+        .follow_links(opts.follow_symlinks)
+        .sort_by(move |a, b| {
+            let ordering = match sort_by {
+                SortBy::NaturalName => natural_cmp(a.file_name(), b.file_name()),
+                _ => a.file_name().cmp(b.file_name()),
+            };
+            if reverse {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+    if let Some(max_depth) = max_depth {
+        walk_dir = walk_dir.max_depth(max_depth);
+    }
+    let walk_diagnostics = diagnostics.clone();
+    // The walk has the other one
+    let mut member_filter = filter.clone();
+    walk_dir
+        .into_iter()
+        .filter_entry({
+            let diagnostics = diagnostics.clone();
+            move |x| {
+                // Archives are walked like directories
+                let is_dir = x.file_type().is_dir()
+                    || (x.file_type().is_file() && archive::is_archive(x.path()));
+                let is_allowed = x.depth() == 0 || filter.allows(x.path(), is_dir);
+                if !is_allowed && x.file_type().is_file() {
+                    diagnostics.reject(x.path(), Reason::Excluded, None);
+                }
+                is_allowed
+            }
+        })
+        .filter_map(move |x| {
+            x.map_err(|err| {
+                let reason = err.io_error().map_or(Reason::UnreadableDir, |io_err| {
+                    Reason::of_io_error(io_err, Reason::UnreadableDir)
+                });
+                let path = err.path().unwrap_or(Path::new(""));
+                walk_diagnostics.reject(path, reason, Some(err.to_string()));
             })
-            .map(|x| x.into_path())
+            .ok()
+        })
+        // Links are only files if they are followed
+        .filter(|x| x.file_type().is_file())
+        .flat_map(move |x| {
+            let path = x.into_path();
+            if archive::is_archive(&path) {
+                return walk_archive(
+                    &path,
+                    sort_by,
+                    reverse,
+                    video,
+                    &mut member_filter,
+                    &diagnostics,
+                );
+            }
+            if !is_valid_media(&path, video) {
+                diagnostics.reject(&path, Reason::NotMedia, None);
+                return vec![];
+            }
+            vec![path]
+        })
+}
+
+/// The media files in the archive, sorted like the walk sorts a directory
+fn walk_archive(
+    path: &Path,
+    sort_by: SortBy,
+    reverse: bool,
+    video: bool,
+    filter: &mut WalkFilter,
+    diagnostics: &Diagnostics,
+) -> Vec<PathBuf> {
+    let mut members = archive::members(path).unwrap_or_else(|err| {
+        diagnostics.reject(path, Reason::UnreadableArchive, Some(err.to_string()));
+        vec![]
+    });
+    members.retain(|member| {
+        let reason = if !filter.allows(member, false) {
+            Reason::Excluded
+        } else if !is_valid_media(member, video) {
+            Reason::NotMedia
+        } else {
+            return true;
+        };
+        diagnostics.reject(member, reason, None);
+        false
+    });
+    members.sort_by(|a, b| {
+        let ordering = match sort_by {
+            SortBy::NaturalName => natural_path_cmp(a, b),
+            _ => a.cmp(b),
+        };
+        if reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+    members
+}
+
+/// The media files listed by the server, sorted like the walk sorts a directory
+fn walk_catalog(
+    opts: &Options,
+    i: usize,
+    video: bool,
+    mut filter: WalkFilter,
+    diagnostics: &Diagnostics,
+) -> Vec<PathBuf> {
+    let source = &opts.paths[i];
+    let mut files = catalog::list(source, opts).unwrap_or_else(|err| {
+        diagnostics.reject(&source.path, Reason::UnreadableDir, Some(err));
+        vec![]
+    });
+    files.retain(|file| {
+        let reason = if !filter.allows(file, false) {
+            Reason::Excluded
+        } else if !is_valid_media(file, video) {
+            Reason::NotMedia
+        } else {
+            return true;
+        };
+        diagnostics.reject(file, reason, None);
+        false
+    });
+    // Assets on photo servers are in directories named by their id, so only their names matter
+    let by_name = photo_server::is_photo_server(&source.path);
+    files.sort_by(|a, b| {
+        let (a, b) = match by_name {
+            true => (
+                Path::new(a.file_name().unwrap()),
+                Path::new(b.file_name().unwrap()),
+            ),
+            false => (a.as_path(), b.as_path()),
+        };
+        let ordering = match opts.sort_by {
+            SortBy::NaturalName => natural_path_cmp(a, b),
+            _ => a.cmp(b),
+        };
+        if opts.reverse_sort {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+    files
+}
+
+/// The media files listed in the playlist in its order, or selected from the library, with listed
+/// directories walked in place
+fn walk_playlist(
+    opts: &Options,
+    source: &Source,
+    video: bool,
+    index: &MediaIndex,
+    diagnostics: Diagnostics,
+) -> impl Iterator<Item = PathBuf> {
+    let mut entries = read_list(source).unwrap_or_else(|(reason, err)| {
+        diagnostics.reject(&source.path, reason, Some(err));
+        vec![]
+    });
+    // Libraries have no order of their own, so they are sorted like a walk
+    if library::is_library(&source.path) {
+        entries.sort_by(|a, b| {
+            let ordering = match opts.sort_by {
+                SortBy::NaturalName => natural_path_cmp(&a.path, &b.path),
+                _ => a.path.cmp(&b.path),
+            };
+            if opts.reverse_sort {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+    }
+    let opts = opts.clone();
+    let index = index.clone();
+    let mut filter = WalkFilter::new(&opts);
+    entries
+        .into_iter()
+        .flat_map(move |entry| -> Box<dyn Iterator<Item = PathBuf>> {
+            let Some((path, is_dir)) = listed_path(&entry.path) else {
+                diagnostics.reject(&entry.path, Reason::Missing, None);
+                return Box::new(std::iter::empty());
+            };
+            if is_dir {
+                // Registers the directory as a root for its own walk
+                let mut dir_filter = WalkFilter::new(&opts);
+                if !dir_filter.allows_listed(&path, true) {
+                    return Box::new(std::iter::empty());
+                }
+                let diagnostics = diagnostics.clone();
+                return Box::new(walk_tree(
+                    &opts,
+                    &path,
+                    None,
+                    video,
+                    dir_filter,
+                    diagnostics,
+                ));
+            }
+            if !filter.allows_listed(&path, false) {
+                diagnostics.reject(&path, Reason::Excluded, None);
+                return Box::new(std::iter::empty());
+            }
+            if !is_valid_media(&path, video) {
+                diagnostics.reject(&path, Reason::NotMedia, None);
+                return Box::new(std::iter::empty());
+            }
+            if let Some(title) = entry.title {
+                index.set_title(&path, title);
+            }
+            Box::new(std::iter::once(path))
+        })
+}
+
+/// Merges iterators that are each sorted into one sorted iterator
+fn merge_sorted<T: Ord>(mut iters: Vec<impl Iterator<Item = T>>) -> impl Iterator<Item = T> {
+    let mut heads: Vec<Option<T>> = iters.iter_mut().map(|x| x.next()).collect();
+    std::iter::from_fn(move || {
+        let i = (0..heads.len())
+            .filter(|&i| heads[i].is_some())
+            .min_by(|&a, &b| heads[a].cmp(&heads[b]))?;
+        std::mem::replace(&mut heads[i], iters[i].next())
     })
 }
 
-pub fn random_media_iterator(opts: Options) -> RandomMediaIterator {
-    RandomMediaIterator::new(opts)
+impl std::iter::Iterator for SequentialMediaIterator {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.apply_events();
+        loop {
+            let walked = self.next_walked();
+            let lower = match &self.last {
+                Some(last) => Bound::Excluded(last),
+                None => Bound::Unbounded,
+            };
+            let pending = self
+                .pending
+                .range((lower, Bound::Unbounded))
+                .next()
+                .cloned();
+            let item = match (pending, walked) {
+                (Some(pending), Some(walked)) if pending <= walked => {
+                    self.pending.remove(&pending);
+                    if pending == walked {
+                        self.peeked = None;
+                    } else {
+                        self.played_early.insert(pending.path.clone());
+                    }
+                    pending
+                }
+                (_, Some(walked)) => {
+                    self.peeked = None;
+                    self.pending.remove(&walked);
+                    walked
+                }
+                (Some(pending), None) => {
+                    self.pending.remove(&pending);
+                    pending
+                }
+                // Files added behind the current position are played once the walk is done
+                (None, None) => self.pending.pop_first()?,
+            };
+            if location::exists(&item.path)
+                && is_playable(
+                    &self.diagnostics,
+                    &self.index,
+                    self.media_filter.as_ref(),
+                    &item.path,
+                )
+            {
+                let path = item.path.clone();
+                self.played.insert(path.clone());
+                self.last = Some(item);
+                return Some(path);
+            }
+        }
+    }
+}
+
+pub fn sequential_media_iterator(
+    opts: Options,
+    index: MediaIndex,
+    diagnostics: Diagnostics,
+) -> SequentialMediaIterator {
+    SequentialMediaIterator::new(opts, index, diagnostics)
+}
+
+pub fn random_media_iterator(
+    opts: Options,
+    index: MediaIndex,
+    history: History,
+    diagnostics: Diagnostics,
+) -> RandomMediaIterator {
+    RandomMediaIterator::new(opts, index, history, diagnostics)
 }
 
 #[auto_enum(Iterator)]
-pub fn media_iterator(mut opts: Options) -> impl Iterator<Item = PathBuf> {
-    let users_dirs = directories::UserDirs::new().unwrap();
-    for path in &mut opts.paths {
-        if path.starts_with("~/") {
-            *path = PathBuf::from(
-                users_dirs
-                    .home_dir()
-                    .join(&path.to_str().unwrap().to_string()[2..])
-                    .to_str()
-                    .unwrap(),
-            );
-        }
+pub fn media_iterator(
+    mut opts: Options,
+    index: MediaIndex,
+    history: History,
+    diagnostics: Diagnostics,
+) -> impl Iterator<Item = PathBuf> {
+    opts.paths.retain(|source| source.enabled);
+    diagnostics.clear();
+    index.set_prober(probe::prober(&opts));
+    index.set_reads_metadata(MediaFilter::from_options(&opts).is_some_and(|x| x.uses_metadata()));
+    for source in &mut opts.paths {
+        source.path = source.resolved_path();
     }
-    if opts.random {
-        random_media_iterator(opts)
+    if opts.order.is_random() {
+        random_media_iterator(opts, index, history, diagnostics)
     } else {
-        sequential_media_iterator(opts)
+        sequential_media_iterator(opts, index, diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::Playable;
+    use crate::test_dir::TestDir;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn library() -> TestDir {
+        let dir = TestDir::new();
+        for name in [
+            "a/1.jpg",
+            "a/2.jpg",
+            "a/b/3.jpg",
+            "c/4.png",
+            "5.jpg",
+            "6.gif",
+        ] {
+            dir.file(name, b"");
+        }
+        dir.file("notes.txt", b"");
+        dir
+    }
+
+    fn relative(dir: &Path, paths: impl Iterator<Item = PathBuf>) -> Vec<String> {
+        paths
+            .map(|x| x.strip_prefix(dir).unwrap().display().to_string())
+            .collect()
+    }
+
+    fn shuffled(dir: &Path, seed: u64, history: &History) -> Vec<String> {
+        let opts = Options {
+            order: Order::Shuffle,
+            seed: Some(seed),
+            watch: false,
+            paths: vec![Source::new(dir.to_path_buf())],
+            ..Default::default()
+        };
+        let index = MediaIndex::default();
+        index.set_prober(Arc::new(Playable));
+        let it = random_media_iterator(opts, index, history.clone(), Diagnostics::default());
+        relative(dir, it)
+    }
+
+    #[test]
+    fn seeded_shuffle_has_a_fixed_order() {
+        let dir = library();
+        let history = History::default();
+        let order = shuffled(dir.path(), 42, &history);
+        assert_eq!(
+            order,
+            [
+                "a/2.jpg",
+                "a/b/3.jpg",
+                "c/4.png",
+                "5.jpg",
+                "6.gif",
+                "a/1.jpg"
+            ]
+        );
+        assert_eq!(shuffled(dir.path(), 42, &history), order);
+        assert_ne!(shuffled(dir.path(), 7, &history), order);
+    }
+
+    #[test]
+    fn seeded_shuffle_ignores_what_was_shown() {
+        let dir = library();
+        let history = History::default();
+        let order = shuffled(dir.path(), 42, &history);
+        // As the next run would see it, with the first files shown in the current cycle
+        for path in &order[..3] {
+            history.mark_shown(&dir.path().join(path));
+        }
+        assert_eq!(shuffled(dir.path(), 42, &history), order);
+    }
+
+    #[test]
+    fn draws_the_least_recently_shown_of_a_bounded_sample() {
+        let history = History::default();
+        let mut scorer = Scorer::LeastRecentlyShown {
+            history: history.clone(),
+            boost_days: 0.0,
+            added: HashMap::new(),
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(42);
+        let files: Vec<PathBuf> = (0..3).map(|i| PathBuf::from(format!("{i}.jpg"))).collect();
+        history.mark_shown(&files[0]);
+        history.mark_shown(&files[2]);
+        assert_eq!(scorer.best(&files, &mut rng), 1);
+
+        let files: Vec<PathBuf> = (0..1000)
+            .map(|i| PathBuf::from(format!("{i}.jpg")))
+            .collect();
+        scorer.best(&files, &mut rng);
+        let Scorer::LeastRecentlyShown { added, .. } = &scorer else {
+            unreachable!();
+        };
+        assert!(added.len() <= 3 + Scorer::SAMPLE_SIZE);
+    }
+
+    struct Memories;
+
+    impl crate::probe::Prober for Memories {
+        fn probe(&self, path: &Path) -> Result<MediaInfo, String> {
+            let today = CaptureDate::today();
+            let is_memory = path.ends_with("memory.jpg");
+            Ok(MediaInfo {
+                format: "test".to_string(),
+                creation_time: is_memory.then(|| {
+                    format!(
+                        "{}:{:02}:{:02} 12:00:00",
+                        today.year - 1,
+                        today.month,
+                        today.day
+                    )
+                }),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[test]
+    fn draws_known_memories_first() {
+        let dir = TestDir::new();
+        for i in 0..20 {
+            dir.file(format!("{i}.jpg"), b"");
+        }
+        let memory = dir.file("memory.jpg", b"");
+        let opts = Options {
+            order: Order::OnThisDay,
+            watch: false,
+            paths: vec![Source::new(dir.path().to_path_buf())],
+            ..Default::default()
+        };
+        let index = MediaIndex::default();
+        index.set_prober(Arc::new(Memories));
+        // As if probed by an earlier run, the others are only probed once drawn
+        index.probe(&memory);
+        let mut it = random_media_iterator(opts, index, History::default(), Diagnostics::default());
+        assert_eq!(it.next(), Some(memory));
+        assert_eq!(it.count(), 20);
+    }
+
+    #[test]
+    fn sorts_by_size_once_everything_was_walked() {
+        let dir = TestDir::new();
+        dir.file("a.jpg", &[0; 3]);
+        dir.file("b.jpg", &[0; 1]);
+        dir.file("c/d.jpg", &[0; 2]);
+        let opts = Options {
+            order: Order::Sequential,
+            sort_by: SortBy::Size,
+            watch: false,
+            paths: vec![Source::new(dir.path().to_path_buf())],
+            ..Default::default()
+        };
+        let index = MediaIndex::default();
+        index.set_prober(Arc::new(Playable));
+        let it = sequential_media_iterator(opts, index, Diagnostics::default());
+        assert_eq!(relative(dir.path(), it), ["b.jpg", "c/d.jpg", "a.jpg"]);
+    }
+
+    #[test]
+    fn walks_names_that_need_escaping_or_are_not_utf8() {
+        let dir = TestDir::new();
+        let mut expected = vec![
+            dir.file("it's a \"test\".jpg", b""),
+            dir.file("100% #1?.jpg", b""),
+            dir.file("caf\u{e9} [2]/\u{1f600} *.png", b""),
+            dir.file("new\nline.jpg", b""),
+            dir.file("back\\slash.gif", b""),
+            dir.file(OsStr::from_bytes(b"latin1 caf\xe9.jpg"), b""),
+            dir.file(Path::new(OsStr::from_bytes(b"\xff\xfe")).join("a.jpg"), b""),
+        ];
+        let opts = Options {
+            order: Order::Sequential,
+            watch: false,
+            paths: vec![Source::new(dir.path().to_path_buf())],
+            ..Default::default()
+        };
+        let index = MediaIndex::default();
+        index.set_prober(Arc::new(Playable));
+        let mut walked: Vec<PathBuf> =
+            sequential_media_iterator(opts, index, Diagnostics::default()).collect();
+        walked.sort();
+        expected.sort();
+        assert_eq!(walked, expected);
+    }
+
+    /// The next file, waiting for the watcher to add one if the walk is done
+    fn next_added(it: &mut SequentialMediaIterator) -> Option<PathBuf> {
+        let started = std::time::Instant::now();
+        while started.elapsed() < std::time::Duration::from_secs(5) {
+            if let Some(path) = it.next() {
+                return Some(path);
+            }
+            thread::sleep(std::time::Duration::from_millis(50));
+        }
+        None
+    }
+
+    #[test]
+    fn adds_the_members_of_new_archives() {
+        let dir = TestDir::new();
+        let first = dir.file("1.jpg", b"");
+        let opts = Options {
+            order: Order::Sequential,
+            watch: true,
+            paths: vec![Source::new(dir.path().to_path_buf())],
+            ..Default::default()
+        };
+        let index = MediaIndex::default();
+        index.set_prober(Arc::new(Playable));
+        let mut it = sequential_media_iterator(opts, index, Diagnostics::default());
+        assert_eq!(it.next(), Some(first));
+        assert_eq!(it.next(), None);
+        let mut builder = tar::Builder::new(Vec::new());
+        for name in ["2.jpg", "notes.txt"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, &[][..]).unwrap();
+        }
+        let archive = dir.file("album.tar", &builder.into_inner().unwrap());
+        assert_eq!(next_added(&mut it), Some(archive.join("2.jpg")));
+        assert_eq!(it.next(), None);
+    }
+
+    #[test]
+    fn adds_new_entries_of_playlist_sources() {
+        let dir = TestDir::new();
+        let first = dir.file("1.jpg", b"");
+        let second = dir.file("2.jpg", b"");
+        let playlist = dir.file("list.m3u", b"1.jpg\n");
+        let opts = Options {
+            order: Order::Sequential,
+            watch: true,
+            paths: vec![Source::new(playlist.clone())],
+            ..Default::default()
+        };
+        let index = MediaIndex::default();
+        index.set_prober(Arc::new(Playable));
+        let mut it = sequential_media_iterator(opts, index, Diagnostics::default());
+        assert_eq!(it.next(), Some(first));
+        assert_eq!(it.next(), None);
+        std::fs::write(&playlist, b"1.jpg\n2.jpg\n").unwrap();
+        // Without the one that was already played
+        assert_eq!(next_added(&mut it), Some(second));
+        assert_eq!(it.next(), None);
     }
 }
@@ -1,14 +1,111 @@
+use crate::catalog;
+use crate::location;
 use libmpv::events::Event as MPVEvent;
 use libmpv2 as libmpv;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// Runs a command with each argument passed as it is, unlike `Mpv::command` which joins them into
+/// one string for mpv to parse
+pub fn command(mpv: &libmpv::Mpv, args: &[&OsStr]) -> libmpv::Result<()> {
+    let args: Vec<CString> = args
+        .iter()
+        .map(|x| CString::new(x.as_bytes()).unwrap())
+        .collect();
+    let mut ptrs: Vec<*const std::os::raw::c_char> = args
+        .iter()
+        .map(|x| x.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect();
+    // SAFETY: the arguments are null terminated and outlive the call
+    let err = unsafe { libmpv::mpv_sys::mpv_command(mpv.ctx.as_ptr(), ptrs.as_mut_ptr()) };
+    if err < 0 {
+        Err(libmpv::Error::Raw(err))
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads a property as the bytes mpv has, as `Mpv::get_property` fails on strings that aren't
+/// valid UTF-8, which file names needn't be
+fn get_property_bytes(mpv: &libmpv::Mpv, name: &str) -> Option<Vec<u8>> {
+    let name = CString::new(name).ok()?;
+    // SAFETY: the name is null terminated, and the returned string is freed once copied
+    unsafe {
+        let ptr = libmpv::mpv_sys::mpv_get_property_string(mpv.ctx.as_ptr(), name.as_ptr());
+        if ptr.is_null() {
+            return None;
+        }
+        let bytes = CStr::from_ptr(ptr).to_bytes().to_vec();
+        libmpv::mpv_sys::mpv_free(ptr.cast());
+        Some(bytes)
+    }
+}
 
 pub struct MpvClient {
     mpv: libmpv::Mpv,
+    // The files that mpv plays from where they are extracted to or from a URL, by what it plays
+    loaded_as: HashMap<OsString, PathBuf>,
 }
 
 impl MpvClient {
     pub fn new(mpv: libmpv::Mpv) -> Self {
-        MpvClient { mpv }
+        MpvClient {
+            mpv,
+            loaded_as: HashMap::new(),
+        }
+    }
+
+    /// Loads members of archives from where they are extracted to and files on servers from their
+    /// URL, with the credentials in header fields rather than in the URL, while the playlist keeps
+    /// the path
+    fn loadfile(&mut self, path: &Path, flags: &str) {
+        let playable_path = location::playable_path(path).unwrap_or_else(|err| {
+            eprintln!("Failed to fetch {}: {err}", location::display(path));
+            path.as_os_str().to_owned()
+        });
+        let http_headers = playable_path
+            .to_str()
+            .map(catalog::http_headers)
+            .unwrap_or_default();
+        let args = [OsStr::new("loadfile"), &playable_path, OsStr::new(flags)];
+        if http_headers.is_empty() {
+            command(&self.mpv, &args).unwrap();
+        } else {
+            // Quoted by length, as header fields have colons and spaces
+            let fields = http_headers.join(",");
+            let options = format!("http-header-fields=%{}%{fields}", fields.len());
+            let options = OsStr::new(&options);
+            // mpv 0.38 added the index to insert at before the options, which older versions
+            // reject as too many arguments
+            command(
+                &self.mpv,
+                &[&args[..], &[OsStr::new("-1"), options]].concat(),
+            )
+            .or_else(|_| command(&self.mpv, &[&args[..], &[options]].concat()))
+            .unwrap();
+        }
+        if playable_path != path.as_os_str() {
+            self.loaded_as.insert(playable_path, path.to_path_buf());
+        }
+    }
+
+    /// What mpv plays at the position in the playlist
+    fn playlist_filename(&self, pos: i64) -> Option<OsString> {
+        let filename = get_property_bytes(&self.mpv, &format!("playlist/{pos}/filename"))?;
+        Some(OsString::from_vec(filename))
+    }
+
+    /// The file at the current playlist position
+    pub fn current_path(&self) -> Option<PathBuf> {
+        let playlist_pos = self.mpv.get_property::<i64>("playlist-pos").ok()?;
+        let filename = self.playlist_filename(playlist_pos)?;
+        Some(match self.loaded_as.get(&filename) {
+            Some(path) => path.clone(),
+            None => PathBuf::from(filename),
+        })
     }
 
     pub fn playlist_prev(&self) {
@@ -22,16 +119,12 @@ impl MpvClient {
         self.mpv.command("playlist-next", &[]).ok();
     }
 
-    pub fn playlist_append_play(&self, path: &Path) {
-        let quoted = format!("'{}'", path.to_str().unwrap());
-        self.mpv
-            .command("loadfile", &[&quoted, "append-play"])
-            .unwrap();
+    pub fn playlist_append_play(&mut self, path: &Path) {
+        self.loadfile(path, "append-play");
     }
 
-    pub fn playlist_append(&self, path: &Path) {
-        let quoted = format!("'{}'", path.to_str().unwrap());
-        self.mpv.command("loadfile", &[&quoted, "append"]).unwrap();
+    pub fn playlist_append(&mut self, path: &Path) {
+        self.loadfile(path, "append");
     }
 
     pub fn need_append(&self) -> bool {
@@ -40,9 +133,10 @@ impl MpvClient {
         playlist_count - playlist_pos < 3
     }
 
-    pub fn playlist_replace(&self, path: &Path) {
-        let quoted = format!("'{}'", path.to_str().unwrap());
-        self.mpv.command("loadfile", &[&quoted]).unwrap();
+    pub fn playlist_replace(&mut self, path: &Path) {
+        // Replacing clears the playlist
+        self.loaded_as.clear();
+        self.loadfile(path, "replace");
     }
 
     pub fn next_event(&mut self) -> Option<libmpv::Result<MPVEvent>> {
@@ -64,7 +158,12 @@ impl MpvClient {
     }
 
     /// Clear the playlist, except the currently played file.
-    pub fn playlist_clear(&self) {
+    pub fn playlist_clear(&mut self) {
         self.mpv.command("playlist-clear", &[]).unwrap();
+        let playlist_count = self.mpv.get_property::<i64>("playlist-count").unwrap();
+        let remaining: HashSet<OsString> = (0..playlist_count)
+            .filter_map(|i| self.playlist_filename(i))
+            .collect();
+        self.loaded_as.retain(|x, _| remaining.contains(x));
     }
 }
@@ -1,8 +1,11 @@
+use crate::diagnostics::Diagnostics;
+use crate::location;
 use crate::mpvclient::MpvClient;
 use crate::runner::UserEvent;
-use crate::settings::Options;
+use crate::settings::{Config, Options};
 use egui::{include_image, vec2, Image, Sense, Vec2};
 use egui_glow::egui_winit::winit;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 use winit::dpi::PhysicalSize;
 use winit::event_loop::EventLoopProxy;
@@ -40,21 +43,56 @@ enum ImageVariants {
 
 struct SettingsGui {
     icon: Image<'static>,
+    config: Config,
     opts: Options,
     opts_copy: Options,
     open: bool,
+    diagnostics: Diagnostics,
+    diagnostics_open: bool,
+    // Where the diagnostics were last exported to, or why that failed
+    export_result: Option<String>,
 }
 
 impl SettingsGui {
-    fn new(opts: Options) -> Self {
+    fn new(config: Config, diagnostics: Diagnostics) -> Self {
+        let opts = config.options().clone();
         Self {
+            config,
             icon: Image::new(include_image!("../assets/svg/settings.svg")).sense(Sense::click()),
             opts_copy: opts.clone(),
             opts,
             open: false,
+            diagnostics,
+            diagnostics_open: false,
+            export_result: None,
         }
     }
 
+    fn diagnostics_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.diagnostics_open;
+        egui::Window::new("Diagnostics")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .default_width(420.0)
+            .show(ctx, |ui| {
+                self.diagnostics.ui(ui);
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.button("Export JSON").clicked() {
+                        self.export_result = Some(match self.diagnostics.export() {
+                            Ok(path) => format!("Saved to {}", path.display()),
+                            Err(err) => format!("Failed to export: {err}"),
+                        });
+                    }
+                    if let Some(result) = &self.export_result {
+                        ui.label(result);
+                    }
+                });
+            });
+        self.diagnostics_open = open;
+    }
+
     fn show(
         &mut self,
         ctx: &egui::Context,
@@ -64,7 +102,7 @@ impl SettingsGui {
         if ui.add(self.icon.clone()).clicked() {
             self.open = !self.open;
         }
-        let size = vec2(290.0, 160.0);
+        let size = vec2(290.0, 290.0);
         let window_size = ctx.input(|input| input.screen_rect().size());
         let mut open = self.open;
         let resp = egui::Window::new("Settings")
@@ -78,7 +116,29 @@ impl SettingsGui {
                 egui::Frame::none()
                     .show(ui, |ui| {
                         ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                            ui.button("Ok")
+                            let ok = ui.button("Ok");
+                            if ui
+                                .button("Export played")
+                                .on_hover_text("Save the files shown so far as an M3U playlist")
+                                .clicked()
+                            {
+                                event_proxy.send_event(UserEvent::ExportPlayed).unwrap();
+                            }
+                            if ui
+                                .button("Diagnostics")
+                                .on_hover_text("Why files were skipped during the scan")
+                                .clicked()
+                            {
+                                self.diagnostics_open = !self.diagnostics_open;
+                            }
+                            if ui
+                                .button("Rebuild index")
+                                .on_hover_text("Probe every file again on the next scan")
+                                .clicked()
+                            {
+                                event_proxy.send_event(UserEvent::RebuildIndex).unwrap();
+                            }
+                            ok
                         })
                         .inner
                     })
@@ -87,35 +147,49 @@ impl SettingsGui {
         if self.open && !open {
             self.close_cancel();
         }
+        if self.diagnostics_open {
+            self.diagnostics_window(ctx);
+        }
         let ok_clicked = resp
             .as_ref()
             .is_some_and(|resp| resp.inner.as_ref().unwrap().clicked());
         if ok_clicked {
             self.close_apply(event_proxy);
-            self.opts.save();
+            self.config.save(&self.opts);
         }
         resp.map(|resp| resp.response)
     }
 
     pub fn close_cancel(&mut self) {
         self.open = false;
+        self.diagnostics_open = false;
         self.opts = self.opts_copy.clone();
     }
 
     fn close_apply(&mut self, event_proxy: &EventLoopProxy<UserEvent>) {
         self.open = false;
+        self.diagnostics_open = false;
         if self.opts != self.opts_copy {
             self.opts_copy = self.opts.clone();
             event_proxy
-                .send_event(UserEvent::Reset(self.opts.clone()))
+                .send_event(UserEvent::Reset(Box::new(self.opts.clone())))
                 .unwrap();
         }
     }
 }
 
 pub struct Overlay {
-    pub path: String,
+    pub path: PathBuf,
+    // Shown instead of the path, if a playlist gave one
+    pub title: Option<String>,
+    // Of the current file, shown next to the path
+    pub rating: Option<i8>,
+    pub tags: Vec<String>,
     pub has_media: bool,
+    // How many years ago the current file was taken, when showing memories of this day
+    pub years_ago: Option<i32>,
+    // Messages shown at the top until dismissed
+    notices: Vec<String>,
     last_ui_render_instant: Instant,
     last_center_render_instant: Instant,
     center_pos: egui::Pos2,
@@ -132,7 +206,7 @@ impl Overlay {
     const DURATION: Duration = Duration::from_millis(1000);
     const CENTER_IMAGE_SIZE: Vec2 = Vec2::splat(200.0);
 
-    pub fn new(size: PhysicalSize<u32>, opts: Options) -> Self {
+    pub fn new(size: PhysicalSize<u32>, config: Config, diagnostics: Diagnostics) -> Self {
         let center_images = [
             Image::new(include_image!("../assets/svg/mute.svg")).sense(Sense::click()),
             Image::new(include_image!("../assets/svg/unmute.svg")).sense(Sense::click()),
@@ -143,7 +217,7 @@ impl Overlay {
         let mute_toggle_button = ImageToggleButton::new(
             Image::new(include_image!("./../assets/svg/unmute.svg")).sense(Sense::click()),
             Image::new(include_image!("./../assets/svg/mute.svg")).sense(Sense::click()),
-            opts.mute,
+            config.options().mute,
         );
 
         let pause_toggle_button = ImageToggleButton::new(
@@ -155,7 +229,10 @@ impl Overlay {
         let inactive_instant = Instant::now() - Self::DURATION * 10;
 
         Self {
-            path: String::new(),
+            path: PathBuf::new(),
+            title: None,
+            rating: None,
+            tags: vec![],
             center_pos: ((vec2(size.width as f32, size.height as f32) - Self::CENTER_IMAGE_SIZE)
                 / 2.0)
                 .to_pos2(),
@@ -163,10 +240,12 @@ impl Overlay {
             center_images,
             last_ui_render_instant: inactive_instant,
             last_center_render_instant: inactive_instant,
-            settings_gui: SettingsGui::new(opts),
+            settings_gui: SettingsGui::new(config, diagnostics),
             mute_toggle_button,
             pause_toggle_button,
             has_media: true,
+            years_ago: None,
+            notices: vec![],
             keep_visible: false,
         }
     }
@@ -194,6 +273,12 @@ impl Overlay {
                     });
                 });
         }
+        if let Some(years_ago) = self.years_ago.filter(|_| self.has_media) {
+            Self::years_ago_ui(ctx, years_ago);
+        }
+        if !self.notices.is_empty() {
+            self.notices_ui(ctx);
+        }
         if self.last_center_render_instant.elapsed() < Self::DURATION {
             egui::Area::new("center_area".into())
                 .interactable(false)
@@ -214,6 +299,50 @@ impl Overlay {
         }
     }
 
+    fn years_ago_ui(ctx: &egui::Context, years_ago: i32) {
+        egui::Area::new("years_ago".into())
+            .interactable(false)
+            .anchor(egui::Align2::LEFT_TOP, vec2(18.0, 18.0))
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    let text = match years_ago {
+                        1 => "1 year ago".to_string(),
+                        n => format!("{n} years ago"),
+                    };
+                    ui.label(egui::RichText::new(text).size(28.0));
+                });
+            });
+    }
+
+    fn notices_ui(&mut self, ctx: &egui::Context) {
+        egui::Area::new("notices".into())
+            .anchor(egui::Align2::CENTER_TOP, vec2(0.0, 18.0))
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.set_max_width(480.0);
+                    let dismissed =
+                        self.notices
+                            .iter()
+                            .enumerate()
+                            .fold(None, |dismissed, (i, notice)| {
+                                ui.horizontal(|ui| {
+                                    let clicked = ui.small_button("Dismiss").clicked();
+                                    ui.label(notice);
+                                    clicked.then_some(i).or(dismissed)
+                                })
+                                .inner
+                            });
+                    if let Some(i) = dismissed {
+                        self.notices.remove(i);
+                    }
+                });
+            });
+    }
+
+    pub fn notify(&mut self, notice: String) {
+        self.notices.push(notice);
+    }
+
     fn bottom_panel(
         &mut self,
         ctx: &egui::Context,
@@ -240,7 +369,11 @@ impl Overlay {
                 })
                 .inner
             });
-        if response.clicked_elsewhere() && inner.is_some_and(|r| r.clicked_elsewhere()) {
+        // Clicks in the diagnostics window would count as elsewhere
+        let is_elsewhere = response.clicked_elsewhere()
+            && inner.is_some_and(|r| r.clicked_elsewhere())
+            && !self.settings_gui.diagnostics_open;
+        if is_elsewhere {
             self.settings_gui.close_cancel();
         }
         self.keep_visible = if self.settings_gui.open || response.contains_pointer() {
@@ -252,12 +385,16 @@ impl Overlay {
     }
 
     fn path_label(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        let path = match &self.title {
+            Some(title) => title.clone(),
+            None => location::display(&self.path),
+        };
         let path_label_width = egui::Area::new("phantom_path_label".into())
             .interactable(false)
             .show(ctx, |ui| {
                 ui.add_visible(
                     false,
-                    egui::Label::new(egui::RichText::new(&self.path).size(14.0)),
+                    egui::Label::new(egui::RichText::new(&path).size(14.0)),
                 )
             })
             .response
@@ -265,7 +402,24 @@ impl Overlay {
             .width();
         let available_width = ui.available_width();
         ui.add_space((available_width - path_label_width) / 2.0);
-        ui.label(egui::RichText::new(&self.path).size(14.0));
+        let label = ui.label(egui::RichText::new(&path).size(14.0));
+        if self.title.is_some() {
+            label.on_hover_text(location::display(&self.path));
+        }
+        // Laid out right to left, so this ends up before the path
+        let rating = match self.rating {
+            Some(-1) => "Rejected".to_string(),
+            Some(stars) => "★".repeat(stars.max(0) as usize),
+            None => String::new(),
+        };
+        let details = [rating, self.tags.join(", ")]
+            .into_iter()
+            .filter(|x| !x.is_empty())
+            .collect::<Vec<_>>()
+            .join("  ");
+        if !details.is_empty() {
+            ui.label(egui::RichText::new(details).size(14.0).weak());
+        }
     }
 
     pub fn toggle_mute(&mut self, mpv_client: &MpvClient) {
@@ -279,6 +433,10 @@ impl Overlay {
         self.last_center_render_instant = Instant::now();
     }
 
+    pub fn is_muted(&self) -> bool {
+        self.mute_toggle_button.on
+    }
+
     pub fn toggle_pause(&mut self, mpv_client: &MpvClient) {
         let pause = self.pause_toggle_button.toggle();
         mpv_client.set_pause(pause);
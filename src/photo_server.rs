@@ -0,0 +1,509 @@
+use crate::capture_date::CaptureDate;
+use crate::catalog::{self, Catalog, Listed};
+use crate::settings::Source;
+use crate::Options;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+// How many assets are asked for at once
+const PAGE_SIZE: usize = 1000;
+
+// The servers of the sources opened so far, by the start of their paths like `immich://host`,
+// so that the paths of their assets can be turned into URLs
+static SERVERS: Mutex<BTreeMap<String, Server>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy, PartialEq)]
+enum Api {
+    Immich,
+    PhotoPrism,
+}
+
+/// Whether the path is on an Immich or PhotoPrism server, either a source or an asset listed in
+/// one. Sources are `immich://host/albums/<id>`, `immich://host/people/<id>`,
+/// `immich://host/favorites` or everything at `immich://host`, and the same for `photoprism://`,
+/// with `immichs://` and `photoprisms://` for HTTPS.
+pub fn is_photo_server(path: &Path) -> bool {
+    parse(path).is_some()
+}
+
+struct Parsed<'a> {
+    api: Api,
+    // The URL of the server, which the API paths are relative to
+    base: Url,
+    // The key of the server in `SERVERS`
+    server: &'a str,
+    // The path after the host
+    rest: &'a str,
+}
+
+fn parse(path: &Path) -> Option<Parsed<'_>> {
+    let str = path.to_str()?;
+    let (scheme, after_scheme) = str.split_once("://")?;
+    let (api, http) = match scheme {
+        "immich" => (Api::Immich, "http"),
+        "immichs" => (Api::Immich, "https"),
+        "photoprism" => (Api::PhotoPrism, "http"),
+        "photoprisms" => (Api::PhotoPrism, "https"),
+        _ => return None,
+    };
+    let (host, rest) = after_scheme.split_once('/').unwrap_or((after_scheme, ""));
+    Some(Parsed {
+        api,
+        base: Url::parse(&format!("{http}://{host}/")).ok()?,
+        server: &str[..scheme.len() + 3 + host.len()],
+        rest,
+    })
+}
+
+/// What a source asks the server for
+enum Query {
+    All,
+    Album(String),
+    Favorites,
+    Person(String),
+}
+
+impl Query {
+    fn parse(rest: &str) -> Option<Self> {
+        let segments: Vec<&str> = rest.split('/').filter(|x| !x.is_empty()).collect();
+        match segments.as_slice() {
+            [] => Some(Self::All),
+            ["albums", id] => Some(Self::Album(id.to_string())),
+            ["favorites"] => Some(Self::Favorites),
+            ["people", id] => Some(Self::Person(id.to_string())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Server {
+    api: Api,
+    base: Url,
+    api_key: String,
+    previews: bool,
+    // PhotoPrism puts these in URLs instead of the API key, and sends them with every response
+    download_token: Option<String>,
+    preview_token: Option<String>,
+}
+
+impl Server {
+    fn request(&self, method: &str, url: &Url) -> ureq::Request {
+        let request = catalog::agent().request_url(method, url);
+        match self.api {
+            Api::Immich => request.set("x-api-key", &self.api_key),
+            Api::PhotoPrism => request.set("Authorization", &format!("Bearer {}", self.api_key)),
+        }
+    }
+
+    fn get(path: &Path) -> io::Result<Self> {
+        let parsed = parse(path).ok_or_else(|| io::Error::other("not on a photo server"))?;
+        let server = SERVERS.lock().unwrap().get(parsed.server).cloned();
+        let mut server = server.ok_or_else(|| io::Error::other("not in a source"))?;
+        if server.api == Api::PhotoPrism && server.download_token.is_none() {
+            // The listing was cached, so no response came with the tokens yet
+            let url = server.base.join("api/v1/photos?count=1").unwrap();
+            let response = server
+                .request("GET", &url)
+                .call()
+                .map_err(io::Error::other)?;
+            remember_tokens(parsed.server, &response);
+            server = SERVERS.lock().unwrap()[parsed.server].clone();
+        }
+        Ok(server)
+    }
+
+    /// Where to fetch the asset from
+    fn asset_url(&self, id: &str, is_image: bool) -> Url {
+        let token = |x: &Option<String>| x.clone().unwrap_or_default();
+        let path = match (self.api, self.previews, is_image) {
+            (Api::Immich, true, true) => format!("api/assets/{id}/thumbnail?size=preview"),
+            (Api::Immich, true, false) => format!("api/assets/{id}/video/playback"),
+            (Api::Immich, false, _) => format!("api/assets/{id}/original"),
+            (Api::PhotoPrism, true, true) => {
+                format!("api/v1/t/{id}/{}/fit_1920", token(&self.preview_token))
+            }
+            (Api::PhotoPrism, true, false) => {
+                format!("api/v1/videos/{id}/{}/avc", token(&self.preview_token))
+            }
+            (Api::PhotoPrism, false, _) => {
+                format!("api/v1/dl/{id}?t={}", token(&self.download_token))
+            }
+        };
+        self.base.join(&path).unwrap()
+    }
+}
+
+fn remember_tokens(server: &str, response: &ureq::Response) {
+    if let Some(server) = SERVERS.lock().unwrap().get_mut(server) {
+        let header = |name| response.header(name).map(str::to_string);
+        server.download_token = header("X-Download-Token").or(server.download_token.take());
+        server.preview_token = header("X-Preview-Token").or(server.preview_token.take());
+    }
+}
+
+/// The id of the asset, which is the name of the directory it is in
+fn asset_id(path: &Path) -> io::Result<&str> {
+    path.parent()
+        .and_then(Path::file_name)
+        .and_then(|x| x.to_str())
+        .ok_or_else(|| io::Error::other("not an asset"))
+}
+
+/// Assets are named like files in a directory named by their id, like
+/// `immich://host/favorites/<id>/IMG_0001.HEIC`, so that the type can be told from the name
+fn asset_path(root: &Path, id: &str, file_name: &str) -> Option<PathBuf> {
+    let file_name = Path::new(file_name).file_name()?;
+    Some(root.join(id).join(file_name))
+}
+
+fn parse_date(str: &str) -> Option<SystemTime> {
+    let secs = u64::try_from(CaptureDate::parse(str)?.timestamp()).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Writes the original or the preview of the asset
+pub fn download(path: &Path, writer: &mut impl io::Write) -> io::Result<()> {
+    let server = Server::get(path)?;
+    let url = server.asset_url(asset_id(path)?, catalog::is_image(path));
+    let response = server
+        .request("GET", &url)
+        .call()
+        .map_err(io::Error::other)?;
+    io::copy(&mut response.into_reader(), writer)?;
+    Ok(())
+}
+
+/// The URL of the original or the preview of the asset, which has the tokens PhotoPrism wants in
+/// it, while Immich wants the key from [`http_header`]
+pub fn url(path: &Path) -> io::Result<String> {
+    let server = Server::get(path)?;
+    let url = server.asset_url(asset_id(path)?, catalog::is_image(path));
+    Ok(url.into())
+}
+
+/// The header field with the API key for the URL, if it is on the Immich server of a source
+pub fn http_header(url: &Url) -> Option<String> {
+    let servers = SERVERS.lock().unwrap();
+    let server = servers.values().find(|x| {
+        x.api == Api::Immich
+            && !x.api_key.is_empty()
+            && x.base.origin() == url.origin()
+            && url.path().starts_with(x.base.path())
+    })?;
+    Some(format!("x-api-key: {}", server.api_key))
+}
+
+/// An album, a person or the favourites on a photo server
+pub struct Collection {
+    root: PathBuf,
+    server: String,
+    query: Option<Query>,
+}
+
+impl Collection {
+    /// Also registers the server, so that the listed assets can be fetched
+    pub fn new(source: &Source, opts: &Options) -> Self {
+        let parsed = parse(&source.path).unwrap();
+        let mut servers = SERVERS.lock().unwrap();
+        let server = servers
+            .entry(parsed.server.to_string())
+            .or_insert_with(|| Server {
+                api: parsed.api,
+                base: parsed.base.clone(),
+                api_key: String::new(),
+                previews: opts.server_previews,
+                download_token: None,
+                preview_token: None,
+            });
+        // Sources on the same server only need the key once
+        if !source.api_key.is_empty() {
+            server.api_key = source.api_key.clone();
+        }
+        server.previews = opts.server_previews;
+        Self {
+            root: source.path.clone(),
+            server: parsed.server.to_string(),
+            query: Query::parse(parsed.rest),
+        }
+    }
+
+    fn list_immich(&self, server: &Server, query: &Query) -> Result<Vec<Listed>, String> {
+        let url = server.base.join("api/search/metadata").unwrap();
+        let mut assets = vec![];
+        let mut page = Some("1".to_string());
+        while let Some(number) = page {
+            let mut body = serde_json::json!({
+                "page": number.parse::<u32>().map_err(|err| err.to_string())?,
+                "size": PAGE_SIZE,
+                "withExif": true,
+            });
+            match query {
+                Query::All => {}
+                Query::Album(id) => body["albumIds"] = serde_json::json!([id]),
+                Query::Favorites => body["isFavorite"] = true.into(),
+                Query::Person(id) => body["personIds"] = serde_json::json!([id]),
+            }
+            let response = server
+                .request("POST", &url)
+                .set("Content-Type", "application/json")
+                .send_string(&body.to_string())
+                .map_err(|err| err.to_string())?;
+            let response: ImmichSearch =
+                serde_json::from_reader(response.into_reader()).map_err(|err| err.to_string())?;
+            assets.extend(response.assets.items.into_iter().filter_map(|asset| {
+                Some(Listed {
+                    path: asset_path(&self.root, &asset.id, &asset.original_file_name)?,
+                    len: asset
+                        .exif_info
+                        .and_then(|x| x.file_size_in_byte)
+                        .unwrap_or_default(),
+                    modified: asset
+                        .file_modified_at
+                        .as_deref()
+                        .and_then(parse_date)
+                        .unwrap_or(SystemTime::UNIX_EPOCH),
+                })
+            }));
+            page = response.assets.next_page;
+        }
+        Ok(assets)
+    }
+
+    fn list_photoprism(&self, server: &Server, query: &Query) -> Result<Vec<Listed>, String> {
+        let url = server.base.join("api/v1/photos").unwrap();
+        let mut assets = vec![];
+        let mut uids = HashSet::new();
+        let mut offset = 0;
+        loop {
+            let mut request = server
+                .request("GET", &url)
+                .query("count", &PAGE_SIZE.to_string())
+                .query("offset", &offset.to_string())
+                .query("merged", "true")
+                .query("order", "oldest");
+            request = match query {
+                Query::All => request,
+                Query::Album(uid) => request.query("s", uid),
+                Query::Favorites => request.query("favorite", "true"),
+                Query::Person(uid) => request.query("subject", uid),
+            };
+            let response = request.call().map_err(|err| err.to_string())?;
+            remember_tokens(&self.server, &response);
+            let photos: Vec<PhotoPrismPhoto> =
+                serde_json::from_reader(response.into_reader()).map_err(|err| err.to_string())?;
+            offset += photos.len();
+            let is_full = photos.len() == PAGE_SIZE;
+            let mut has_new = false;
+            assets.extend(photos.into_iter().filter_map(|photo| {
+                if !uids.insert(photo.uid) {
+                    return None;
+                }
+                has_new = true;
+                let len = photo
+                    .files
+                    .iter()
+                    .find(|x| x.hash == photo.hash)
+                    .map_or(0, |x| x.size);
+                Some(Listed {
+                    path: asset_path(&self.root, &photo.hash, &photo.file_name)?,
+                    len,
+                    modified: photo
+                        .updated_at
+                        .as_deref()
+                        .and_then(parse_date)
+                        .unwrap_or(SystemTime::UNIX_EPOCH),
+                })
+            }));
+            // A server that ignores the offset would send the same page forever
+            if !is_full || !has_new {
+                break;
+            }
+        }
+        Ok(assets)
+    }
+}
+
+impl Catalog for Collection {
+    fn list(&self) -> Result<Vec<Listed>, String> {
+        let query = self
+            .query
+            .as_ref()
+            .ok_or("not everything, an album, a person or the favorites")?;
+        let server = SERVERS.lock().unwrap()[&self.server].clone();
+        if server.api_key.is_empty() {
+            return Err("no API key".to_string());
+        }
+        match server.api {
+            Api::Immich => self.list_immich(&server, query),
+            Api::PhotoPrism => self.list_photoprism(&server, query),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ImmichSearch {
+    assets: ImmichPage,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImmichPage {
+    items: Vec<ImmichAsset>,
+    // A number as a string, or None after the last page
+    next_page: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImmichAsset {
+    id: String,
+    original_file_name: String,
+    file_modified_at: Option<String>,
+    exif_info: Option<ImmichExif>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImmichExif {
+    file_size_in_byte: Option<u64>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PhotoPrismPhoto {
+    #[serde(rename = "UID")]
+    uid: String,
+    // Of the primary file, which is how files are fetched
+    hash: String,
+    // Relative to the originals folder
+    file_name: String,
+    updated_at: Option<String>,
+    #[serde(default)]
+    files: Vec<PhotoPrismFile>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PhotoPrismFile {
+    hash: String,
+    size: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Request, TestServer};
+
+    fn collection(path: String) -> Collection {
+        let source = Source {
+            api_key: "secret".to_string(),
+            ..Source::new(PathBuf::from(path))
+        };
+        Collection::new(&source, &Options::default())
+    }
+
+    fn query(request: &Request, name: &str) -> String {
+        let url = Url::parse(&format!("http://localhost{}", request.target)).unwrap();
+        let (_, value) = url.query_pairs().find(|(x, _)| x == name).unwrap();
+        value.into_owned()
+    }
+
+    #[test]
+    fn lists_immich_albums_by_page() {
+        let server = TestServer::new(|request| {
+            let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+            let (id, next_page) = match body["page"].as_u64().unwrap() {
+                1 => ("a", serde_json::json!("2")),
+                _ => ("b", serde_json::Value::Null),
+            };
+            let response = serde_json::json!({"assets": {
+                "items": [{
+                    "id": id,
+                    "originalFileName": format!("{id}.jpg"),
+                    "fileModifiedAt": "2021-06-01T12:00:00.000Z",
+                    "exifInfo": {"fileSizeInByte": 3},
+                }],
+                "nextPage": next_page,
+            }});
+            (200, response.to_string())
+        });
+        let root = format!("immich://{}/albums/1", server.host());
+        let listed = collection(root.clone()).list().unwrap();
+        let paths: Vec<PathBuf> = listed.iter().map(|x| x.path.clone()).collect();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from(format!("{root}/a/a.jpg")),
+                PathBuf::from(format!("{root}/b/b.jpg"))
+            ]
+        );
+        assert_eq!(listed[0].len, 3);
+        for request in server.requests() {
+            assert_eq!(request.target, "/api/search/metadata");
+            assert_eq!(request.header("x-api-key"), Some("secret"));
+            let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+            assert_eq!(body["albumIds"], serde_json::json!(["1"]));
+        }
+
+        // The key goes in a header field rather than in the URL
+        let url = url(&paths[0]).unwrap();
+        assert_eq!(
+            url,
+            format!("http://{}/api/assets/a/original", server.host())
+        );
+        assert_eq!(catalog::http_headers(&url), ["x-api-key: secret"]);
+    }
+
+    /// Photos from the offset on, from a library of the given size, or always the first page if
+    /// the offset is ignored
+    fn photoprism(total: usize, ignores_offset: bool) -> TestServer {
+        TestServer::new(move |request| {
+            let count: usize = query(request, "count").parse().unwrap();
+            let offset: usize = match ignores_offset {
+                true => 0,
+                false => query(request, "offset").parse().unwrap(),
+            };
+            let photos: Vec<_> = (offset..total.min(offset + count))
+                .map(|i| {
+                    serde_json::json!({
+                        "UID": format!("p{i}"),
+                        "Hash": format!("h{i}"),
+                        "FileName": format!("2021/{i}.jpg"),
+                        "Files": [{"Hash": format!("h{i}"), "Size": i}],
+                    })
+                })
+                .collect();
+            (200, serde_json::Value::from(photos).to_string())
+        })
+    }
+
+    #[test]
+    fn lists_photoprism_by_page() {
+        let server = photoprism(PAGE_SIZE + 5, false);
+        let root = format!("photoprism://{}/favorites", server.host());
+        let listed = collection(root.clone()).list().unwrap();
+        assert_eq!(listed.len(), PAGE_SIZE + 5);
+        assert_eq!(listed[7].path, PathBuf::from(format!("{root}/h7/7.jpg")));
+        assert_eq!(listed[7].len, 7);
+        let offsets: Vec<String> = server
+            .requests()
+            .iter()
+            .map(|x| query(x, "offset"))
+            .collect();
+        assert_eq!(offsets, ["0", PAGE_SIZE.to_string().as_str()]);
+    }
+
+    #[test]
+    fn stops_when_photoprism_sends_the_same_page() {
+        let server = photoprism(PAGE_SIZE * 3, true);
+        let root = format!("photoprism://{}", server.host());
+        let listed = collection(root).list().unwrap();
+        assert_eq!(listed.len(), PAGE_SIZE);
+        assert_eq!(server.requests().len(), 2);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::location;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// A file or directory listed in a playlist
+pub struct Entry {
+    pub path: PathBuf,
+    // From `#EXTINF` or `TitleN=`, if given
+    pub title: Option<String>,
+}
+
+/// Whether the file is read as a list of paths rather than walked or shown
+pub fn is_playlist(path: &Path) -> bool {
+    path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+        ["m3u", "m3u8", "pls", "txt", "lst"]
+            .iter()
+            .any(|x| ext.eq_ignore_ascii_case(x))
+    })
+}
+
+/// Reads an M3U, M3U8 or PLS playlist, or a plain list with one path per line. Relative entries
+/// are relative to the directory of the playlist.
+pub fn read(path: &Path) -> std::io::Result<Vec<Entry>> {
+    let bytes = std::fs::read(path)?;
+    // Written by some Windows editors
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&bytes);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let lines = bytes
+        .split(|&x| x == b'\n')
+        .map(|x| x.trim_ascii())
+        .filter(|x| !x.is_empty());
+    let is_pls = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("pls"))
+        || lines.clone().next() == Some(b"[playlist]");
+    Ok(if is_pls {
+        read_pls(dir, lines)
+    } else {
+        read_m3u(dir, lines)
+    })
+}
+
+/// Also reads plain lists, which are M3U without the extended tags
+fn read_m3u<'a>(dir: &Path, lines: impl Iterator<Item = &'a [u8]>) -> Vec<Entry> {
+    let mut entries = vec![];
+    let mut title = None;
+    for line in lines {
+        if let Some(info) = line.strip_prefix(b"#EXTINF:") {
+            // Like `#EXTINF:123,Artist - Title`, the title is after the duration and attributes
+            title = info
+                .iter()
+                .position(|&x| x == b',')
+                .map(|i| String::from_utf8_lossy(info[i + 1..].trim_ascii()).into_owned())
+                .filter(|x| !x.is_empty());
+        } else if !line.starts_with(b"#") {
+            entries.push(Entry {
+                path: resolve(dir, line),
+                title: title.take(),
+            });
+        }
+    }
+    entries
+}
+
+fn read_pls<'a>(dir: &Path, lines: impl Iterator<Item = &'a [u8]>) -> Vec<Entry> {
+    // By the number in `FileN=` and `TitleN=`, which can come in any order
+    let mut numbered: BTreeMap<u32, (Option<PathBuf>, Option<String>)> = BTreeMap::new();
+    for line in lines {
+        let Some(i) = line.iter().position(|&x| x == b'=') else {
+            continue;
+        };
+        let (key, value) = (line[..i].to_ascii_lowercase(), line[i + 1..].trim_ascii());
+        let number = |prefix: &[u8]| {
+            let number = key.strip_prefix(prefix)?;
+            std::str::from_utf8(number).ok()?.parse().ok()
+        };
+        if let Some(n) = number(b"file") {
+            numbered.entry(n).or_default().0 = Some(resolve(dir, value));
+        } else if let Some(n) = number(b"title") {
+            numbered.entry(n).or_default().1 = Some(String::from_utf8_lossy(value).into_owned());
+        }
+    }
+    numbered
+        .into_values()
+        .filter_map(|(path, title)| Some(Entry { path: path?, title }))
+        .collect()
+}
+
+/// The path of an entry, which is kept as is if it is a URL other than `file://`, so that it shows
+/// up as missing
+fn resolve(dir: &Path, entry: &[u8]) -> PathBuf {
+    if let Some(path) = entry.strip_prefix(b"file://") {
+        // Like `file:///home/...`, or `file://localhost/home/...`
+        let path = path.strip_prefix(b"localhost").unwrap_or(path);
+        return PathBuf::from(OsStr::from_bytes(&percent_decode(path)));
+    }
+    let path = Path::new(OsStr::from_bytes(entry));
+    let is_url = entry
+        .windows(3)
+        .position(|x| x == b"://")
+        .is_some_and(|i| entry[..i].iter().all(u8::is_ascii_alphanumeric));
+    if is_url {
+        path.to_path_buf()
+    } else {
+        dir.join(path)
+    }
+}
+
+pub fn percent_decode(bytes: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Writes an extended M3U playlist with absolute paths. Members of archives and files on servers
+/// are left out, since other players can't open their paths. Returns how many were left out.
+pub fn write_m3u(path: &Path, entries: &[Entry]) -> std::io::Result<usize> {
+    let mut bytes = b"#EXTM3U\n".to_vec();
+    let mut skipped = 0;
+    for entry in entries {
+        if !location::is_local(&entry.path) {
+            skipped += 1;
+            continue;
+        }
+        if let Some(title) = &entry.title {
+            // A line break would end the tag early
+            let title = title.replace(['\r', '\n'], " ");
+            bytes.extend_from_slice(format!("#EXTINF:-1,{title}\n").as_bytes());
+        }
+        // As is, the path might not be UTF-8
+        bytes.extend_from_slice(entry.path.as_os_str().as_bytes());
+        bytes.push(b'\n');
+    }
+    std::fs::write(path, bytes)?;
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn paths(entries: &[Entry]) -> Vec<&Path> {
+        entries.iter().map(|x| x.path.as_path()).collect()
+    }
+
+    #[test]
+    fn reads_m3u() {
+        let dir = TestDir::new();
+        let playlist = dir.file(
+            "lists/holiday.m3u",
+            b"\xEF\xBB\xBF#EXTM3U\r\n\
+              #EXTINF:12,Beach\r\n\
+              beach.jpg\r\n\
+              # A comment\r\n\
+              \r\n\
+              ../dunes.mp4\r\n\
+              /photos/sunset.jpg\r\n\
+              #EXTINF:-1 tvg-id=\"x\",\r\n\
+              file:///photos/my%20cat.jpg\r\n\
+              https://example.com/a.jpg\r\n",
+        );
+        let entries = read(&playlist).unwrap();
+        let lists = dir.path().join("lists");
+        assert_eq!(
+            paths(&entries),
+            [
+                lists.join("beach.jpg").as_path(),
+                &lists.join("../dunes.mp4"),
+                Path::new("/photos/sunset.jpg"),
+                Path::new("/photos/my cat.jpg"),
+                Path::new("https://example.com/a.jpg"),
+            ]
+        );
+        let titles: Vec<_> = entries.iter().map(|x| x.title.as_deref()).collect();
+        assert_eq!(titles, [Some("Beach"), None, None, None, None]);
+    }
+
+    #[test]
+    fn reads_pls_by_number() {
+        let dir = TestDir::new();
+        let playlist = dir.file(
+            "holiday.pls",
+            b"[playlist]\n\
+              ; A comment\n\
+              File2=second.jpg\n\
+              Title1=First\n\
+              File10=tenth.jpg\n\
+              file1=/photos/first.jpg\n\
+              Title3=Without a file\n\
+              NumberOfEntries=3\n",
+        );
+        let entries = read(&playlist).unwrap();
+        assert_eq!(
+            paths(&entries),
+            [
+                Path::new("/photos/first.jpg"),
+                &dir.path().join("second.jpg"),
+                &dir.path().join("tenth.jpg"),
+            ]
+        );
+        let titles: Vec<_> = entries.iter().map(|x| x.title.as_deref()).collect();
+        assert_eq!(titles, [Some("First"), None, None]);
+    }
+
+    #[test]
+    fn writes_only_local_files() {
+        let dir = TestDir::new();
+        let archive = dir.file("album.zip", b"");
+        let entries = [
+            Entry {
+                path: dir.path().join("beach.jpg"),
+                title: Some("Beach\nat night".into()),
+            },
+            Entry {
+                path: archive.join("page.jpg"),
+                title: None,
+            },
+            Entry {
+                path: PathBuf::from("webdav://example.com/a.jpg"),
+                title: None,
+            },
+            Entry {
+                path: PathBuf::from("immich://example.com/albums/1/a/a.jpg"),
+                title: None,
+            },
+            Entry {
+                path: dir.path().join("dunes.mp4"),
+                title: None,
+            },
+        ];
+        let playlist = dir.path().join("played.m3u");
+        assert_eq!(write_m3u(&playlist, &entries).unwrap(), 3);
+        let entries = read(&playlist).unwrap();
+        assert_eq!(
+            paths(&entries),
+            [dir.path().join("beach.jpg"), dir.path().join("dunes.mp4")]
+        );
+        assert_eq!(entries[0].title.as_deref(), Some("Beach at night"));
+        assert_eq!(entries[1].title, None);
+    }
+}
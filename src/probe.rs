@@ -0,0 +1,347 @@
+use crate::catalog;
+use crate::media_index::MediaInfo;
+use crate::Options;
+use libmpv::events::Event;
+use libmpv2 as libmpv;
+use std::ffi::OsStr;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A way of finding out what a file is
+pub trait Prober: Send + Sync {
+    /// The media info of a playable file, or why it isn't one
+    fn probe(&self, path: &Path) -> Result<MediaInfo, String>;
+}
+
+/// The prober chosen by the options
+pub fn prober(opts: &Options) -> Arc<dyn Prober> {
+    let timeout = Some(Duration::from_secs_f64(opts.probe_timeout_secs));
+    #[cfg(feature = "ffprobe")]
+    if opts.use_ffprobe {
+        if ffprobe_binary::exists() {
+            return Arc::new(ffprobe_binary::Ffprobe { timeout });
+        }
+        eprintln!("ffprobe: command not found, probing in-process instead");
+    }
+    Arc::new(Builtin { timeout })
+}
+
+/// Probes in-process, images by parsing their header and everything else with libmpv
+#[derive(Default)]
+pub struct Builtin {
+    // Probing is given up after this long, unlimited if None
+    pub timeout: Option<Duration>,
+}
+
+// Probes that timed out but are still running, so that a mount that hangs on every read can't
+// pile up threads without bound
+static STUCK_PROBES: AtomicUsize = AtomicUsize::new(0);
+
+impl Builtin {
+    const MAX_STUCK_PROBES: usize = 16;
+}
+
+impl Prober for Builtin {
+    fn probe(&self, path: &Path) -> Result<MediaInfo, String> {
+        let Some(timeout) = self.timeout else {
+            return probe_in_process(path, None);
+        };
+        if STUCK_PROBES.load(Ordering::Relaxed) >= Self::MAX_STUCK_PROBES {
+            return Err("skipped, as too many probes are stuck".to_string());
+        }
+        // Neither a read from a hung mount nor libmpv shutting down can be interrupted, so the
+        // probe runs on a thread of its own that is left behind if it takes too long
+        let (tx, rx) = channel();
+        let abandoned = Arc::new(Mutex::new(false));
+        let abandoned0 = abandoned.clone();
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            let result = probe_in_process(&path, Some(timeout));
+            if *abandoned0.lock().unwrap() {
+                STUCK_PROBES.fetch_sub(1, Ordering::Relaxed);
+            } else {
+                tx.send(result).ok();
+            }
+        });
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                let mut abandoned = abandoned.lock().unwrap();
+                // It may have finished just now
+                if let Ok(result) = rx.try_recv() {
+                    return result;
+                }
+                *abandoned = true;
+                STUCK_PROBES.fetch_add(1, Ordering::Relaxed);
+                Err(format!("timed out after {timeout:?}"))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err("probing crashed".to_string()),
+        }
+    }
+}
+
+fn probe_in_process(path: &Path, timeout: Option<Duration>) -> Result<MediaInfo, String> {
+    let is_image = mime_guess::from_path(path)
+        .first()
+        .is_some_and(|x| x.type_() == mime_guess::mime::IMAGE);
+    match is_image.then(|| probe_image(path)).flatten() {
+        Some(info) => Ok(info),
+        None => probe_with_mpv(path, timeout),
+    }
+}
+
+/// Takes every file to be a playable image without opening it, for tests that can't rely on
+/// libmpv
+#[cfg(test)]
+pub struct Playable;
+
+#[cfg(test)]
+impl Prober for Playable {
+    fn probe(&self, _path: &Path) -> Result<MediaInfo, String> {
+        Ok(MediaInfo {
+            format: "test".to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Reads the format and size from the header, None if the format isn't known
+fn probe_image(path: &Path) -> Option<MediaInfo> {
+    let mut header = [0; 16];
+    let mut file = std::fs::File::open(path).ok()?;
+    std::io::Read::read_exact(&mut file, &mut header).ok()?;
+    let image_type = imagesize::image_type(&header).ok()?;
+    std::io::Seek::rewind(&mut file).ok()?;
+    let size = imagesize::reader_size(BufReader::new(file)).ok()?;
+    Some(image_info(image_type, size))
+}
+
+/// Like the builtin prober does for images, from the start of an image that isn't on disk, such
+/// as a member of an archive. None if the format isn't known or the size isn't in there.
+pub fn probe_image_head(head: &[u8]) -> Option<MediaInfo> {
+    let image_type = imagesize::image_type(head).ok()?;
+    let size = imagesize::blob_size(head).ok()?;
+    Some(image_info(image_type, size))
+}
+
+fn image_info(image_type: imagesize::ImageType, size: imagesize::ImageSize) -> MediaInfo {
+    let format = format!("{image_type:?}").to_lowercase();
+    MediaInfo {
+        width: Some(size.width as u32),
+        height: Some(size.height as u32),
+        duration_secs: None,
+        codec: Some(format.clone()),
+        format,
+        creation_time: None,
+        size: None,
+        rating: None,
+        tags: vec![],
+    }
+}
+
+/// Opens the file in an mpv instance that doesn't output anything, and reads its properties once
+/// it is loaded
+fn probe_with_mpv(path: &Path, timeout: Option<Duration>) -> Result<MediaInfo, String> {
+    let http_headers = path.to_str().map(catalog::http_headers).unwrap_or_default();
+    let mut mpv = libmpv::Mpv::with_initializer(|mpv| {
+        mpv.set_option("config", false)?;
+        mpv.set_option("load-scripts", false)?;
+        mpv.set_option("vo", "null")?;
+        mpv.set_option("ao", "null")?;
+        mpv.set_option("pause", true)?;
+        if !http_headers.is_empty() {
+            mpv.set_option("http-header-fields", http_headers.join(","))?;
+        }
+        Ok(())
+    })
+    .map_err(|err| format!("couldn't create mpv: {err}"))?;
+    crate::mpvclient::command(&mpv, &[OsStr::new("loadfile"), path.as_os_str()])
+        .map_err(|err| format!("mpv couldn't load it: {err}"))?;
+    let deadline = timeout.map(|x| Instant::now() + x);
+    loop {
+        // A negative timeout waits for as long as it takes
+        let wait_secs = deadline.map_or(-1.0, |x| {
+            x.saturating_duration_since(Instant::now()).as_secs_f64()
+        });
+        match mpv.event_context_mut().wait_event(wait_secs) {
+            Some(Ok(Event::FileLoaded)) => break,
+            Some(Ok(Event::EndFile(_))) => return Err("mpv couldn't open it".to_string()),
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(format!("mpv failed: {err}")),
+            None => {
+                if deadline.is_some_and(|x| Instant::now() >= x) {
+                    return Err(format!("timed out after {:?}", timeout.unwrap()));
+                }
+            }
+        }
+    }
+    let dimension = |name| {
+        mpv.get_property::<i64>(name)
+            .ok()
+            .and_then(|x| u32::try_from(x).ok())
+    };
+    Ok(MediaInfo {
+        width: dimension("width"),
+        height: dimension("height"),
+        duration_secs: mpv.get_property::<f64>("duration").ok(),
+        codec: mpv.get_property::<String>("video-format").ok(),
+        format: mpv
+            .get_property::<String>("file-format")
+            .unwrap_or_default(),
+        creation_time: mpv
+            .get_property::<String>("metadata/by-key/creation_time")
+            .ok(),
+        size: None,
+        rating: None,
+        tags: vec![],
+    })
+}
+
+#[cfg(feature = "ffprobe")]
+mod ffprobe_binary {
+    use super::Prober;
+    use crate::catalog;
+    use crate::media_index::MediaInfo;
+    use std::io::Read;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    /// Probes with the ffprobe binary, one process per file
+    pub struct Ffprobe {
+        // Probes that take longer are killed, unlimited if None
+        pub timeout: Option<Duration>,
+    }
+
+    impl Prober for Ffprobe {
+        fn probe(&self, path: &Path) -> Result<MediaInfo, String> {
+            run(path, self.timeout).map(media_info)
+        }
+    }
+
+    pub fn exists() -> bool {
+        let result = Command::new("ffprobe")
+            .arg("-version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        !matches!(result, Err(err) if err.kind() == std::io::ErrorKind::NotFound)
+    }
+
+    fn media_info(probe: ffprobe::FfProbe) -> MediaInfo {
+        let stream = probe
+            .streams
+            .iter()
+            .find(|stream| stream.codec_type.as_deref() == Some("video"));
+        let creation_time = probe
+            .format
+            .tags
+            .as_ref()
+            .and_then(|tags| tags.creation_time.clone())
+            .or_else(|| {
+                probe
+                    .streams
+                    .iter()
+                    .find_map(|stream| stream.tags.as_ref()?.creation_time.clone())
+            });
+        MediaInfo {
+            width: stream.and_then(|x| x.width).map(|x| x as u32),
+            height: stream.and_then(|x| x.height).map(|x| x as u32),
+            duration_secs: probe.format.duration.and_then(|x| x.parse().ok()),
+            codec: stream.and_then(|x| x.codec_name.clone()),
+            format: probe.format.format_name,
+            creation_time,
+            size: None,
+            rating: None,
+            tags: vec![],
+        }
+    }
+
+    /// Runs ffprobe like `ffprobe::ffprobe` does, killing it once it takes longer than the timeout
+    fn run(path: &Path, timeout: Option<Duration>) -> Result<ffprobe::FfProbe, String> {
+        let http_headers = path.to_str().map(catalog::http_headers).unwrap_or_default();
+        let mut command = Command::new("ffprobe");
+        command
+            .args(["-v", "error", "-show_format", "-show_streams"])
+            .args(["-print_format", "json"]);
+        if !http_headers.is_empty() {
+            let lines: String = http_headers.iter().map(|x| format!("{x}\r\n")).collect();
+            command.arg("-headers").arg(lines);
+        }
+        let mut child = command
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("couldn't run ffprobe: {err}"))?;
+        // Read while waiting, so that ffprobe can't block on a full pipe
+        let read_to_end = |mut pipe: Box<dyn Read + Send>| {
+            std::thread::spawn(move || {
+                let mut buf = Vec::new();
+                pipe.read_to_end(&mut buf).ok();
+                buf
+            })
+        };
+        let stdout = read_to_end(Box::new(child.stdout.take().unwrap()));
+        let stderr = read_to_end(Box::new(child.stderr.take().unwrap()));
+        let deadline = timeout.map(|x| Instant::now() + x);
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => {}
+                Err(err) => return Err(format!("couldn't wait for ffprobe: {err}")),
+            }
+            if deadline.is_some_and(|x| Instant::now() >= x) {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(format!("timed out after {:?}", timeout.unwrap()));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let stdout = stdout.join().unwrap();
+        let stderr = stderr.join().unwrap();
+        if !status.success() {
+            let stderr = String::from_utf8_lossy(&stderr);
+            return Err(match stderr.lines().next() {
+                Some(line) => format!("ffprobe failed: {line}"),
+                None => format!("ffprobe failed: {status}"),
+            });
+        }
+        serde_json::from_slice(&stdout).map_err(|err| format!("unexpected ffprobe output: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn gives_up_on_a_file_that_never_opens() {
+        let dir = TestDir::new();
+        // Opening a pipe blocks until something writes to it
+        let fifo = dir.path().join("stuck.jpg");
+        let c_path = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        // SAFETY: the path is null terminated
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+        let prober = Builtin {
+            timeout: Some(Duration::from_millis(100)),
+        };
+        let started = Instant::now();
+        assert_eq!(
+            prober.probe(&fifo),
+            Err("timed out after 100ms".to_string())
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // Left behind, and counted until it gets unstuck
+        assert_eq!(STUCK_PROBES.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::media_index::{MediaIndex, MediaInfo};
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = (PathBuf, Sender<Option<MediaInfo>>);
+
+/// Probes files on a fixed number of threads, so that files are probed in parallel and one that
+/// hangs only holds up its own thread until the probe times out
+///
+/// Cloning is cheap and every clone shares the threads, which exit once every clone is dropped
+#[derive(Clone)]
+pub struct ProbePool {
+    jobs: Sender<Job>,
+    threads: usize,
+}
+
+impl ProbePool {
+    pub fn new(index: &MediaIndex) -> Self {
+        let threads = thread::available_parallelism().map_or(4, NonZeroUsize::get);
+        let (jobs, rx) = channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            let rx = rx.clone();
+            let index = index.clone();
+            thread::spawn(move || loop {
+                let Ok((path, reply)) = rx.lock().unwrap().recv() else {
+                    return;
+                };
+                reply.send(index.probe(&path)).ok();
+            });
+        }
+        Self { jobs, threads }
+    }
+
+    /// Starts probing the file in the background
+    pub fn start(&self, path: PathBuf) -> Probing {
+        let (reply, rx) = channel();
+        self.jobs.send((path.clone(), reply)).unwrap();
+        Probing { path, rx }
+    }
+
+    /// How many files are worth probing ahead
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Probes the files ahead of time, as many at once as there are threads, and returns them in
+    /// the same order with their media info
+    pub fn probe_ahead(
+        &self,
+        paths: impl Iterator<Item = PathBuf>,
+    ) -> impl Iterator<Item = (PathBuf, Option<MediaInfo>)> {
+        let pool = self.clone();
+        let mut paths = paths.fuse();
+        let mut probing = VecDeque::new();
+        std::iter::from_fn(move || {
+            while probing.len() < pool.threads {
+                let Some(path) = paths.next() else {
+                    break;
+                };
+                probing.push_back(pool.start(path));
+            }
+            Some(probing.pop_front()?.wait())
+        })
+    }
+}
+
+/// A file being probed by a [`ProbePool`]
+pub struct Probing {
+    path: PathBuf,
+    rx: Receiver<Option<MediaInfo>>,
+}
+
+impl Probing {
+    pub fn wait(self) -> (PathBuf, Option<MediaInfo>) {
+        let info = self.rx.recv().ok().flatten();
+        (self.path, info)
+    }
+}
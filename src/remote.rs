@@ -0,0 +1,441 @@
+use crate::capture_date::CaptureDate;
+use crate::catalog::{self, Catalog, Listed};
+use crate::playlist::percent_decode;
+use base64::Engine;
+use quick_xml::events::Event;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+// Asks for what is needed to list a directory, and nothing else
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/></d:prop>
+</d:propfind>"#;
+
+/// Whether the path is a `webdav://` or `webdavs://` folder on a WebDAV server, or a directory
+/// index served over `http://` or `https://`, or a file in one
+pub fn is_remote(path: &Path) -> bool {
+    scheme(path).is_some()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    WebDav,
+    Index,
+}
+
+/// The kind of server and the scheme to talk to it with
+fn scheme(path: &Path) -> Option<(Kind, &'static str)> {
+    let (scheme, _) = path.to_str()?.split_once("://")?;
+    match scheme {
+        "webdav" => Some((Kind::WebDav, "http")),
+        "webdavs" => Some((Kind::WebDav, "https")),
+        "http" => Some((Kind::Index, "http")),
+        "https" => Some((Kind::Index, "https")),
+        _ => None,
+    }
+}
+
+/// The URL to request, with the path percent-encoded
+pub fn url(path: &Path) -> Option<Url> {
+    let (_, scheme) = scheme(path)?;
+    let (_, rest) = path.to_str()?.split_once("://")?;
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let mut url = Url::parse(&format!("{scheme}://{host}/")).ok()?;
+    url.path_segments_mut()
+        .ok()?
+        .pop_if_empty()
+        .extend(path.split('/').filter(|x| !x.is_empty()));
+    Some(url)
+}
+
+/// The header field with the credentials from the secrets file for the URL, if it has any, for
+/// programs that are given the URL
+pub fn http_header(url: &Url) -> Option<String> {
+    let credentials = Credentials::find(url)?;
+    Some(format!("Authorization: {}", credentials.header()))
+}
+
+/// Writes the content of the file
+pub fn download(path: &Path, writer: &mut impl io::Write) -> io::Result<()> {
+    let url = url(path).ok_or_else(|| io::Error::other("not a valid URL"))?;
+    let response = request("GET", &url).call().map_err(io::Error::other)?;
+    io::copy(&mut response.into_reader(), writer)?;
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl Credentials {
+    /// Next to the config file, kept apart so that the config can be shared
+    fn file_path() -> PathBuf {
+        let project_dirs = directories::ProjectDirs::from("", "", "abelscreensaver").unwrap();
+        project_dirs.config_dir().join("secrets.json")
+    }
+
+    /// The secrets file maps URLs, like the ones in the paths, to a username and password. It is
+    /// read once, when first needed.
+    fn secrets() -> &'static [(Url, Self)] {
+        static SECRETS: OnceLock<Vec<(Url, Credentials)>> = OnceLock::new();
+        SECRETS.get_or_init(|| {
+            let Ok(file) = std::fs::File::open(Self::file_path()) else {
+                return vec![];
+            };
+            let secrets: HashMap<String, Self> =
+                match serde_json::from_reader(io::BufReader::new(file)) {
+                    Ok(secrets) => secrets,
+                    Err(err) => {
+                        eprintln!("Ignoring the secrets file: {err}");
+                        return vec![];
+                    }
+                };
+            secrets
+                .into_iter()
+                .filter_map(|(prefix, credentials)| Some((url(Path::new(&prefix))?, credentials)))
+                .collect()
+        })
+    }
+
+    /// The credentials for the longest URL in the secrets file that the URL starts with
+    fn find(target: &Url) -> Option<&'static Self> {
+        Self::secrets()
+            .iter()
+            // By whole path segments, so that `/photos` doesn't match `/photos-private`
+            .filter(|(prefix, _)| {
+                prefix.origin() == target.origin()
+                    && Path::new(target.path()).starts_with(prefix.path())
+            })
+            .max_by_key(|(prefix, _)| prefix.path().len())
+            .map(|(_, credentials)| credentials)
+    }
+
+    fn header(&self) -> String {
+        let encoded = base64::engine::general_purpose::STANDARD
+            .encode(format!("{}:{}", self.username, self.password));
+        format!("Basic {encoded}")
+    }
+}
+
+fn request(method: &str, url: &Url) -> ureq::Request {
+    let request = catalog::agent().request_url(method, url);
+    match Credentials::find(url) {
+        Some(credentials) => request.set("Authorization", &credentials.header()),
+        None => request,
+    }
+}
+
+/// A folder on a WebDAV server or behind a directory index, listed recursively
+pub struct Folder {
+    root: PathBuf,
+}
+
+impl Folder {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+}
+
+impl Catalog for Folder {
+    /// Lists the folders one at a time, as servers often refuse to list everything at once
+    fn list(&self) -> Result<Vec<Listed>, String> {
+        list(&self.root)
+    }
+}
+
+fn list(root: &Path) -> Result<Vec<Listed>, String> {
+    let (kind, _) = scheme(root).ok_or("not a valid URL")?;
+    let mut root_url = url(root).ok_or("not a valid URL")?;
+    // Relative links in a directory are relative to its URL with a trailing slash
+    root_url
+        .path_segments_mut()
+        .unwrap()
+        .pop_if_empty()
+        .push("");
+    let mut files = vec![];
+    let mut dirs = VecDeque::from([root_url.clone()]);
+    let mut seen = HashSet::from([root_url.clone()]);
+    while let Some(dir) = dirs.pop_front() {
+        let entries = match kind {
+            Kind::WebDav => propfind(&dir)?,
+            Kind::Index => index(&dir)?,
+        };
+        for entry in entries {
+            // Only what is below the folder, and not the folder itself
+            let Some(relative) = entry.url.path().strip_prefix(root_url.path()) else {
+                continue;
+            };
+            if relative.is_empty() {
+                continue;
+            }
+            if entry.is_dir {
+                let mut url = entry.url;
+                url.path_segments_mut().unwrap().pop_if_empty().push("");
+                if seen.insert(url.clone()) {
+                    dirs.push_back(url);
+                }
+                continue;
+            }
+            let relative: PathBuf = relative
+                .split('/')
+                .map(|x| String::from_utf8_lossy(&percent_decode(x.as_bytes())).into_owned())
+                .collect();
+            files.push(Listed {
+                path: root.join(relative),
+                len: entry.len,
+                modified: entry.modified,
+            });
+        }
+    }
+    Ok(files)
+}
+
+struct Entry {
+    url: Url,
+    is_dir: bool,
+    len: u64,
+    modified: SystemTime,
+}
+
+fn propfind(dir: &Url) -> Result<Vec<Entry>, String> {
+    let body = request("PROPFIND", dir)
+        .set("Depth", "1")
+        .set("Content-Type", "application/xml; charset=utf-8")
+        .send_string(PROPFIND_BODY)
+        .map_err(|err| err.to_string())?
+        .into_string()
+        .map_err(|err| err.to_string())?;
+    let mut reader = quick_xml::Reader::from_str(&body);
+    let mut entries = vec![];
+    // Local names of the open elements, ignoring the namespace prefix which servers choose
+    let mut open: Vec<Vec<u8>> = vec![];
+    let mut entry: Option<Entry> = None;
+    loop {
+        match reader.read_event().map_err(|err| err.to_string())? {
+            Event::Start(element) => {
+                let name = element.local_name().as_ref().to_vec();
+                if name == b"response" {
+                    entry = Some(Entry {
+                        url: dir.clone(),
+                        is_dir: false,
+                        len: 0,
+                        modified: SystemTime::UNIX_EPOCH,
+                    });
+                }
+                if name == b"collection" {
+                    entry.iter_mut().for_each(|x| x.is_dir = true);
+                }
+                open.push(name);
+            }
+            Event::Empty(element) if element.local_name().as_ref() == b"collection" => {
+                entry.iter_mut().for_each(|x| x.is_dir = true);
+            }
+            Event::Text(text) => {
+                let (Some(entry), Some(name)) = (&mut entry, open.last()) else {
+                    continue;
+                };
+                let text = text.unescape().map_err(|err| err.to_string())?;
+                let text = text.trim();
+                match name.as_slice() {
+                    b"href" => {
+                        // Usually only the path, but a full URL is valid too
+                        entry.url = dir.join(text).map_err(|err| err.to_string())?;
+                    }
+                    b"getcontentlength" => entry.len = text.parse().unwrap_or_default(),
+                    b"getlastmodified" => {
+                        entry.modified = parse_http_date(text).unwrap_or(SystemTime::UNIX_EPOCH);
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(element) => {
+                if element.local_name().as_ref() == b"response" {
+                    entries.extend(entry.take());
+                }
+                open.pop();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+/// The links in an HTML directory index, such as the ones generated by Apache, nginx or
+/// `python -m http.server`. Links ending with a slash are directories. Sizes and dates aren't
+/// listed in a standard way, so files are probed again only if their name changes.
+fn index(dir: &Url) -> Result<Vec<Entry>, String> {
+    let body = request("GET", dir)
+        .call()
+        .map_err(|err| err.to_string())?
+        .into_string()
+        .map_err(|err| err.to_string())?;
+    let mut entries = vec![];
+    // Lowercasing ASCII keeps every character where it is, so the offsets hold for both
+    let lowercase = body.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(i) = lowercase[offset..].find("href=") {
+        offset += i + 5;
+        let rest = &body[offset..];
+        let Some(quote) = rest.chars().next().filter(|x| *x == '"' || *x == '\'') else {
+            continue;
+        };
+        let Some(end) = rest[1..].find(quote) else {
+            break;
+        };
+        let href = rest[1..end + 1].replace("&amp;", "&");
+        offset += end + 1;
+        // Links to sort the listing and the like
+        let Ok(url) = dir.join(&href) else {
+            continue;
+        };
+        if url.query().is_some() || url.fragment().is_some() {
+            continue;
+        }
+        entries.push(Entry {
+            is_dir: url.path().ends_with('/'),
+            url,
+            len: 0,
+            modified: SystemTime::UNIX_EPOCH,
+        });
+    }
+    Ok(entries)
+}
+
+/// Parses dates like `Tue, 15 Nov 1994 12:45:26 GMT`
+fn parse_http_date(str: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut parts = str.split_whitespace().skip(1);
+    let day = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|x| *x == month)? as u32 + 1;
+    let year = parts.next()?;
+    let mut time = parts.next()?.split(':').map(|x| x.parse().ok());
+    let date = CaptureDate {
+        year: year.parse().ok()?,
+        month,
+        day,
+        hour: time.next()??,
+        minute: time.next()??,
+        second: time.next()??,
+    };
+    let secs = u64::try_from(date.timestamp()).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::TestServer;
+
+    fn listed(root: &str) -> Vec<(String, u64, SystemTime)> {
+        let mut files: Vec<_> = list(Path::new(root))
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.path.display().to_string(), x.len, x.modified))
+            .collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        files
+    }
+
+    #[test]
+    fn lists_a_directory_index() {
+        let server = TestServer::new(|request| match request.target.as_str() {
+            "/photos/" => (
+                200,
+                r##"<html><body><h1>Index of /photos</h1>
+                <a href="?C=M;O=A">Last modified</a>
+                <a href="../">Parent Directory</a>
+                <A HREF="2021/">2021/</A>
+                <a href='beach%20day.jpg'>beach day.jpg</a>
+                <a href="/photos/clip.mp4">clip.mp4</a>
+                <a href="#top">Top</a>
+                <a href=unquoted.jpg>unquoted.jpg</a>
+                </body></html>"##
+                    .to_string(),
+            ),
+            "/photos/2021/" => (200, r#"<a href="Caf%C3%A9.png">Café.png</a>"#.to_string()),
+            _ => (404, String::new()),
+        });
+        let root = format!("http://{}/photos", server.host());
+        let files: Vec<String> = listed(&root).into_iter().map(|x| x.0).collect();
+        assert_eq!(
+            files,
+            [
+                format!("{root}/2021/Café.png"),
+                format!("{root}/beach day.jpg"),
+                format!("{root}/clip.mp4"),
+            ]
+        );
+        let requests = server.requests();
+        assert!(requests.iter().all(|x| x.method == "GET"));
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[test]
+    fn lists_a_webdav_folder() {
+        let response = |hrefs: &[(&str, Option<u64>)]| {
+            let responses: String = hrefs
+                .iter()
+                .map(|(href, len)| {
+                    let prop = match len {
+                        Some(len) => format!(
+                            "<D:resourcetype/><D:getcontentlength>{len}</D:getcontentlength>\
+                             <D:getlastmodified>Tue, 15 Nov 1994 12:45:26 GMT</D:getlastmodified>"
+                        ),
+                        None => "<D:resourcetype><D:collection/></D:resourcetype>".to_string(),
+                    };
+                    format!(
+                        "<D:response><D:href>{href}</D:href>\
+                         <D:propstat><D:prop>{prop}</D:prop></D:propstat></D:response>"
+                    )
+                })
+                .collect();
+            format!(
+                r#"<?xml version="1.0" encoding="utf-8"?>
+                <D:multistatus xmlns:D="DAV:">{responses}</D:multistatus>"#
+            )
+        };
+        let server = TestServer::new(move |request| {
+            if request.method != "PROPFIND" || request.header("depth") != Some("1") {
+                return (400, String::new());
+            }
+            match request.target.as_str() {
+                "/dav/" => (
+                    207,
+                    response(&[
+                        ("/dav/", None),
+                        ("/dav/a%20b.jpg", Some(3)),
+                        ("/dav/sub/", None),
+                    ]),
+                ),
+                "/dav/sub/" => (207, response(&[("/dav/sub/", None), ("c.mp4", Some(5))])),
+                _ => (404, String::new()),
+            }
+        });
+        let files = listed(&format!("webdav://{}/dav", server.host()));
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(784903526);
+        let root = format!("webdav://{}/dav", server.host());
+        assert_eq!(
+            files,
+            [
+                (format!("{root}/a b.jpg"), 3, modified),
+                (format!("{root}/sub/c.mp4"), 5, modified),
+            ]
+        );
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|x| x.body == PROPFIND_BODY));
+    }
+}
@@ -1,6 +1,12 @@
+use crate::capture_date::CaptureDate;
+use crate::diagnostics::Diagnostics;
+use crate::history::History;
+use crate::media_index::MediaIndex;
 use crate::media_iterator::media_iterator;
 use crate::mpvclient::MpvClient;
 use crate::overlay::Overlay;
+use crate::playlist;
+use crate::settings::{self, Order};
 use crate::Options;
 use egui_glow::egui_winit::winit;
 use egui_glow::{glow, EventResponse};
@@ -12,11 +18,13 @@ use glutin::display::GetGlDisplay;
 use glutin::prelude::*;
 use glutin::surface::{Surface, SwapInterval, WindowSurface};
 use glutin_winit::GlWindow;
-use libmpv::events::{Event as MPVEvent, PropertyData};
+use libmpv::events::Event as MPVEvent;
 use libmpv::render::{OpenGLInitParams, RenderContext, RenderParam, RenderParamApiType};
 use libmpv2 as libmpv;
 use std::ffi::CString;
+use std::io::Write;
 use std::num::NonZeroU32;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::Arc;
@@ -33,7 +41,41 @@ type GLContext = Rc<glutin::display::Display>;
 pub enum UserEvent {
     RequestRedraw,
     MPVEvents,
-    Reset(Options),
+    // Boxed as the options are much larger than the other events
+    Reset(Box<Options>),
+    RebuildIndex,
+    ExportPlayed,
+    // A signal asked the screensaver to end
+    Exit,
+}
+
+/// The signals that end the screensaver, like the SIGTERM from xscreensaver. They are blocked in
+/// every thread and waited for in one, so the index and history get saved before exiting.
+pub struct ExitSignals(libc::sigset_t);
+
+impl ExitSignals {
+    /// Must be called before any thread is spawned, as threads inherit the blocked signals
+    pub fn block() -> Self {
+        unsafe {
+            let mut signals: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut signals);
+            for signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+                libc::sigaddset(&mut signals, signal);
+            }
+            libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+            Self(signals)
+        }
+    }
+
+    fn forward(self, event_proxy: EventLoopProxy<UserEvent>) {
+        std::thread::spawn(move || {
+            let mut signal = 0;
+            unsafe { libc::sigwait(&self.0, &mut signal) };
+            if event_proxy.send_event(UserEvent::Exit).is_err() {
+                std::process::exit(128 + signal);
+            }
+        });
+    }
 }
 
 fn setup_mpv(
@@ -62,9 +104,6 @@ fn setup_mpv(
     )
     .expect("Failed creating render context");
 
-    mpv.event_context()
-        .observe_property("path", libmpv::Format::String, 0)
-        .unwrap();
     let event_proxy0 = event_proxy.clone();
     mpv.event_context_mut().set_wakeup_callback(move || {
         event_proxy0.send_event(UserEvent::MPVEvents).unwrap();
@@ -78,11 +117,20 @@ fn setup_mpv(
     (mpv, render_context)
 }
 
-pub fn run(opts: Options, black_pixel_path: PathBuf) {
+pub fn run(
+    config: settings::Config,
+    index: MediaIndex,
+    history: History,
+    notices: Vec<String>,
+    windowed: bool,
+    black_pixel_path: PathBuf,
+    exit_signals: ExitSignals,
+) {
     let event_loop = EventLoop::with_user_event().build().unwrap();
+    exit_signals.forward(event_loop.create_proxy());
     let (window, gl_config) = {
         let window_attributes = Window::default_attributes()
-            .with_fullscreen(Some(Fullscreen::Borderless(None)))
+            .with_fullscreen((!windowed).then_some(Fullscreen::Borderless(None)))
             .with_title("abelscreensaver");
         let display_builder =
             glutin_winit::DisplayBuilder::new().with_window_attributes(Some(window_attributes));
@@ -94,7 +142,10 @@ pub fn run(opts: Options, black_pixel_path: PathBuf) {
     };
 
     let mut runner = Runner::new(
-        opts,
+        config,
+        index,
+        history,
+        notices,
         window,
         gl_config,
         event_loop.create_proxy(),
@@ -115,8 +166,10 @@ struct ActiveRunner {
 }
 
 impl ActiveRunner {
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        opts: Options,
+        config: settings::Config,
+        diagnostics: Diagnostics,
         gl_config: &Config,
         event_loop: &ActiveEventLoop,
         event_proxy: &EventLoopProxy<UserEvent>,
@@ -178,8 +231,9 @@ impl ActiveRunner {
             egui_glow
         };
         let (mpv_client, render_context, has_media) = {
-            let (mpv, render_context) = setup_mpv(event_proxy, Rc::new(gl_display), &opts);
-            let mpv_client = MpvClient::new(mpv);
+            let (mpv, render_context) =
+                setup_mpv(event_proxy, Rc::new(gl_display), config.options());
+            let mut mpv_client = MpvClient::new(mpv);
             let has_media = if let Some(first_path) = it.next() {
                 mpv_client.playlist_append_play(&first_path);
                 true
@@ -190,7 +244,7 @@ impl ActiveRunner {
             };
             (mpv_client, render_context, has_media)
         };
-        let overlay = Overlay::new(size, opts);
+        let overlay = Overlay::new(size, config, diagnostics);
         Self {
             size,
             egui_glow,
@@ -205,7 +259,17 @@ impl ActiveRunner {
 }
 
 struct Runner {
+    config: settings::Config,
     opts: Options,
+    // See `Options::source_roots`
+    source_roots: Vec<(PathBuf, bool)>,
+    index: MediaIndex,
+    history: History,
+    diagnostics: Diagnostics,
+    // Every file shown so far, in order
+    played: Vec<PathBuf>,
+    // Shown by the overlay once it exists
+    notices: Vec<String>,
     window: Window,
     gl_config: Config,
     it: Box<dyn Iterator<Item = PathBuf>>,
@@ -215,16 +279,34 @@ struct Runner {
 }
 
 impl Runner {
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        opts: Options,
+        config: settings::Config,
+        index: MediaIndex,
+        history: History,
+        notices: Vec<String>,
         window: Window,
         gl_config: Config,
         event_proxy: EventLoopProxy<UserEvent>,
         black_pixel_path: PathBuf,
     ) -> Self {
-        let it = Box::new(media_iterator(opts.clone()));
+        let opts = config.options().clone();
+        let diagnostics = Diagnostics::default();
+        let it = Box::new(media_iterator(
+            opts.clone(),
+            index.clone(),
+            history.clone(),
+            diagnostics.clone(),
+        ));
         Self {
+            config,
+            source_roots: opts.source_roots(),
             opts,
+            index,
+            history,
+            diagnostics,
+            played: vec![],
+            notices,
             window,
             gl_config,
             it,
@@ -237,6 +319,10 @@ impl Runner {
 
 impl ApplicationHandler<UserEvent> for Runner {
     fn user_event(&mut self, event_loop: &ActiveEventLoop, event: UserEvent) {
+        if let UserEvent::Exit = event {
+            event_loop.exit();
+            return;
+        }
         let window = &self.window;
         let Some(active_runner) = self.active_runner.as_mut() else {
             return;
@@ -257,15 +343,25 @@ impl ApplicationHandler<UserEvent> for Runner {
                             }
                         }
                         overlay.has_media = *has_media;
-                    }
-                    Some(Ok(MPVEvent::PropertyChange {
-                        name: "path",
-                        change: PropertyData::Str(str),
-                        ..
-                    })) => {
-                        overlay.path = str.to_string();
-                        if *has_media {
-                            println!("{str}");
+                        let path = mpv_client.current_path().filter(|_| *has_media);
+                        overlay.years_ago = None;
+                        if let Some(path) = path {
+                            let is_muted = settings::is_source_muted(&self.source_roots, &path);
+                            mpv_client.set_mute(overlay.is_muted() || is_muted);
+                            let info = self.index.probe_shown(&path).unwrap_or_default();
+                            overlay.years_ago = (self.opts.order == Order::OnThisDay)
+                                .then(|| info.capture_date()?.years_ago(&CaptureDate::today()))
+                                .flatten();
+                            // Printed as is, the path might not be UTF-8
+                            let mut stdout = std::io::stdout().lock();
+                            stdout.write_all(path.as_os_str().as_bytes()).ok();
+                            stdout.write_all(b"\n").ok();
+                            self.history.mark_shown(&path);
+                            self.played.push(path.clone());
+                            overlay.rating = info.rating;
+                            overlay.tags = info.tags;
+                            overlay.title = self.index.title(&path);
+                            overlay.path = path;
                         }
                     }
                     Some(Ok(_)) => {}
@@ -281,7 +377,12 @@ impl ApplicationHandler<UserEvent> for Runner {
                 }
             },
             UserEvent::Reset(opts) => {
-                self.it = Box::new(media_iterator(opts.clone()));
+                self.it = Box::new(media_iterator(
+                    (*opts).clone(),
+                    self.index.clone(),
+                    self.history.clone(),
+                    self.diagnostics.clone(),
+                ));
                 *has_media = if let Some(first_path) = self.it.next() {
                     mpv_client.playlist_replace(&first_path);
                     mpv_client.playlist_clear();
@@ -296,20 +397,50 @@ impl ApplicationHandler<UserEvent> for Runner {
                     }
                     false
                 };
+                self.source_roots = opts.source_roots();
+                self.opts = *opts;
             }
+            UserEvent::RebuildIndex => {
+                self.index.clear();
+                self.event_proxy
+                    .send_event(UserEvent::Reset(Box::new(self.opts.clone())))
+                    .unwrap();
+            }
+            UserEvent::ExportPlayed => {
+                let notice = match export_played(&self.played, &self.index) {
+                    Ok((path, 0)) => format!("Saved the played files to {}", path.display()),
+                    Ok((path, skipped)) => format!(
+                        "Saved the played files to {}, without {skipped} in archives or on servers",
+                        path.display()
+                    ),
+                    Err(err) => format!("Failed to export the played files: {err}"),
+                };
+                overlay.notify(notice);
+            }
+            UserEvent::Exit => {}
         }
     }
 
+    fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
+        self.index.save();
+        self.history.save();
+    }
+
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        self.active_runner = Some(ActiveRunner::new(
-            self.opts.clone(),
+        let mut active_runner = ActiveRunner::new(
+            self.config.clone(),
+            self.diagnostics.clone(),
             &self.gl_config,
             event_loop,
             &self.event_proxy,
             &self.window,
             &mut self.it,
             &self.black_pixel_path,
-        ));
+        );
+        for notice in self.notices.drain(..) {
+            active_runner.overlay.notify(notice);
+        }
+        self.active_runner = Some(active_runner);
     }
 
     fn device_event(
@@ -405,3 +536,25 @@ pub fn gl_config_picker(configs: Box<dyn Iterator<Item = Config> + '_>) -> Confi
         })
         .unwrap()
 }
+
+/// Writes the played files as an M3U playlist named after the current time to the cache dir.
+/// Returns its path and how many files were left out.
+fn export_played(played: &[PathBuf], index: &MediaIndex) -> std::io::Result<(PathBuf, usize)> {
+    let project_dirs = directories::ProjectDirs::from("", "", "abelscreensaver").unwrap();
+    let now = CaptureDate::today();
+    let file_name = format!(
+        "played-{}-{:02}-{:02}-{:02}{:02}{:02}.m3u",
+        now.year, now.month, now.day, now.hour, now.minute, now.second
+    );
+    let file_path = project_dirs.cache_dir().join(file_name);
+    let entries: Vec<_> = played
+        .iter()
+        .map(|path| playlist::Entry {
+            path: path.clone(),
+            title: index.title(path),
+        })
+        .collect();
+    std::fs::create_dir_all(file_path.parent().unwrap())?;
+    let skipped = playlist::write_m3u(&file_path, &entries)?;
+    Ok((file_path, skipped))
+}
@@ -1,10 +1,219 @@
+use crate::library;
+use crate::media_filter::MediaFilter;
+use crate::photo_server;
+use crate::walk_filter;
 use egui::{pos2, vec2, Vec2};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Source {
+    // Where to search for media
+    #[serde(with = "path_serde")]
+    pub path: PathBuf,
+
+    // The API key for a source on a photo server, see `photo_server`
+    pub api_key: String,
+
+    // What to play from a digiKam or Shotwell library
+    pub library: library::Selection,
+
+    // How often this source is picked relative to the others when randomized
+    pub weight: f64,
+
+    // How many directories deep to search, unlimited if None
+    pub max_depth: Option<usize>,
+
+    // Include videos from this source
+    pub video: bool,
+
+    // Mute videos from this source
+    pub mute: bool,
+
+    // Search this source at all
+    pub enabled: bool,
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::new(),
+            api_key: String::new(),
+            library: library::Selection::default(),
+            weight: 1.0,
+            max_depth: None,
+            video: true,
+            mute: false,
+            enabled: true,
+        }
+    }
+}
+
+impl Source {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            ..Default::default()
+        }
+    }
+
+    /// The path with a leading `~/` expanded to the home directory
+    pub fn expanded_path(&self) -> PathBuf {
+        match self.path.strip_prefix("~/") {
+            Ok(rest) => directories::UserDirs::new().unwrap().home_dir().join(rest),
+            Err(_) => self.path.clone(),
+        }
+    }
+
+    /// The expanded path without symlinks, so that the same directory always has the same path
+    pub fn resolved_path(&self) -> PathBuf {
+        let path = self.expanded_path();
+        std::fs::canonicalize(&path).unwrap_or(path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Order {
+    // Walk the paths in order
+    Sequential,
+    // Random order, preferring files not shown in the current cycle unless seeded
+    Shuffle,
+    // Files not shown for the longest time first, with a boost for newly added ones
+    LeastRecentlyShown,
+    // Files taken on this day in earlier years first, then the rest in random order
+    OnThisDay,
+}
+
+impl Order {
+    pub const ALL: [Self; 4] = [
+        Self::Sequential,
+        Self::Shuffle,
+        Self::LeastRecentlyShown,
+        Self::OnThisDay,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sequential => "sequential",
+            Self::Shuffle => "shuffle",
+            Self::LeastRecentlyShown => "least-recently-shown",
+            Self::OnThisDay => "on-this-day",
+        }
+    }
+
+    pub fn is_random(self) -> bool {
+        self != Self::Sequential
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Sequential => "In order",
+            Self::Shuffle => "Shuffle",
+            Self::LeastRecentlyShown => "Least recently shown",
+            Self::OnThisDay => "On this day",
+        }
+    }
+}
+
+impl std::str::FromStr for Order {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|order| order.name() == str)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|x| x.name()).collect();
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+/// What sequential playback is sorted by
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortBy {
+    // Path, character by character
+    Name,
+    // Path, with numbers compared by value so that 2 comes before 10
+    NaturalName,
+    // Modification time
+    Modified,
+    // When it was taken according to its metadata, or else its modification time
+    CaptureDate,
+    // File size
+    Size,
+}
+
+impl SortBy {
+    pub const ALL: [Self; 5] = [
+        Self::Name,
+        Self::NaturalName,
+        Self::Modified,
+        Self::CaptureDate,
+        Self::Size,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::NaturalName => "natural-name",
+            Self::Modified => "modified",
+            Self::CaptureDate => "capture-date",
+            Self::Size => "size",
+        }
+    }
+
+    /// Whether the order follows the directory tree, so files can be played while walking
+    pub fn is_by_name(self) -> bool {
+        matches!(self, Self::Name | Self::NaturalName)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Name => "Name",
+            Self::NaturalName => "Natural name",
+            Self::Modified => "Modified",
+            Self::CaptureDate => "Capture date",
+            Self::Size => "Size",
+        }
+    }
+}
+
+impl std::str::FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|sort_by| sort_by.name() == str)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|x| x.name()).collect();
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct Options {
-    // Randomize playback
-    pub random: bool,
+    // Layout of the config file, see `Options::migrate`
+    pub version: u32,
+
+    // How to order playback
+    pub order: Order,
+
+    // What sequential playback is sorted by, across all paths. Sorting by anything but the name
+    // needs every file looked at before the first one is shown
+    pub sort_by: SortBy,
+
+    // Sort sequential playback in descending order
+    pub reverse_sort: bool,
+
+    // How many days of not being shown a newly added file is worth, fading out over as many
+    // days after it was added. Only used by `Order::LeastRecentlyShown`
+    pub new_file_boost_days: f64,
 
     // Include hidden entries
     pub hidden: bool,
@@ -18,8 +227,54 @@ pub struct Options {
     // How long to show each image
     pub period_secs: f64,
 
-    // The paths to search for media
-    pub paths: Vec<PathBuf>,
+    // The sources to search for media
+    pub paths: Vec<Source>,
+
+    // Pick up media added to or removed from the paths while running
+    pub watch: bool,
+
+    // Walk into linked directories and play linked files
+    pub follow_symlinks: bool,
+
+    // Only include files matching one of these globs, unless empty
+    pub include: Vec<String>,
+
+    // Skip files and directories matching any of these globs
+    pub exclude: Vec<String>,
+
+    // Only play files whose metadata matches this expression, see `MediaFilter`
+    pub filter: String,
+
+    // Only play files rated at least this many stars, in their XMP or EXIF data or sidecar
+    pub min_rating: u8,
+
+    // Only play files with all of these tags or tags below them, like `people` for `People/Alice`
+    pub required_tags: Vec<String>,
+
+    // Skip files with any of these tags or tags below them
+    pub excluded_tags: Vec<String>,
+
+    // Skip files with the same content as one found before, slow the first time
+    pub dedupe_content: bool,
+
+    // Skip images that look the same as one found before, very slow the first time
+    pub dedupe_similar: bool,
+
+    // Give up on files that take longer to probe, such as corrupt ones that make ffprobe hang
+    pub probe_timeout_secs: f64,
+
+    // Probe with the ffprobe binary instead of in-process, if built with it
+    pub use_ffprobe: bool,
+
+    // List sources on servers again once their cached listing is older than this
+    pub listing_cache_mins: f64,
+
+    // Show the previews photo servers make instead of the originals, which load faster
+    pub server_previews: bool,
+
+    // Seed for the random order, which is then the same for the same files.
+    // A different order every run if None
+    pub seed: Option<u64>,
 }
 
 impl Default for Options {
@@ -30,39 +285,229 @@ impl Default for Options {
             .map(|path| path.to_path_buf())
             .or_else(|| std::env::current_dir().ok())
             .into_iter()
+            .map(Source::new)
             .collect();
         Self {
-            random: true,
+            version: Self::VERSION,
+            order: Order::Shuffle,
+            sort_by: SortBy::NaturalName,
+            reverse_sort: false,
+            new_file_boost_days: 30.0,
             hidden: false,
             video: true,
             mute: false,
             period_secs: 4.0,
             paths,
+            watch: true,
+            follow_symlinks: false,
+            include: vec![],
+            exclude: vec![],
+            filter: String::new(),
+            min_rating: 0,
+            required_tags: vec![],
+            excluded_tags: vec![],
+            dedupe_content: false,
+            dedupe_similar: false,
+            probe_timeout_secs: 10.0,
+            use_ffprobe: false,
+            listing_cache_mins: 60.0,
+            server_previews: false,
+            seed: None,
         }
     }
 }
 
-impl Options {
-    pub fn load() -> Self {
+/// The file the options are persisted in.
+///
+/// Options overridden for this run only, such as from the command line, are not written back.
+#[derive(Clone)]
+pub struct Config {
+    path: PathBuf,
+    read_only: bool,
+    // What is in the file
+    saved: Options,
+    // What was in use after loading or the last save, including overrides
+    applied: Options,
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
         let project_dirs = directories::ProjectDirs::from("", "", "abelscreensaver").unwrap();
-        let config_dir = project_dirs.config_dir();
-        std::fs::create_dir_all(config_dir).unwrap();
-        let file = std::fs::File::open(config_dir.join("config.json"));
-        if let Ok(file) = file {
-            serde_json::from_reader(file).unwrap()
-        } else {
-            let result = Self::default();
-            result.save();
-            result
+        project_dirs.config_dir().join("config.json")
+    }
+
+    /// Loads the config, falling back to defaults for anything missing or invalid.
+    ///
+    /// Also returns a message for the user if the file had to be repaired. A file that can't be
+    /// read is only replaced once backed up, and one written by a newer version is never written.
+    pub fn load(path: PathBuf, read_only: bool) -> (Self, Option<String>) {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => Ok(contents),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let result = Self::new(path, read_only, Options::default());
+                result.write();
+                return (result, None);
+            }
+            Err(err) => Err(err.to_string()),
+        };
+        let value = contents.and_then(|contents| {
+            serde_json::from_str::<serde_json::Value>(&contents).map_err(|err| err.to_string())
+        });
+        let (opts, problem, outdated, is_newer) = match value {
+            Ok(mut value) => {
+                let version = value.get("version").and_then(|x| x.as_u64()).unwrap_or(0) as u32;
+                Options::migrate(&mut value, version);
+                let (opts, invalid_fields) = Options::from_value(value);
+                let is_newer = version > Options::VERSION;
+                let problem = if !invalid_fields.is_empty() {
+                    Some(format!("invalid {}", invalid_fields.join(", ")))
+                } else if is_newer {
+                    Some("written by a newer version, so changes won't be saved".to_string())
+                } else {
+                    None
+                };
+                (opts, problem, version < Options::VERSION, is_newer)
+            }
+            Err(err) => (Options::default(), Some(err), false, false),
+        };
+        // Saving would drop what this version doesn't know about
+        let result = Self::new(path, read_only || is_newer, opts);
+        if (problem.is_some() || outdated) && !result.read_only {
+            let backup_path = backup(&result.path);
+            if backup_path.is_some() {
+                result.write();
+            }
+            let message = problem.map(|problem| match &backup_path {
+                Some(backup_path) => format!(
+                    "{} ({problem}), using defaults where needed. The original was backed up to {}",
+                    result.path.display(),
+                    backup_path.display()
+                ),
+                None => format!(
+                    "{} ({problem}), using defaults where needed. It couldn't be backed up, so it \
+                     was left as is",
+                    result.path.display()
+                ),
+            });
+            return (result, message);
         }
+        let message = problem.map(|problem| {
+            format!(
+                "{} ({problem}), using defaults where needed",
+                result.path.display()
+            )
+        });
+        (result, message)
     }
 
-    pub fn save(&self) {
-        let serialized = serde_json::to_string_pretty(self).unwrap();
-        let project_dirs = directories::ProjectDirs::from("", "", "abelscreensaver").unwrap();
-        let config_dir = project_dirs.config_dir();
-        std::fs::create_dir_all(config_dir).unwrap();
-        std::fs::write(config_dir.join("config.json"), serialized).unwrap();
+    fn new(path: PathBuf, read_only: bool, opts: Options) -> Self {
+        Self {
+            path,
+            read_only,
+            saved: opts.clone(),
+            applied: opts,
+        }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.applied
+    }
+
+    /// Changes the options for this run without saving them
+    pub fn apply_overrides(&mut self, apply: impl FnOnce(&mut Options)) {
+        apply(&mut self.applied);
+    }
+
+    /// Saves the fields that differ from what was applied before, keeping the saved value of
+    /// unchanged fields that were overridden
+    pub fn save(&mut self, opts: &Options) {
+        let applied = serde_json::to_value(&self.applied).unwrap();
+        let mut saved = serde_json::to_value(&self.saved).unwrap();
+        if let serde_json::Value::Object(fields) = serde_json::to_value(opts).unwrap() {
+            for (key, value) in fields {
+                if applied.get(&key) != Some(&value) {
+                    saved[&key] = value;
+                }
+            }
+        }
+        self.saved = serde_json::from_value(saved).unwrap();
+        self.applied = opts.clone();
+        self.write();
+    }
+
+    fn write(&self) {
+        if self.read_only {
+            return;
+        }
+        let serialized = serde_json::to_string_pretty(&self.saved).unwrap();
+        let result = std::fs::create_dir_all(self.path.parent().unwrap())
+            .and_then(|()| std::fs::write(&self.path, serialized));
+        if let Err(err) = result {
+            eprintln!("Failed to save {}: {err}", self.path.display());
+        }
+    }
+}
+
+impl Options {
+    const VERSION: u32 = 2;
+
+    /// Upgrades an older config layout to the current one
+    fn migrate(value: &mut serde_json::Value, version: u32) {
+        if version < 1 {
+            // Paths used to be plain strings instead of sources
+            if let Some(paths) = value.get_mut("paths").and_then(|x| x.as_array_mut()) {
+                for path in paths {
+                    if path.is_string() {
+                        *path = serde_json::json!({ "path": path.take() });
+                    }
+                }
+            }
+        }
+        if version < 2 {
+            // Random used to be the only alternative to sequential
+            if let Some(random) = value.as_object_mut().and_then(|x| x.remove("random")) {
+                let order = match random.as_bool() {
+                    Some(false) => Order::Sequential,
+                    _ => Order::Shuffle,
+                };
+                value["order"] = serde_json::to_value(order).unwrap();
+            }
+        }
+        if version < Self::VERSION {
+            value["version"] = Self::VERSION.into();
+        }
+    }
+
+    /// Keeps every field that deserializes on its own, and returns the names of the rest
+    fn from_value(value: serde_json::Value) -> (Self, Vec<String>) {
+        if let Ok(result) = serde_json::from_value(value.clone()) {
+            return (result, vec![]);
+        }
+        let serde_json::Value::Object(fields) = value else {
+            return (Self::default(), vec!["root".to_string()]);
+        };
+        let mut merged = serde_json::to_value(Self::default()).unwrap();
+        let mut invalid_fields = vec![];
+        for (key, field) in fields {
+            let mut candidate = merged.clone();
+            candidate[&key] = field;
+            if serde_json::from_value::<Self>(candidate.clone()).is_ok() {
+                merged = candidate;
+            } else {
+                invalid_fields.push(key);
+            }
+        }
+        (serde_json::from_value(merged).unwrap(), invalid_fields)
+    }
+
+    /// The resolved paths of the enabled sources and whether they mute their videos, looked up by
+    /// [`is_source_muted`]. Resolving touches the disk, so it is done once the options change.
+    pub fn source_roots(&self) -> Vec<(PathBuf, bool)> {
+        self.paths
+            .iter()
+            .filter(|source| source.enabled)
+            .map(|source| (source.resolved_path(), source.mute))
+            .collect()
     }
 
     pub fn ui(&mut self, ui: &mut egui::Ui) {
@@ -74,72 +519,379 @@ impl Options {
                     .num_columns(2)
                     .spacing(vec2(16.0, 10.0))
                     .show(ui, |ui| {
-                        ui.checkbox(&mut self.random, "Randomize");
+                        order_ui(ui, &mut self.order);
                         ui.checkbox(&mut self.hidden, "Include hidden files");
                         ui.end_row();
+                        if self.order == Order::Sequential {
+                            sort_by_ui(ui, &mut self.sort_by);
+                            ui.checkbox(&mut self.reverse_sort, "Reverse");
+                            ui.end_row();
+                        }
                         ui.checkbox(&mut self.video, "Include video");
                         ui.add_enabled(
                             self.video,
                             egui::Checkbox::new(&mut self.mute, "Mute video"),
                         );
                         ui.end_row();
+                        ui.checkbox(&mut self.watch, "Watch for changes");
+                        ui.add_enabled_ui(self.order.is_random(), |ui| seed_ui(ui, &mut self.seed));
+                        ui.end_row();
+                        ui.checkbox(&mut self.dedupe_content, "Skip identical copies")
+                            .on_hover_text("Compare the content of files with the same size");
+                        ui.checkbox(&mut self.dedupe_similar, "Skip similar images")
+                            .on_hover_text("Compare downscaled images");
+                        ui.end_row();
+                        ui.add(
+                            egui::DragValue::new(&mut self.min_rating)
+                                .range(0..=5)
+                                .prefix("Stars ≥ "),
+                        )
+                        .on_hover_text("Skip files rated lower, or not at all");
+                        ui.end_row();
+                        ui.checkbox(&mut self.follow_symlinks, "Follow symlinks");
+                        ui.add(
+                            egui::DragValue::new(&mut self.probe_timeout_secs)
+                                .range(0.5..=600.0)
+                                .speed(0.5)
+                                .prefix("Probe timeout ")
+                                .suffix(" s"),
+                        )
+                        .on_hover_text("Skip files that take longer to look at");
+                        ui.end_row();
+                        ui.add(
+                            egui::DragValue::new(&mut self.listing_cache_mins)
+                                .range(0.0..=10080.0)
+                                .prefix("List servers every ")
+                                .suffix(" min"),
+                        )
+                        .on_hover_text("Reuse the listing of sources on servers until then");
+                        ui.checkbox(&mut self.server_previews, "Server previews")
+                            .on_hover_text("Show the previews photo servers make, not originals");
+                        ui.end_row();
+                        #[cfg(feature = "ffprobe")]
+                        {
+                            ui.checkbox(&mut self.use_ffprobe, "Probe with ffprobe")
+                                .on_hover_text("Slower, but knows more formats");
+                            ui.end_row();
+                        }
+                        if self.order == Order::LeastRecentlyShown {
+                            ui.add(
+                                egui::DragValue::new(&mut self.new_file_boost_days)
+                                    .range(0.0..=3650.0)
+                                    .prefix("New file boost ")
+                                    .suffix(" days"),
+                            )
+                            .on_hover_text(
+                                "How many days of not being shown a newly added file is worth",
+                            );
+                            ui.end_row();
+                        }
                     });
                 ui.add(
                     egui::Slider::new(&mut self.period_secs, 0.1..=20.0)
                         .clamp_to_range(false)
                         .text("Period"),
                 );
+                filter_ui(ui, &mut self.filter);
             });
-        let focus_last_path = ui
+        list_ui(
+            ui,
+            "Paths",
+            48.0,
+            &mut self.paths,
+            |source| source.path.as_os_str().is_empty(),
+            source_ui,
+        );
+        list_ui(
+            ui,
+            "Include",
+            12.0,
+            &mut self.include,
+            String::is_empty,
+            glob_ui,
+        );
+        list_ui(
+            ui,
+            "Exclude",
+            12.0,
+            &mut self.exclude,
+            String::is_empty,
+            glob_ui,
+        );
+        list_ui(
+            ui,
+            "Required tags",
+            12.0,
+            &mut self.required_tags,
+            String::is_empty,
+            tag_ui,
+        );
+        list_ui(
+            ui,
+            "Excluded tags",
+            12.0,
+            &mut self.excluded_tags,
+            String::is_empty,
+            tag_ui,
+        );
+    }
+}
+
+/// Whether the deepest of the source roots containing the path mutes its videos
+pub fn is_source_muted(source_roots: &[(PathBuf, bool)], path: &Path) -> bool {
+    source_roots
+        .iter()
+        .filter(|(root, _)| path.starts_with(root))
+        .max_by_key(|(root, _)| root.components().count())
+        .is_some_and(|(_, mute)| *mute)
+}
+
+/// Copies the file next to itself with a timestamp, returning the copy's path
+fn backup(path: &Path) -> Option<PathBuf> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    let mut file_name = path.file_name()?.to_os_string();
+    file_name.push(format!(".{timestamp}.bak"));
+    let backup_path = path.with_file_name(file_name);
+    std::fs::copy(path, &backup_path).ok()?;
+    Some(backup_path)
+}
+
+fn source_ui(ui: &mut egui::Ui, source: &mut Source) -> egui::Response {
+    ui.vertical(|ui| {
+        let text_edit = ui
             .horizontal(|ui| {
-                ui.heading("Paths");
-                let clicked = add_button(ui).clicked();
-                if clicked
-                    && !self
-                        .paths
-                        .last()
-                        .is_some_and(|path| path.to_str().unwrap().is_empty())
-                {
-                    self.paths
-                        .push(PathBuf::from(std::ffi::OsString::from(String::new())));
+                ui.checkbox(&mut source.enabled, "")
+                    .on_hover_text("Search this path");
+                // Paths that aren't UTF-8 are kept as they are unless edited
+                let mut str = source.path.to_string_lossy().into_owned();
+                let text_edit = ui
+                    .add(
+                        egui::TextEdit::singleline(&mut str)
+                            .desired_width(236.0)
+                            .margin(egui::vec2(13.0, 0.0)),
+                    )
+                    .on_hover_text(
+                        "A directory, playlist, archive or digiKam or Shotwell database, \
+                        a webdav:// or http:// URL, \
+                        or an immich:// or photoprism:// album, person or favorites",
+                    );
+                if text_edit.changed() {
+                    source.path = PathBuf::from(std::ffi::OsString::from(str));
                 }
-                clicked
+                text_edit
             })
             .inner;
+        if photo_server::is_photo_server(&source.path) {
+            ui.horizontal(|ui| {
+                ui.add_space(24.0);
+                ui.add(
+                    egui::TextEdit::singleline(&mut source.api_key)
+                        .desired_width(236.0)
+                        .password(true)
+                        .hint_text("API key"),
+                );
+            });
+        }
+        if library::is_library(&source.path) {
+            ui.horizontal(|ui| {
+                ui.add_space(24.0);
+                selection_ui(ui, &source.path, &mut source.library);
+            });
+        }
+        ui.add_enabled_ui(source.enabled, |ui| {
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::DragValue::new(&mut source.weight)
+                        .range(0.0..=100.0)
+                        .speed(0.05)
+                        .prefix("Weight "),
+                )
+                .on_hover_text("How often this path is picked when randomized");
+                let mut max_depth = source.max_depth.unwrap_or(0);
+                ui.add(
+                    egui::DragValue::new(&mut max_depth)
+                        .range(0..=64)
+                        .prefix("Depth ")
+                        .custom_formatter(|n, _| match n as usize {
+                            0 => "any".to_string(),
+                            n => n.to_string(),
+                        }),
+                )
+                .on_hover_text("How many directories deep to search");
+                source.max_depth = (max_depth > 0).then_some(max_depth);
+                ui.checkbox(&mut source.video, "Video");
+                ui.add_enabled(source.video, egui::Checkbox::new(&mut source.mute, "Mute"));
+            });
+        });
+        text_edit
+    })
+    .inner
+}
+
+/// Picks the album, tag and rating from what is in the library
+fn selection_ui(ui: &mut egui::Ui, path: &Path, selection: &mut library::Selection) {
+    let choices = library::choices(path).unwrap_or_default();
+    let album_id = ("album", path);
+    choice_ui(
+        ui,
+        album_id,
+        &mut selection.album,
+        "Any album",
+        &choices.albums,
+    );
+    let tag_id = ("tag", path);
+    choice_ui(ui, tag_id, &mut selection.tag, "Any tag", &choices.tags);
+    ui.add(
+        egui::DragValue::new(&mut selection.min_rating)
+            .range(0..=5)
+            .prefix("Stars ≥ "),
+    )
+    .on_hover_text("Only files rated at least this many stars");
+}
+
+/// One of the choices, or any if empty
+fn choice_ui(
+    ui: &mut egui::Ui,
+    id_salt: impl std::hash::Hash,
+    value: &mut String,
+    any: &str,
+    choices: &[String],
+) {
+    let selected = match value.is_empty() {
+        true => any.to_string(),
+        false => value.clone(),
+    };
+    egui::ComboBox::from_id_salt(id_salt)
+        .width(100.0)
+        .selected_text(selected)
+        .show_ui(ui, |ui| {
+            ui.selectable_value(value, String::new(), any);
+            for choice in choices {
+                ui.selectable_value(value, choice.clone(), choice);
+            }
+        });
+}
+
+fn order_ui(ui: &mut egui::Ui, order: &mut Order) {
+    egui::ComboBox::from_id_salt("order")
+        .selected_text(order.label())
+        .show_ui(ui, |ui| {
+            for value in Order::ALL {
+                ui.selectable_value(order, value, value.label());
+            }
+        });
+}
+
+fn sort_by_ui(ui: &mut egui::Ui, sort_by: &mut SortBy) {
+    egui::ComboBox::from_id_salt("sort_by")
+        .selected_text(sort_by.label())
+        .show_ui(ui, |ui| {
+            for value in SortBy::ALL {
+                ui.selectable_value(sort_by, value, value.label());
+            }
+        });
+}
+
+fn seed_ui(ui: &mut egui::Ui, seed: &mut Option<u64>) {
+    ui.horizontal(|ui| {
+        let mut fixed = seed.is_some();
+        ui.checkbox(&mut fixed, "Seed")
+            .on_hover_text("Shuffle in the same order every run");
+        let mut value = seed.unwrap_or(0);
+        ui.add_enabled(fixed, egui::DragValue::new(&mut value));
+        *seed = fixed.then_some(value);
+    });
+}
+
+fn filter_ui(ui: &mut egui::Ui, filter: &mut String) {
+    let error = MediaFilter::parse(filter).err();
+    ui.horizontal(|ui| {
+        ui.label("Filter");
+        let mut text_edit = egui::TextEdit::singleline(filter)
+            .desired_width(236.0)
+            .hint_text("width >= 1920 && duration < 60s");
+        if error.is_some() {
+            text_edit = text_edit.text_color(ui.visuals().error_fg_color);
+        }
+        ui.add(text_edit);
+    });
+    if let Some(error) = error {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+}
+
+fn glob_ui(ui: &mut egui::Ui, pattern: &mut String) -> egui::Response {
+    let error = walk_filter::glob_error(pattern);
+    let mut text_edit = egui::TextEdit::singleline(pattern)
+        .desired_width(260.0)
+        .margin(egui::vec2(13.0, 0.0));
+    if error.is_some() {
+        text_edit = text_edit.text_color(ui.visuals().error_fg_color);
+    }
+    let response = ui.add(text_edit);
+    match error {
+        Some(error) => response.on_hover_text(error),
+        None => response,
+    }
+}
+
+fn tag_ui(ui: &mut egui::Ui, tag: &mut String) -> egui::Response {
+    ui.add(
+        egui::TextEdit::singleline(tag)
+            .desired_width(260.0)
+            .margin(egui::vec2(13.0, 0.0))
+            .hint_text("People/Alice"),
+    )
+}
+
+/// Editable list with a heading, an add button, and a remove button per item
+fn list_ui<T: Default>(
+    ui: &mut egui::Ui,
+    heading: &str,
+    max_height: f32,
+    items: &mut Vec<T>,
+    is_empty: impl Fn(&T) -> bool,
+    mut item_ui: impl FnMut(&mut egui::Ui, &mut T) -> egui::Response,
+) {
+    let focus_last = ui
+        .horizontal(|ui| {
+            ui.heading(heading);
+            let clicked = add_button(ui).clicked();
+            if clicked && !items.last().is_some_and(&is_empty) {
+                items.push(T::default());
+            }
+            clicked
+        })
+        .inner;
+    ui.push_id(heading, |ui| {
         egui::Frame::none()
             .fill(egui::Color32::from_gray(20))
             .rounding(egui::Rounding::same(2.0))
             .show(ui, |ui| {
                 egui::ScrollArea::vertical()
-                    .max_height(12.0)
+                    .max_height(max_height)
                     .auto_shrink([false, false])
                     .show(ui, |ui| {
-                        let length = self.paths.len();
+                        let length = items.len();
                         (0..length)
                             .fold(None, |remove_index, i| {
-                                let path = &mut self.paths[i];
-                                let mut str = path.to_str().unwrap().to_string();
                                 ui.horizontal(|ui| {
-                                    let text_edit = ui.add(
-                                        egui::TextEdit::singleline(&mut str)
-                                            .desired_width(260.0)
-                                            .margin(egui::vec2(13.0, 0.0)),
-                                    );
-                                    if text_edit.changed() {
-                                        *path = PathBuf::from(std::ffi::OsString::from(str));
-                                    }
-                                    if focus_last_path && i == length - 1 {
-                                        text_edit.scroll_to_me(None);
-                                        ui.memory_mut(|memory| memory.request_focus(text_edit.id));
+                                    let response = item_ui(ui, &mut items[i]);
+                                    if focus_last && i == length - 1 {
+                                        response.scroll_to_me(None);
+                                        ui.memory_mut(|memory| memory.request_focus(response.id));
                                     }
                                     remove_button(ui).clicked().then_some(i).or(remove_index)
                                 })
                                 .inner
                             })
-                            .map(|i| self.paths.remove(i));
+                            .map(|i| items.remove(i));
                     })
             });
-    }
+    });
 }
 
 fn add_button(ui: &mut egui::Ui) -> egui::Response {
@@ -188,3 +940,136 @@ fn remove_button(ui: &mut egui::Ui) -> egui::Response {
     })
     .inner
 }
+
+/// Paths are saved as strings, or as arrays of bytes when they aren't UTF-8
+mod path_serde {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::path::{Path, PathBuf};
+
+    pub fn serialize<S: serde::Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(str) => serializer.serialize_str(str),
+            None => serializer.collect_seq(path.as_os_str().as_bytes()),
+        }
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<PathBuf, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Saved {
+            Str(PathBuf),
+            Bytes(Vec<u8>),
+        }
+        Ok(match serde::Deserialize::deserialize(deserializer)? {
+            Saved::Str(path) => path,
+            Saved::Bytes(bytes) => PathBuf::from(OsString::from_vec(bytes)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn backups(dir: &TestDir) -> Vec<String> {
+        std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|x| x.ends_with(".bak"))
+            .collect()
+    }
+
+    fn saved(path: &Path) -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn migrates_the_first_format() {
+        let dir = TestDir::new();
+        let original = r#"{"random": false, "hidden": true, "video": false, "mute": true,
+            "period_secs": 7.0, "paths": ["/a", "/b"]}"#;
+        let path = dir.file("config.json", original.as_bytes());
+        let (config, problem) = Config::load(path.clone(), false);
+        assert_eq!(problem, None);
+        assert_eq!(
+            *config.options(),
+            Options {
+                order: Order::Sequential,
+                hidden: true,
+                video: false,
+                mute: true,
+                period_secs: 7.0,
+                paths: vec![Source::new("/a".into()), Source::new("/b".into())],
+                ..Default::default()
+            }
+        );
+        // Written in the current format, keeping the original
+        assert_eq!(saved(&path)["version"], Options::VERSION);
+        assert_eq!(saved(&path)["order"], "sequential");
+        let backups = backups(&dir);
+        assert_eq!(backups.len(), 1);
+        let backup = std::fs::read_to_string(dir.path().join(&backups[0])).unwrap();
+        assert_eq!(backup, original);
+    }
+
+    #[test]
+    fn backs_up_an_unreadable_file_and_uses_defaults() {
+        let dir = TestDir::new();
+        let path = dir.file("config.json", br#"{"version": 2, "hidden": tr"#);
+        let (config, problem) = Config::load(path.clone(), false);
+        assert_eq!(*config.options(), Options::default());
+        assert!(problem.unwrap().contains("backed up"));
+        assert_eq!(backups(&dir).len(), 1);
+        assert_eq!(saved(&path)["hidden"], false);
+
+        // Unless asked not to write
+        let path = dir.file("read_only.json", b"[");
+        let (config, problem) = Config::load(path.clone(), true);
+        assert_eq!(*config.options(), Options::default());
+        assert!(problem.is_some());
+        assert_eq!(backups(&dir).len(), 1);
+        assert_eq!(std::fs::read(&path).unwrap(), b"[");
+    }
+
+    #[test]
+    fn keeps_the_fields_that_are_valid() {
+        let dir = TestDir::new();
+        let path = dir.file(
+            "config.json",
+            br#"{"version": 2, "hidden": "yes", "period_secs": 5.0, "order": "backwards",
+                "from_a_plugin": 1}"#,
+        );
+        let (config, problem) = Config::load(path.clone(), false);
+        assert_eq!(
+            *config.options(),
+            Options {
+                period_secs: 5.0,
+                ..Default::default()
+            }
+        );
+        let problem = problem.unwrap();
+        assert!(problem.contains("invalid hidden, order"), "{problem}");
+        assert_eq!(saved(&path)["period_secs"], 5.0);
+
+        let (opts, invalid_fields) = Options::from_value(serde_json::json!([1, 2]));
+        assert_eq!(opts, Options::default());
+        assert_eq!(invalid_fields, ["root"]);
+    }
+
+    #[test]
+    fn never_writes_a_newer_format() {
+        let dir = TestDir::new();
+        let contents = br#"{"version": 99, "hidden": true, "new_option": 1}"#;
+        let path = dir.file("config.json", contents);
+        let (mut config, problem) = Config::load(path.clone(), false);
+        assert!(config.options().hidden);
+        assert!(problem.unwrap().contains("newer version"));
+        config.save(&Options::default());
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+        assert!(backups(&dir).is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::location;
+use crate::media_index::MediaIndex;
+use crate::settings::SortBy;
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A file's position in sequential playback
+#[derive(Debug, Clone)]
+pub struct SortKey {
+    sort_by: SortBy,
+    reverse: bool,
+    // Compared before the path, zero when sorting by name
+    key: i64,
+    pub path: PathBuf,
+}
+
+impl SortKey {
+    pub fn new(sort_by: SortBy, reverse: bool, path: PathBuf, index: &MediaIndex) -> Self {
+        let modified = || {
+            location::modified(&path)
+                .ok()
+                .and_then(|x| x.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map_or(0, |x| x.as_secs() as i64)
+        };
+        let key = match sort_by {
+            SortBy::Name | SortBy::NaturalName => 0,
+            SortBy::Modified => modified(),
+            SortBy::CaptureDate => index
+                .probe(&path)
+                .and_then(|info| info.capture_date())
+                .map_or_else(modified, |date| date.timestamp()),
+            SortBy::Size => location::len(&path).map_or(0, |x| x as i64),
+        };
+        Self {
+            sort_by,
+            reverse,
+            key,
+            path,
+        }
+    }
+}
+
+impl Ord for SortKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ordering = self.key.cmp(&other.key).then_with(|| match self.sort_by {
+            SortBy::NaturalName => natural_path_cmp(&self.path, &other.path),
+            _ => self.path.cmp(&other.path),
+        });
+        if self.reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SortKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SortKey {}
+
+/// Compares paths component by component like [`natural_cmp`], so that the order matches walking
+/// the directory tree with siblings in natural order
+pub fn natural_path_cmp(a: &Path, b: &Path) -> Ordering {
+    let mut a = a.iter();
+    let mut b = b.iter();
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => match natural_cmp(x, y) {
+                Ordering::Equal => {}
+                ordering => return ordering,
+            },
+        }
+    }
+}
+
+/// Compares names with runs of digits compared by value, so that `IMG_2` comes before `IMG_10`.
+/// Names that only differ in leading zeros are compared as they are.
+pub fn natural_cmp(a: &OsStr, b: &OsStr) -> Ordering {
+    let a_str = a.to_string_lossy();
+    let b_str = b.to_string_lossy();
+    let mut a_chars = a_str.chars().peekable();
+    let mut b_chars = b_str.chars().peekable();
+    loop {
+        let ordering = match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a_chars);
+                let y = take_number(&mut b_chars);
+                x.len().cmp(&y.len()).then_with(|| x.cmp(&y))
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.cmp(y);
+                a_chars.next();
+                b_chars.next();
+                ordering
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// The digits at the start, without leading zeros
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        digits.push(c);
+    }
+    digits.trim_start_matches('0').to_string()
+}
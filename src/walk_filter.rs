@@ -0,0 +1,318 @@
+use crate::watcher::root_index;
+use crate::Options;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Gitignore-style rules for the directory it is in and everything below
+pub const IGNORE_FILE_NAME: &str = ".abelscreensaverignore";
+
+/// Excludes the directory it is in and everything below
+pub const NO_MEDIA_FILE_NAME: &str = ".nomedia";
+
+#[derive(Clone)]
+struct DirRules {
+    no_media: bool,
+    ignore: Option<Gitignore>,
+}
+
+impl DirRules {
+    fn read(dir: &Path) -> Self {
+        let ignore_path = dir.join(IGNORE_FILE_NAME);
+        let ignore = ignore_path.is_file().then(|| {
+            let mut builder = GitignoreBuilder::new(dir);
+            if let Some(err) = builder.add(&ignore_path) {
+                eprintln!("{}: {err}", ignore_path.display());
+            }
+            builder.build().unwrap_or_else(|_| Gitignore::empty())
+        });
+        Self {
+            no_media: dir.join(NO_MEDIA_FILE_NAME).exists(),
+            ignore,
+        }
+    }
+}
+
+/// Decides which entries the walkers visit, from the hidden option, the include/exclude globs and
+/// the ignore files found along the way
+#[derive(Clone)]
+pub struct WalkFilter {
+    // The sources, followed by the directories listed in playlists
+    roots: Vec<PathBuf>,
+    sources: usize,
+    max_depths: Vec<Option<usize>>,
+    hidden: bool,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    dir_rules: HashMap<PathBuf, DirRules>,
+    // Device and inode of every directory allowed so far, when following symlinks. Each is only
+    // visited once, so a link to a directory above it can't make the walk go around in circles.
+    visited_dirs: Option<HashSet<(u64, u64)>>,
+}
+
+impl WalkFilter {
+    pub fn new(opts: &Options) -> Self {
+        Self {
+            roots: opts.paths.iter().map(|x| x.path.clone()).collect(),
+            sources: opts.paths.len(),
+            max_depths: opts.paths.iter().map(|x| x.max_depth).collect(),
+            hidden: opts.hidden,
+            include: (!opts.include.is_empty()).then(|| build_glob_set(&opts.include)),
+            exclude: build_glob_set(&opts.exclude),
+            dir_rules: HashMap::new(),
+            visited_dirs: opts.follow_symlinks.then(|| {
+                opts.paths
+                    .iter()
+                    .filter_map(|x| dir_identity(&x.path))
+                    .collect()
+            }),
+        }
+    }
+
+    pub fn allows(&mut self, path: &Path, is_dir: bool) -> bool {
+        let Some(i) = root_index(&self.roots, path) else {
+            return false;
+        };
+        let root = &self.roots[i];
+        let relative = path.strip_prefix(root).unwrap();
+        // A root inside another root is walked on its own
+        if is_dir && relative.as_os_str().is_empty() {
+            return false;
+        }
+        if !self.hidden && relative.iter().any(is_hidden) {
+            return false;
+        }
+        if let Some(max_depth) = self.max_depths[i] {
+            // Nothing below a directory at the max depth can be visited
+            let depth = relative.components().count();
+            if depth > max_depth || (is_dir && depth == max_depth) {
+                return false;
+            }
+        }
+        if glob_matches(&self.exclude, path) {
+            return false;
+        }
+        if !is_dir
+            && self
+                .include
+                .as_ref()
+                .is_some_and(|include| !glob_matches(include, path))
+        {
+            return false;
+        }
+        // From the root down, so that deeper ignore files take precedence
+        let mut dirs: Vec<_> = path
+            .ancestors()
+            .skip(if is_dir { 0 } else { 1 })
+            .take_while(|dir| dir.starts_with(root))
+            .map(Path::to_path_buf)
+            .collect();
+        dirs.reverse();
+        !self.is_ignored(path, is_dir, dirs) && self.is_unvisited(path, is_dir)
+    }
+
+    /// Checks a file or directory listed in a playlist, which can be anywhere, so only the rules
+    /// of its own directory apply. An allowed directory is walked like a root without a max depth.
+    pub fn allows_listed(&mut self, path: &Path, is_dir: bool) -> bool {
+        let is_hidden = path.file_name().is_some_and(is_hidden);
+        if (!self.hidden && is_hidden) || glob_matches(&self.exclude, path) {
+            return false;
+        }
+        if !is_dir
+            && self
+                .include
+                .as_ref()
+                .is_some_and(|include| !glob_matches(include, path))
+        {
+            return false;
+        }
+        let dirs = path
+            .parent()
+            .into_iter()
+            .chain(is_dir.then_some(path))
+            .map(Path::to_path_buf)
+            .collect();
+        let is_allowed = !self.is_ignored(path, is_dir, dirs) && self.is_unvisited(path, is_dir);
+        if is_allowed && is_dir && !self.roots.iter().any(|x| x == path) {
+            self.roots.push(path.to_path_buf());
+            self.max_depths.push(None);
+        }
+        is_allowed
+    }
+
+    /// Forget which directories were visited, so that the sources can be walked again
+    pub fn restart(&mut self) {
+        if let Some(visited_dirs) = &mut self.visited_dirs {
+            visited_dirs.clear();
+            visited_dirs.extend(
+                self.roots[..self.sources]
+                    .iter()
+                    .filter_map(|x| dir_identity(x)),
+            );
+        }
+    }
+
+    /// Whether a `.nomedia` or ignore file in the directories excludes the path, checking them
+    /// from the top down
+    fn is_ignored(&mut self, path: &Path, is_dir: bool, dirs: Vec<PathBuf>) -> bool {
+        let mut ignored = false;
+        for dir in dirs {
+            let rules = self
+                .dir_rules
+                .entry(dir.clone())
+                .or_insert_with(|| DirRules::read(&dir));
+            if rules.no_media {
+                return true;
+            }
+            if dir == path {
+                continue;
+            }
+            if let Some(ignore) = &rules.ignore {
+                let matched = ignore.matched_path_or_any_parents(path, is_dir);
+                if matched.is_ignore() {
+                    ignored = true;
+                } else if matched.is_whitelist() {
+                    ignored = false;
+                }
+            }
+        }
+        ignored
+    }
+
+    fn is_unvisited(&mut self, path: &Path, is_dir: bool) -> bool {
+        match &mut self.visited_dirs {
+            Some(visited_dirs) if is_dir => {
+                dir_identity(path).is_some_and(|identity| visited_dirs.insert(identity))
+            }
+            _ => true,
+        }
+    }
+}
+
+fn dir_identity(path: &Path) -> Option<(u64, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+fn is_hidden(str: &OsStr) -> bool {
+    str.as_bytes().starts_with(b".")
+}
+
+/// Globs are matched against the whole path, and against the file name alone
+fn glob_matches(glob_set: &GlobSet, path: &Path) -> bool {
+    glob_set.is_match(path)
+        || path
+            .file_name()
+            .is_some_and(|file_name| glob_set.is_match(file_name))
+}
+
+/// Invalid patterns are skipped, see [`glob_error`]
+fn build_glob_set(patterns: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns.iter().filter(|x| !x.is_empty()) {
+        if let Ok(glob) = Glob::new(pattern) {
+            builder.add(glob);
+        }
+    }
+    builder.build().unwrap_or_else(|_| GlobSet::empty())
+}
+
+pub fn glob_error(pattern: &str) -> Option<String> {
+    Glob::new(pattern).err().map(|err| err.kind().to_string())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::diagnostics::Diagnostics;
+    use crate::history::History;
+    use crate::media_index::MediaIndex;
+    use crate::media_iterator::{random_media_iterator, sequential_media_iterator};
+    use crate::probe::Playable;
+    use crate::settings::{Order, Source};
+    use crate::test_dir::TestDir;
+    use crate::Options;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    /// A link back to the parent of its directory, and two directories linking to each other
+    fn looped() -> TestDir {
+        let dir = TestDir::new();
+        dir.file("a.jpg", b"");
+        dir.file("sub/b.jpg", b"");
+        dir.file("x/c.jpg", b"");
+        dir.file("y/d.jpg", b"");
+        symlink("..", dir.path().join("sub/up")).unwrap();
+        symlink("../y", dir.path().join("x/to_y")).unwrap();
+        symlink("../x", dir.path().join("y/to_x")).unwrap();
+        dir
+    }
+
+    fn options(dir: &TestDir, order: Order) -> Options {
+        Options {
+            order,
+            follow_symlinks: true,
+            watch: false,
+            paths: vec![Source::new(dir.path().to_path_buf())],
+            ..Default::default()
+        }
+    }
+
+    /// Each file, however it was reached
+    fn files(paths: impl Iterator<Item = PathBuf>) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = paths.map(|x| x.canonicalize().unwrap()).collect();
+        files.sort();
+        files
+    }
+
+    fn expected(dir: &TestDir) -> Vec<PathBuf> {
+        let root = dir.path().canonicalize().unwrap();
+        ["a.jpg", "sub/b.jpg", "x/c.jpg", "y/d.jpg"]
+            .map(|x| root.join(x))
+            .to_vec()
+    }
+
+    #[test]
+    fn walks_symlink_loops_once() {
+        let dir = looped();
+        let index = MediaIndex::default();
+        index.set_prober(Arc::new(Playable));
+        let walked = sequential_media_iterator(
+            options(&dir, Order::Sequential),
+            index.clone(),
+            Diagnostics::default(),
+        );
+        assert_eq!(files(walked), expected(&dir));
+        for seed in 0..4 {
+            let opts = Options {
+                seed: Some(seed),
+                ..options(&dir, Order::Shuffle)
+            };
+            let walked = random_media_iterator(
+                opts,
+                index.clone(),
+                History::default(),
+                Diagnostics::default(),
+            );
+            assert_eq!(files(walked), expected(&dir));
+        }
+    }
+
+    #[test]
+    fn allows_each_directory_once() {
+        let dir = looped();
+        let mut filter = super::WalkFilter::new(&options(&dir, Order::Sequential));
+        assert!(filter.allows(&dir.path().join("x"), true));
+        assert!(!filter.allows(&dir.path().join("y/to_x"), true));
+        assert!(filter.allows(&dir.path().join("x/to_y"), true));
+        assert!(!filter.allows(&dir.path().join("y"), true));
+        // The root is visited first
+        assert!(!filter.allows(&dir.path().join("sub/up"), true));
+        filter.restart();
+        assert!(filter.allows(&dir.path().join("y"), true));
+    }
+}
@@ -0,0 +1,274 @@
+use crate::catalog;
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, SendError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+// How long a new file must keep its size before it counts as fully copied, for when there is no
+// event for it being closed
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+#[derive(Debug, PartialEq)]
+pub enum WatchEvent {
+    // A file appeared, or finished being written
+    Added(PathBuf),
+    // A file or directory disappeared
+    Removed(PathBuf),
+}
+
+/// Watches the given paths recursively, except for sources on servers which can't be watched.
+/// Sources that are files, like playlists and archives, are watched through their directory, so
+/// that they are still watched after being replaced.
+///
+/// Events stop once the returned watcher is dropped, which also disconnects the receiver
+pub fn watch(paths: &[PathBuf]) -> Option<(RecommendedWatcher, Receiver<WatchEvent>)> {
+    let (raw_tx, raw_rx) = channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            raw_tx.send(event).ok();
+        }
+    })
+    .map_err(|err| eprintln!("Failed to watch paths: {err}"))
+    .ok()?;
+    for path in paths.iter().filter(|x| !catalog::is_catalog(x)) {
+        let result = match path.parent().filter(|_| path.is_file()) {
+            Some(dir) => watcher.watch(dir, RecursiveMode::NonRecursive),
+            None => watcher.watch(path, RecursiveMode::Recursive),
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to watch {}: {err}", path.display());
+        }
+    }
+    let (tx, rx) = channel();
+    // Stops once the watcher is dropped, or the receiver is
+    thread::spawn(move || debounce(raw_rx, tx));
+    Some((watcher, rx))
+}
+
+/// Files that were created but may still be being written, with their size and when it was last
+/// seen to change
+type Settling = HashMap<PathBuf, (u64, Instant)>;
+
+/// Forwards the events, holding back new files until they are closed or their size settles
+fn debounce(
+    events: Receiver<notify::Event>,
+    tx: Sender<WatchEvent>,
+) -> Result<(), SendError<WatchEvent>> {
+    let mut settling = Settling::new();
+    loop {
+        let deadline = settling.values().map(|&(_, at)| at + SETTLE_TIME).min();
+        let event = match deadline {
+            Some(deadline) => {
+                events.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Ok(event) => forward(event, &tx, &mut settling)?,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        let now = Instant::now();
+        let due: Vec<PathBuf> = settling
+            .iter()
+            .filter(|(_, &(_, at))| now >= at + SETTLE_TIME)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in due {
+            let size = settling[&path].0;
+            match std::fs::metadata(&path) {
+                Ok(metadata) if metadata.len() == size => {
+                    settling.remove(&path);
+                    tx.send(WatchEvent::Added(path))?;
+                }
+                Ok(metadata) => {
+                    settling.insert(path, (metadata.len(), now));
+                }
+                // Gone again, which was reported
+                Err(_) => {
+                    settling.remove(&path);
+                }
+            }
+        }
+    }
+}
+
+fn forward(
+    event: notify::Event,
+    tx: &Sender<WatchEvent>,
+    settling: &mut Settling,
+) -> Result<(), SendError<WatchEvent>> {
+    match event.kind {
+        // Copies start out empty, so they are only reported once they are done
+        EventKind::Create(_) => {
+            let now = Instant::now();
+            for path in event.paths.iter().flat_map(|x| files(x)) {
+                let size = std::fs::metadata(&path).map_or(0, |x| x.len());
+                settling.insert(path, (size, now));
+            }
+        }
+        EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any) => {
+            for path in &event.paths {
+                if let Some((_, at)) = settling.get_mut(path) {
+                    *at = Instant::now();
+                }
+            }
+        }
+        // Also files that were changed in place, so that they are probed again
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+            for path in event.paths.iter().flat_map(|x| files(x)) {
+                settling.remove(&path);
+                tx.send(WatchEvent::Added(path))?;
+            }
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            for path in event.paths.iter().flat_map(|x| files(x)) {
+                tx.send(WatchEvent::Added(path))?;
+            }
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            let mut paths = event.paths.into_iter();
+            if let Some(from) = paths.next() {
+                removed(from, tx, settling)?;
+            }
+            for path in paths.next().iter().flat_map(|x| files(x)) {
+                tx.send(WatchEvent::Added(path))?;
+            }
+        }
+        EventKind::Modify(ModifyKind::Name(_)) => {
+            for path in event.paths {
+                if path.exists() {
+                    for path in files(&path) {
+                        tx.send(WatchEvent::Added(path))?;
+                    }
+                } else {
+                    removed(path, tx, settling)?;
+                }
+            }
+        }
+        EventKind::Remove(_) => {
+            for path in event.paths {
+                removed(path, tx, settling)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn removed(
+    path: PathBuf,
+    tx: &Sender<WatchEvent>,
+    settling: &mut Settling,
+) -> Result<(), SendError<WatchEvent>> {
+    settling.retain(|x, _| !x.starts_with(&path));
+    tx.send(WatchEvent::Removed(path))
+}
+
+/// The file, or the files in the directory. Directories moved into a watched path only produce a
+/// single event, so their contents are reported with them.
+fn files(path: &Path) -> Vec<PathBuf> {
+    if path.is_dir() {
+        WalkDir::new(path)
+            .into_iter()
+            .filter_map(|x| x.ok())
+            .filter(|x| x.file_type().is_file())
+            .map(|x| x.into_path())
+            .collect()
+    } else if path.is_file() {
+        vec![path.to_path_buf()]
+    } else {
+        vec![]
+    }
+}
+
+/// The index of the root that contains the path, if any
+pub fn root_index(roots: &[PathBuf], path: &Path) -> Option<usize> {
+    // The deepest one, when roots are nested
+    (0..roots.len())
+        .filter(|&i| path.starts_with(&roots[i]))
+        .max_by_key(|&i| roots[i].components().count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use std::io::Write;
+
+    /// The events until none came for a while
+    fn quiet_events(rx: &Receiver<WatchEvent>) -> Vec<WatchEvent> {
+        rx.recv_timeout(Duration::from_secs(5))
+            .into_iter()
+            .chain(std::iter::from_fn(|| rx.recv_timeout(SETTLE_TIME * 2).ok()))
+            .collect()
+    }
+
+    #[test]
+    fn reports_new_files_once_they_are_written() {
+        let dir = TestDir::new();
+        let (_watcher, rx) = watch(&[dir.path().to_path_buf()]).unwrap();
+        let path = dir.path().join("a.jpg");
+        let mut file = std::fs::File::create(&path).unwrap();
+        for _ in 0..4 {
+            file.write_all(&[0; 1000]).unwrap();
+            thread::sleep(SETTLE_TIME / 2);
+        }
+        assert!(rx.try_recv().is_err());
+        drop(file);
+        assert_eq!(quiet_events(&rx), [WatchEvent::Added(path)]);
+    }
+
+    #[test]
+    fn reports_new_files_once_their_size_settles() {
+        let dir = TestDir::new();
+        let (_watcher, rx) = watch(&[dir.path().to_path_buf()]).unwrap();
+        let path = dir.path().join("a.jpg");
+        let started = Instant::now();
+        // Kept open, so it is never closed
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(&[0; 1000]).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            WatchEvent::Added(path)
+        );
+        assert!(started.elapsed() >= SETTLE_TIME);
+    }
+
+    #[test]
+    fn reports_moves_and_removals() {
+        let dir = TestDir::new();
+        let watched = dir.path().join("watched");
+        let a = dir.file("watched/a.jpg", b"");
+        dir.file("outside/b/c.jpg", b"");
+        let (_watcher, rx) = watch(std::slice::from_ref(&watched)).unwrap();
+        // Moved files are whole, so they are reported right away
+        std::fs::rename(dir.path().join("outside"), watched.join("moved")).unwrap();
+        let events = quiet_events(&rx);
+        assert!(events.contains(&WatchEvent::Added(watched.join("moved/b/c.jpg"))));
+        let renamed = watched.join("renamed.jpg");
+        std::fs::rename(&a, &renamed).unwrap();
+        let events = quiet_events(&rx);
+        assert!(events.contains(&WatchEvent::Removed(a)));
+        assert!(events.contains(&WatchEvent::Added(renamed.clone())));
+        std::fs::remove_file(&renamed).unwrap();
+        assert_eq!(quiet_events(&rx), [WatchEvent::Removed(renamed)]);
+    }
+
+    #[test]
+    fn watches_sources_that_are_replaced() {
+        let dir = TestDir::new();
+        let playlist = dir.file("list.m3u", b"a.jpg\n");
+        let (_watcher, rx) = watch(std::slice::from_ref(&playlist)).unwrap();
+        // Like editors that save to a new file
+        let saved = dir.file("list.m3u.tmp", b"a.jpg\nb.jpg\n");
+        std::fs::rename(saved, &playlist).unwrap();
+        assert!(quiet_events(&rx).contains(&WatchEvent::Added(playlist.clone())));
+        std::fs::write(&playlist, b"c.jpg\n").unwrap();
+        assert!(quiet_events(&rx).contains(&WatchEvent::Added(playlist)));
+    }
+}
@@ -0,0 +1,371 @@
+use crate::location;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::NsReader;
+use std::io::{BufRead, Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// Embedded packets are only looked for this far into the file, which is where images have them
+pub const MAX_SCAN_BYTES: u64 = 4 << 20;
+
+const XMP_NS: &[u8] = b"http://ns.adobe.com/xap/1.0/";
+const DC_NS: &[u8] = b"http://purl.org/dc/elements/1.1/";
+const LIGHTROOM_NS: &[u8] = b"http://ns.adobe.com/lightroom/1.0/";
+const DIGIKAM_NS: &[u8] = b"http://www.digikam.org/ns/1.0/";
+
+// EXIF tags written by Windows, which aren't named by the exif crate
+const EXIF_RATING: exif::Tag = exif::Tag(exif::Context::Tiff, 0x4746);
+const EXIF_KEYWORDS: exif::Tag = exif::Tag(exif::Context::Tiff, 0x9c9e);
+
+/// The rating and tags of a file, as photo managers write them
+#[derive(Debug, Default, PartialEq)]
+pub struct Metadata {
+    // From -1 for rejected to 5 stars, None if unrated
+    pub rating: Option<i8>,
+    // Levels of hierarchical tags are joined with `/`, like `People/Alice`
+    pub tags: Vec<String>,
+}
+
+impl Metadata {
+    /// Takes the rating of the other if it has one, and adds its tags
+    fn merge(&mut self, other: Self) {
+        self.rating = other.rating.or(self.rating);
+        for tag in other.tags {
+            self.add_tag(tag);
+        }
+    }
+
+    fn add_tag(&mut self, tag: String) {
+        let lowercase = tag.to_lowercase();
+        if !self.tags.iter().any(|x| x.to_lowercase() == lowercase) {
+            self.tags.push(tag);
+        }
+    }
+}
+
+/// The `.xmp` sidecar of a file on disk, named either like `photo.jpg.xmp` as darktable and
+/// digiKam do, or like `photo.xmp` as Lightroom does
+pub fn sidecar(path: &Path) -> Option<PathBuf> {
+    if !location::is_local(path) {
+        return None;
+    }
+    let mut with_suffix = path.as_os_str().to_os_string();
+    with_suffix.push(".xmp");
+    [PathBuf::from(with_suffix), path.with_extension("xmp")]
+        .into_iter()
+        .find(|x| x.is_file())
+}
+
+/// When the sidecar of the file was last written, None if it has none
+pub fn sidecar_modified(path: &Path) -> Option<SystemTime> {
+    sidecar(path)?.metadata().ok()?.modified().ok()
+}
+
+/// The rating and tags from the EXIF data and the XMP packet of the copy of the file being read,
+/// then from the sidecar of the file, which win as they are edited last
+pub fn read(path: &Path, reader: &mut (impl BufRead + Seek)) -> Metadata {
+    let exif = read_exif(reader);
+    let mut head = vec![];
+    if reader.rewind().is_ok() {
+        reader.take(MAX_SCAN_BYTES).read_to_end(&mut head).ok();
+    }
+    merge(path, exif, &head)
+}
+
+/// Like [`read`], from the first [`MAX_SCAN_BYTES`] of a file that isn't on disk, such as a member
+/// of an archive
+pub fn read_head(path: &Path, head: &[u8]) -> Metadata {
+    merge(path, read_exif(&mut Cursor::new(head)), head)
+}
+
+fn merge(path: &Path, exif: Option<Metadata>, head: &[u8]) -> Metadata {
+    let mut metadata = exif.unwrap_or_default();
+    if let Some(packet) = embedded_packet(head) {
+        metadata.merge(parse(&packet).unwrap_or_default());
+    }
+    if let Some(sidecar) = sidecar(path) {
+        match std::fs::read_to_string(&sidecar)
+            .map_err(|err| err.to_string())
+            .and_then(|xml| parse(&xml).map_err(|err| err.to_string()))
+        {
+            Ok(sidecar_metadata) => metadata.merge(sidecar_metadata),
+            Err(err) => eprintln!("Ignoring the sidecar {}: {err}", sidecar.display()),
+        }
+    }
+    // 0 is how ratings are cleared
+    metadata.rating = metadata.rating.filter(|x| *x != 0);
+    metadata
+}
+
+/// The rating and keywords Windows writes
+fn read_exif(reader: &mut (impl BufRead + Seek)) -> Option<Metadata> {
+    let exif = exif::Reader::new().read_from_container(reader).ok()?;
+    let mut metadata = Metadata {
+        rating: exif
+            .get_field(EXIF_RATING, exif::In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .map(|x| x.min(5) as i8),
+        tags: vec![],
+    };
+    // UTF-16 with a trailing null, separated by semicolons
+    if let Some(exif::Value::Byte(bytes)) = exif
+        .get_field(EXIF_KEYWORDS, exif::In::PRIMARY)
+        .map(|field| &field.value)
+    {
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]]))
+            .collect();
+        let keywords = String::from_utf16_lossy(&units);
+        keywords
+            .trim_end_matches('\0')
+            .split(';')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .for_each(|x| metadata.add_tag(x.to_string()));
+    }
+    Some(metadata)
+}
+
+/// The XMP packet in the start of the file, found by its markers as every format stores it
+/// differently
+fn embedded_packet(head: &[u8]) -> Option<String> {
+    let start = find(head, b"<x:xmpmeta")?;
+    let end = start + find(&head[start..], b"</x:xmpmeta>")? + b"</x:xmpmeta>".len();
+    Some(String::from_utf8_lossy(&head[start..end]).into_owned())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|x| x == needle)
+}
+
+/// The properties read, going by their namespace rather than the prefix, which writers choose
+#[derive(Clone, Copy, PartialEq)]
+enum Property {
+    Rating,
+    // Flat keywords
+    Subject,
+    // Lightroom's hierarchical keywords, with levels separated by `|`
+    HierarchicalSubject,
+    // digiKam's hierarchical tags, with levels separated by `/`
+    TagsList,
+}
+
+impl Property {
+    fn of(namespace: &ResolveResult, local_name: &[u8]) -> Option<Self> {
+        let ResolveResult::Bound(Namespace(namespace)) = namespace else {
+            return None;
+        };
+        match (*namespace, local_name) {
+            (XMP_NS, b"Rating") => Some(Self::Rating),
+            (DC_NS, b"subject") => Some(Self::Subject),
+            (LIGHTROOM_NS, b"hierarchicalSubject") => Some(Self::HierarchicalSubject),
+            (DIGIKAM_NS, b"TagsList") => Some(Self::TagsList),
+            _ => None,
+        }
+    }
+}
+
+/// The rating and tags in an XMP packet or sidecar
+fn parse(xml: &str) -> Result<Metadata, quick_xml::Error> {
+    let mut reader = NsReader::from_str(xml);
+    let mut metadata = Metadata::default();
+    // The property whose value is being read
+    let mut property = None;
+    loop {
+        let (namespace, event) = reader.read_resolved_event()?;
+        match event {
+            Event::Start(element) => {
+                let opened = Property::of(&namespace, element.local_name().as_ref());
+                property = opened.or(property);
+                rating_attribute(&reader, &element, &mut metadata);
+            }
+            Event::Empty(element) => rating_attribute(&reader, &element, &mut metadata),
+            Event::Text(text) => {
+                let text = text.unescape()?;
+                let text = text.trim();
+                match property {
+                    Some(Property::Rating) => {
+                        metadata.rating = parse_rating(text).or(metadata.rating);
+                    }
+                    // Only the items of the list have text
+                    Some(Property::HierarchicalSubject) if !text.is_empty() => {
+                        metadata.add_tag(text.replace('|', "/"));
+                    }
+                    Some(_) if !text.is_empty() => metadata.add_tag(text.to_string()),
+                    _ => {}
+                }
+            }
+            Event::End(element)
+                if Property::of(&namespace, element.local_name().as_ref()).is_some() =>
+            {
+                property = None;
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(metadata)
+}
+
+/// The rating written as an attribute, as in `<rdf:Description xmp:Rating="3">`
+fn rating_attribute(reader: &NsReader<&[u8]>, element: &BytesStart, metadata: &mut Metadata) {
+    for attribute in element.attributes().flatten() {
+        let (namespace, local_name) = reader.resolve_attribute(attribute.key);
+        if Property::of(&namespace, local_name.as_ref()) != Some(Property::Rating) {
+            continue;
+        }
+        if let Ok(value) = attribute.unescape_value() {
+            metadata.rating = parse_rating(&value).or(metadata.rating);
+        }
+    }
+}
+
+/// Ratings are integers, but some writers add decimals
+fn parse_rating(str: &str) -> Option<i8> {
+    let rating: f64 = str.trim().parse().ok()?;
+    Some(rating.round().clamp(-1.0, 5.0) as i8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn packet(description: &str) -> String {
+        format!(
+            r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:lr="http://ns.adobe.com/lightroom/1.0/"
+    xmlns:digiKam="http://www.digikam.org/ns/1.0/"{description}
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#
+        )
+    }
+
+    /// A little-endian TIFF with only the rating and keywords Windows writes
+    fn tiff(rating: u16, keywords: &str) -> Vec<u8> {
+        let mut keywords: Vec<u8> = keywords
+            .encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend(8u32.to_le_bytes());
+        tiff.extend(2u16.to_le_bytes());
+        // Short, stored in the entry
+        tiff.extend(0x4746u16.to_le_bytes());
+        tiff.extend(3u16.to_le_bytes());
+        tiff.extend(1u32.to_le_bytes());
+        tiff.extend((rating as u32).to_le_bytes());
+        // Bytes, stored after the directory
+        tiff.extend(0x9c9eu16.to_le_bytes());
+        tiff.extend(1u16.to_le_bytes());
+        tiff.extend((keywords.len() as u32).to_le_bytes());
+        tiff.extend((8 + 2 + 2 * 12 + 4u32).to_le_bytes());
+        // No next directory
+        tiff.extend(0u32.to_le_bytes());
+        tiff.append(&mut keywords);
+        tiff
+    }
+
+    #[test]
+    fn reads_embedded_packets() {
+        let dir = TestDir::new();
+        let path = dir.path().join("photo.jpg");
+        let xml = packet(
+            r#">
+   <xmp:Rating>4</xmp:Rating>
+   <dc:subject>
+    <rdf:Bag><rdf:li>Cats</rdf:li><rdf:li>Dogs</rdf:li></rdf:Bag>
+   </dc:subject>
+   <lr:hierarchicalSubject>
+    <rdf:Bag><rdf:li>Places|Paris</rdf:li></rdf:Bag>
+   </lr:hierarchicalSubject>"#,
+        );
+        let head = [b"\xff\xd8 image data ".as_slice(), xml.as_bytes(), b" more"].concat();
+        assert_eq!(
+            read_head(&path, &head),
+            Metadata {
+                rating: Some(4),
+                tags: vec!["Cats".into(), "Dogs".into(), "Places/Paris".into()],
+            }
+        );
+
+        // The rating as an attribute and digiKam's tags, with a prefix of its own
+        let xml = packet(
+            r#"
+    xmp:Rating="2.6">
+   <digiKam:TagsList>
+    <rdf:Seq><rdf:li>People/Alice</rdf:li></rdf:Seq>
+   </digiKam:TagsList>"#,
+        )
+        .replace("digiKam", "dk");
+        assert_eq!(
+            read_head(&path, xml.as_bytes()),
+            Metadata {
+                rating: Some(3),
+                tags: vec!["People/Alice".into()],
+            }
+        );
+        assert_eq!(read_head(&path, b"no packet"), Metadata::default());
+    }
+
+    #[test]
+    fn reads_the_exif_rating_and_keywords() {
+        let dir = TestDir::new();
+        let path = dir.path().join("photo.tif");
+        let head = tiff(3, "Cats; Dogs");
+        assert_eq!(
+            read(&path, &mut Cursor::new(&head)),
+            Metadata {
+                rating: Some(3),
+                tags: vec!["Cats".into(), "Dogs".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn later_edits_win() {
+        let dir = TestDir::new();
+        let path = dir.file("photo.jpg", b"");
+        // The packet is written over the EXIF data, and tags are kept once ignoring case
+        let xml = packet(
+            r#">
+   <xmp:Rating>4</xmp:Rating>
+   <dc:subject><rdf:Bag><rdf:li>cats</rdf:li><rdf:li>Birds</rdf:li></rdf:Bag></dc:subject>"#,
+        );
+        let head = [tiff(1, "Cats;Dogs"), xml.into_bytes()].concat();
+        assert_eq!(
+            read_head(&path, &head),
+            Metadata {
+                rating: Some(4),
+                tags: vec!["Cats".into(), "Dogs".into(), "Birds".into()],
+            }
+        );
+
+        // Then the sidecar, named either way
+        let sidecar = packet(
+            r#">
+   <xmp:Rating>5</xmp:Rating>
+   <dc:subject><rdf:Bag><rdf:li>Fish</rdf:li></rdf:Bag></dc:subject>"#,
+        );
+        dir.file("photo.xmp", sidecar.as_bytes());
+        let merged = read_head(&path, &head);
+        assert_eq!(merged.rating, Some(5));
+        assert_eq!(merged.tags, ["Cats", "Dogs", "Birds", "Fish"]);
+
+        // Where a rating of 0 clears it
+        dir.file("photo.jpg.xmp", packet(r#" xmp:Rating="0">"#).as_bytes());
+        let cleared = read_head(&path, &head);
+        assert_eq!(cleared.rating, None);
+        assert_eq!(cleared.tags, ["Cats", "Dogs", "Birds"]);
+    }
+}